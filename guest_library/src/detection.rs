@@ -0,0 +1,129 @@
+/// A detected object in frame coordinates, as handed to the plugin
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Detection {
+    /// Top left corner and size of the box in pixels of the frame
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+    pub class_id: u32,
+    pub score: f32,
+    /// Class name, e.g. "person"
+    pub label: Option<String>,
+    /// RGB colour, picked from the class id by the plugin when not set
+    pub color: Option<[u8; 3]>,
+    /// Identity of the object across frames, written to the MOT and COCO exports
+    pub track_id: Option<u32>,
+}
+
+impl Detection {
+    pub(crate) fn to_json(&self) -> String {
+        let mut fields = vec![
+            format!("\"x\":{}", self.x),
+            format!("\"y\":{}", self.y),
+            format!("\"width\":{}", self.width),
+            format!("\"height\":{}", self.height),
+            format!("\"class_id\":{}", self.class_id),
+            format!("\"score\":{}", self.score),
+        ];
+        if let Some(label) = self.label.as_deref() {
+            fields.push(format!("\"label\":{}", json_string(label)));
+        }
+        if let Some([red, green, blue]) = self.color {
+            fields.push(format!("\"color\":[{red},{green},{blue}]"));
+        }
+        if let Some(track_id) = self.track_id {
+            fields.push(format!("\"track_id\":{track_id}"));
+        }
+        format!("{{{}}}", fields.join(","))
+    }
+}
+
+/// How `VideoReader::draw_detections` renders boxes
+#[derive(Debug, Clone)]
+pub struct DrawStyle {
+    /// Width of the box outlines in pixels, from 1 to 256
+    pub thickness: u32,
+    /// Glyphs are 8 x 8 pixels times this factor, from 1 to 64
+    pub font_scale: u32,
+    /// Whether to draw the label with its filled background above each box
+    pub labels: bool,
+}
+
+impl Default for DrawStyle {
+    fn default() -> Self {
+        DrawStyle {
+            thickness: 2,
+            font_scale: 2,
+            labels: true,
+        }
+    }
+}
+
+impl DrawStyle {
+    pub(crate) fn to_json(&self, detections: &[Detection]) -> String {
+        let detections: Vec<String> = detections.iter().map(Detection::to_json).collect();
+        format!(
+            "{{\"detections\":[{}],\"thickness\":{},\"font_scale\":{},\"labels\":{}}}",
+            detections.join(","),
+            self.thickness,
+            self.font_scale,
+            self.labels
+        )
+    }
+}
+
+/// Sidecar files written next to the output video
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnnotationFormat {
+    /// One JSON object per frame with its timestamp, `<video>.jsonl`
+    JsonLines,
+    /// CocoVID JSON as used by video tracking tools, `<video>.coco.json`
+    CocoVideo,
+    /// MOT Challenge CSV, `<video>.mot.txt`
+    Mot,
+    /// One YOLO label file per frame, `<video>_labels/<frame>.txt`
+    Yolo,
+}
+
+pub(crate) fn annotation_options_json(
+    formats: &[AnnotationFormat],
+    class_names: &[&str],
+) -> String {
+    let formats: Vec<&str> = formats
+        .iter()
+        .map(|format| match format {
+            AnnotationFormat::JsonLines => "\"json_lines\"",
+            AnnotationFormat::CocoVideo => "\"coco_video\"",
+            AnnotationFormat::Mot => "\"mot\"",
+            AnnotationFormat::Yolo => "\"yolo\"",
+        })
+        .collect();
+    let class_names: Vec<String> = class_names.iter().map(|name| json_string(name)).collect();
+    format!(
+        "{{\"formats\":[{}],\"class_names\":[{}]}}",
+        formats.join(","),
+        class_names.join(",")
+    )
+}
+
+// Quoted JSON string with everything escaped that JSON requires
+pub(crate) fn json_string(value: &str) -> String {
+    let mut json = String::with_capacity(value.len() + 2);
+    json.push('"');
+    for character in value.chars() {
+        match character {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            character if (character as u32) < 0x20 => {
+                json.push_str(&format!("\\u{:04x}", character as u32))
+            }
+            character => json.push(character),
+        }
+    }
+    json.push('"');
+    json
+}
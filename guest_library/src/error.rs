@@ -0,0 +1,131 @@
+use std::fmt::Display;
+
+use crate::ffi;
+
+/// Mirrors the codes returned by the host functions of the plugin
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ErrorCode {
+    EndOfStream,
+    FileNotFound,
+    UnsupportedCodec,
+    NoVideoStream,
+    EmptyVideo,
+    FrameIndexOutOfRange,
+    BufferTooSmall,
+    MissingOutputFrames,
+    DecoderError,
+    EncoderError,
+    InvalidSession,
+    InvalidGuestMemory,
+    InvalidState,
+    InvalidArgument,
+    IoError,
+    /// A path leads outside of the directories the plugin was given, see `YOLO_VIDEO_PROC_DIRS`
+    PermissionDenied,
+    /// A code added to the plugin after this version of the library
+    Unknown(i32),
+}
+
+impl From<i32> for ErrorCode {
+    fn from(code: i32) -> Self {
+        match code {
+            1 => ErrorCode::EndOfStream,
+            2 => ErrorCode::FileNotFound,
+            3 => ErrorCode::UnsupportedCodec,
+            4 => ErrorCode::NoVideoStream,
+            5 => ErrorCode::EmptyVideo,
+            6 => ErrorCode::FrameIndexOutOfRange,
+            7 => ErrorCode::BufferTooSmall,
+            8 => ErrorCode::MissingOutputFrames,
+            9 => ErrorCode::DecoderError,
+            10 => ErrorCode::EncoderError,
+            11 => ErrorCode::InvalidSession,
+            12 => ErrorCode::InvalidGuestMemory,
+            13 => ErrorCode::InvalidState,
+            14 => ErrorCode::InvalidArgument,
+            15 => ErrorCode::IoError,
+            16 => ErrorCode::PermissionDenied,
+            code => ErrorCode::Unknown(code),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Error {
+    /// A host function failed, `message` is what `get_last_error_message` returned for it
+    Plugin { code: ErrorCode, message: String },
+    /// The plugin loaded by the runtime speaks another version of the host function ABI
+    AbiMismatch { plugin: i32, library: i32 },
+    /// An image handed to a writer does not have the size of the video
+    ImageSize {
+        expected: (u32, u32),
+        actual: (u32, u32),
+    },
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Plugin { code, message } => write!(f, "{:?}: {}", code, message),
+            Error::AbiMismatch { plugin, library } => write!(
+                f,
+                "Plugin ABI version {} does not match library ABI version {}",
+                plugin, library
+            ),
+            Error::ImageSize { expected, actual } => write!(
+                f,
+                "Image of {}x{} does not match video of {}x{}",
+                actual.0, actual.1, expected.0, expected.1
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Turns the code returned by a host function into a `Result`,
+/// fetching the message of the failure from the plugin
+pub(crate) fn check(code: i32) -> Result<()> {
+    match code {
+        0 => Ok(()),
+        code => Err(plugin_error(code)),
+    }
+}
+
+pub(crate) fn plugin_error(code: i32) -> Error {
+    Error::Plugin {
+        code: ErrorCode::from(code),
+        message: last_error_message(),
+    }
+}
+
+/// Message of the last host function that failed
+pub fn last_error_message() -> String {
+    read_plugin_string(|buf_ptr, buf_len, len_ptr| unsafe {
+        ffi::get_last_error_message(buf_ptr, buf_len, len_ptr)
+    })
+    .unwrap_or_default()
+}
+
+// Calls a host function that copies a string into a guest buffer,
+// growing the buffer when the plugin reports that it was too small
+pub(crate) fn read_plugin_string(
+    read: impl Fn(i32, i32, *mut i32) -> i32,
+) -> std::result::Result<String, i32> {
+    let mut buf: Vec<u8> = vec![0; 256];
+    let mut len: i32 = 0;
+    let len_ptr = std::ptr::addr_of_mut!(len);
+
+    let mut result = read(buf.as_mut_ptr() as usize as i32, buf.len() as i32, len_ptr);
+    if result != 0 && len as usize > buf.len() {
+        buf.resize(len as usize, 0);
+        result = read(buf.as_mut_ptr() as usize as i32, buf.len() as i32, len_ptr);
+    }
+    if result != 0 {
+        return Err(result);
+    }
+    buf.truncate(len as usize);
+    Ok(String::from_utf8_lossy(&buf).into_owned())
+}
@@ -0,0 +1,216 @@
+//! Raw imports of the `yolo-video-proc` plugin.
+//! Pointers are offsets into the linear memory of the guest, passed as `i32`.
+
+// Mirrors FrameMetadata of the plugin
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct FrameMetadata {
+    pub pts: i64,
+    pub duration: i64,
+    pub pts_seconds: f64,
+    pub duration_seconds: f64,
+    pub time_base_num: i32,
+    pub time_base_den: i32,
+    pub picture_type: u8,
+    pub key_frame: u8,
+    pub has_pts: u8,
+    pub _reserved: [u8; 5],
+}
+
+// Mirrors FrameTransform of the plugin
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct FrameTransform {
+    pub source_width: u32,
+    pub source_height: u32,
+    pub width: u32,
+    pub height: u32,
+    pub scale_x: f64,
+    pub scale_y: f64,
+    pub offset_x: i32,
+    pub offset_y: i32,
+}
+
+impl FrameTransform {
+    /// Maps a point of a resized frame back to the source video, e.g. a corner of a detection
+    pub fn to_source(&self, x: f64, y: f64) -> (f64, f64) {
+        (
+            (x - self.offset_x as f64) / self.scale_x,
+            (y - self.offset_y as f64) / self.scale_y,
+        )
+    }
+}
+
+#[link(wasm_import_module = "yolo-video-proc")]
+extern "C" {
+    pub fn get_abi_version() -> i32;
+
+    pub fn init_plugin_logging(level: *const i32) -> i32;
+
+    pub fn load_video_to_host_memory(
+        str_ptr: i32,
+        str_len: i32,
+        width_ptr: *mut i32,
+        height_ptr: *mut i32,
+        frame_count: *mut i32,
+        session_ptr: *mut i32,
+    ) -> i32;
+
+    pub fn load_video_range_to_host_memory(
+        str_ptr: i32,
+        str_len: i32,
+        start_us: i64,
+        end_us: i64,
+        width_ptr: *mut i32,
+        height_ptr: *mut i32,
+        frame_count: *mut i32,
+        session_ptr: *mut i32,
+    ) -> i32;
+
+    pub fn load_video_with_options_to_host_memory(
+        str_ptr: i32,
+        str_len: i32,
+        options_ptr: i32,
+        options_len: i32,
+        width_ptr: *mut i32,
+        height_ptr: *mut i32,
+        frame_count: *mut i32,
+        session_ptr: *mut i32,
+    ) -> i32;
+
+    pub fn get_frame(session: i32, frame_index: i32, image_buf_ptr: i32, image_buf_len: i32)
+        -> i32;
+
+    pub fn get_frame_tensor(
+        session: i32,
+        frame_index: i32,
+        options_ptr: i32,
+        options_len: i32,
+        tensor_buf_ptr: i32,
+        tensor_buf_len: i32,
+        tensor_len_ptr: *mut i32,
+        transform_ptr: *mut FrameTransform,
+    ) -> i32;
+
+    pub fn open_video_stream(
+        str_ptr: i32,
+        str_len: i32,
+        width_ptr: *mut i32,
+        height_ptr: *mut i32,
+        session_ptr: *mut i32,
+    ) -> i32;
+
+    pub fn open_video_stream_with_options(
+        str_ptr: i32,
+        str_len: i32,
+        options_ptr: i32,
+        options_len: i32,
+        width_ptr: *mut i32,
+        height_ptr: *mut i32,
+        session_ptr: *mut i32,
+    ) -> i32;
+
+    pub fn create_input_buffer(buffer_ptr: *mut i32) -> i32;
+
+    pub fn append_input_bytes(buffer: i32, bytes_ptr: i32, bytes_len: i32) -> i32;
+
+    pub fn discard_input_buffer(buffer: i32) -> i32;
+
+    pub fn load_video_from_input_buffer(
+        buffer: i32,
+        options_ptr: i32,
+        options_len: i32,
+        width_ptr: *mut i32,
+        height_ptr: *mut i32,
+        frame_count_ptr: *mut i32,
+        session_ptr: *mut i32,
+    ) -> i32;
+
+    pub fn open_video_stream_from_input_buffer(
+        buffer: i32,
+        options_ptr: i32,
+        options_len: i32,
+        width_ptr: *mut i32,
+        height_ptr: *mut i32,
+        session_ptr: *mut i32,
+    ) -> i32;
+
+    pub fn next_frame(
+        session: i32,
+        image_buf_ptr: i32,
+        image_buf_len: i32,
+        frame_index_ptr: *mut i32,
+    ) -> i32;
+
+    pub fn seek_to_timestamp(session: i32, time_us: i64) -> i32;
+
+    pub fn seek_to_frame(session: i32, frame_number: i64) -> i32;
+
+    pub fn write_frame(
+        session: i32,
+        frame_index: i32,
+        image_buf_ptr: i32,
+        image_buf_len: i32,
+    ) -> i32;
+
+    pub fn draw_detections(session: i32, frame_index: i32, json_ptr: i32, json_len: i32) -> i32;
+
+    pub fn redact_regions(session: i32, frame_index: i32, json_ptr: i32, json_len: i32) -> i32;
+
+    pub fn set_frame_detections(
+        session: i32,
+        frame_index: i32,
+        json_ptr: i32,
+        json_len: i32,
+    ) -> i32;
+
+    pub fn init_tracker(session: i32, json_ptr: i32, json_len: i32) -> i32;
+
+    pub fn track_detections(
+        session: i32,
+        frame_index: i32,
+        json_ptr: i32,
+        json_len: i32,
+        ids_ptr: *mut i32,
+        ids_len: i32,
+    ) -> i32;
+
+    pub fn set_annotation_options(session: i32, json_ptr: i32, json_len: i32) -> i32;
+
+    pub fn assemble_output_frames_to_video(session: i32, str_ptr: i32, str_len: i32) -> i32;
+
+    pub fn open_video_encoder(session: i32, str_ptr: i32, str_len: i32) -> i32;
+
+    pub fn assemble_output_frames_to_memory(session: i32) -> i32;
+
+    pub fn open_video_encoder_to_memory(session: i32) -> i32;
+
+    pub fn read_output_bytes(session: i32, buf_ptr: i32, buf_len: i32, len_ptr: *mut i32) -> i32;
+
+    pub fn set_encoder_options(session: i32, json_ptr: i32, json_len: i32) -> i32;
+
+    pub fn finish_video_encoder(session: i32) -> i32;
+
+    pub fn close_video(session: i32) -> i32;
+
+    pub fn get_last_error_message(
+        message_buf_ptr: i32,
+        message_buf_len: i32,
+        message_len_ptr: *mut i32,
+    ) -> i32;
+
+    pub fn get_video_info(
+        session: i32,
+        json_buf_ptr: i32,
+        json_buf_len: i32,
+        json_len_ptr: *mut i32,
+    ) -> i32;
+
+    pub fn get_frame_metadata(
+        session: i32,
+        frame_index: i32,
+        metadata_ptr: *mut FrameMetadata,
+    ) -> i32;
+
+    pub fn get_frame_transform(session: i32, transform_ptr: *mut FrameTransform) -> i32;
+}
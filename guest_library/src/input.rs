@@ -0,0 +1,40 @@
+use crate::{error::check, ffi, Result};
+
+/// Bytes of a video file handed to the plugin in chunks, e.g. while they arrive over the
+/// network, to be opened with `VideoReader::open_buffer` or `open_stream_buffer` without
+/// going through the file system
+pub struct InputBuffer {
+    handle: i32,
+}
+
+impl InputBuffer {
+    pub fn new() -> Result<Self> {
+        let mut handle = 0;
+        check(unsafe { ffi::create_input_buffer(std::ptr::addr_of_mut!(handle)) })?;
+        Ok(InputBuffer { handle })
+    }
+
+    /// Appends the next chunk of the file
+    pub fn push(&mut self, bytes: &[u8]) -> Result<()> {
+        check(unsafe {
+            ffi::append_input_bytes(
+                self.handle,
+                bytes.as_ptr() as usize as i32,
+                bytes.len() as i32,
+            )
+        })
+    }
+
+    // Handle for opening the buffer, the plugin frees it once opened
+    pub(crate) fn into_handle(self) -> i32 {
+        let handle = self.handle;
+        std::mem::forget(self);
+        handle
+    }
+}
+
+impl Drop for InputBuffer {
+    fn drop(&mut self) {
+        unsafe { ffi::discard_input_buffer(self.handle) };
+    }
+}
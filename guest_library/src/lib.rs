@@ -0,0 +1,72 @@
+//! Safe API over the host functions of the `yolo-video-proc` WasmEdge plugin.
+//!
+//! ```ignore
+//! let mut reader = VideoReader::open("input.mp4")?;
+//! let mut writer = reader.writer();
+//! for frame in &mut reader {
+//!     let (index, mut image) = frame?;
+//!     // draw on image
+//!     writer.write(index, &image)?;
+//! }
+//! writer.finish("output.mp4")?;
+//! ```
+
+mod detection;
+mod error;
+pub mod ffi;
+mod input;
+mod options;
+mod reader;
+mod redact;
+mod tensor;
+mod tracking;
+mod writer;
+
+use log::LevelFilter;
+
+pub use detection::{AnnotationFormat, Detection, DrawStyle};
+pub use error::{last_error_message, Error, ErrorCode, Result};
+pub use ffi::{FrameMetadata, FrameTransform};
+pub use image::RgbImage;
+pub use input::InputBuffer;
+pub use options::{
+    ChannelOrder, DecodeOptions, Interpolation, ResizeMode, TensorDataType, TensorOptions,
+};
+pub use reader::VideoReader;
+pub use redact::{RedactMethod, RedactStyle, Region};
+pub use tensor::Tensor;
+pub use tracking::TrackerOptions;
+pub use writer::{StreamWriter, VideoWriter};
+
+/// Version of the host function ABI this library was written against,
+/// has to match `PLUGIN_ABI_VERSION` of the plugin
+pub const ABI_VERSION: i32 = 1;
+
+/// Fails when the loaded plugin speaks another version of the host function ABI.
+/// Called by `VideoReader` before it opens a video.
+pub fn check_abi_version() -> Result<()> {
+    let plugin = unsafe { ffi::get_abi_version() };
+    if plugin != ABI_VERSION {
+        return Err(Error::AbiMismatch {
+            plugin,
+            library: ABI_VERSION,
+        });
+    }
+    Ok(())
+}
+
+/// Starts logging of the plugin itself, to the terminal of the runtime
+pub fn init_plugin_logging(level_filter: LevelFilter) {
+    let level_filter_i32 = level_filter as i32;
+    let level_filter_ptr = std::ptr::addr_of!(level_filter_i32);
+    unsafe { ffi::init_plugin_logging(level_filter_ptr) };
+}
+
+// Video session of the plugin, closed once the reader and all writers using it are dropped
+pub(crate) struct Session(i32);
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        unsafe { ffi::close_video(self.0) };
+    }
+}
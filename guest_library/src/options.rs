@@ -0,0 +1,250 @@
+use std::time::Duration;
+
+use crate::detection::json_string;
+
+/// How frames are fitted into `DecodeOptions::width` x `height` of another aspect ratio
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ResizeMode {
+    /// Scales width and height independently, distorting the image
+    #[default]
+    Stretch,
+    /// Scales the whole frame into the target and pads the borders with `pad_color`
+    Letterbox,
+    /// Scales the frame to cover the target and cuts off what sticks out
+    CenterCrop,
+}
+
+impl ResizeMode {
+    fn json_name(self) -> &'static str {
+        match self {
+            ResizeMode::Stretch => "stretch",
+            ResizeMode::Letterbox => "letterbox",
+            ResizeMode::CenterCrop => "center_crop",
+        }
+    }
+}
+
+/// Interpolation the plugin resizes frames with
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Interpolation {
+    FastBilinear,
+    #[default]
+    Bilinear,
+    Bicubic,
+    Point,
+    Area,
+    Gauss,
+    Sinc,
+    Lanczos,
+    Spline,
+}
+
+impl Interpolation {
+    fn json_name(self) -> &'static str {
+        match self {
+            Interpolation::FastBilinear => "fast_bilinear",
+            Interpolation::Bilinear => "bilinear",
+            Interpolation::Bicubic => "bicubic",
+            Interpolation::Point => "point",
+            Interpolation::Area => "area",
+            Interpolation::Gauss => "gauss",
+            Interpolation::Sinc => "sinc",
+            Interpolation::Lanczos => "lanczos",
+            Interpolation::Spline => "spline",
+        }
+    }
+}
+
+/// Element type of a tensor, F16 values are rounded to the nearest half, ties to even
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TensorDataType {
+    #[default]
+    F32,
+    F16,
+}
+
+impl TensorDataType {
+    /// Size of one element in bytes
+    pub fn size(self) -> usize {
+        match self {
+            TensorDataType::F32 => 4,
+            TensorDataType::F16 => 2,
+        }
+    }
+}
+
+/// Order of the channel planes of a tensor
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ChannelOrder {
+    #[default]
+    Rgb,
+    Bgr,
+}
+
+/// Which frames the plugin decodes, sent to it as JSON when a video is opened.
+/// The sampling options pick from the decoded frames, before `filter` runs. Frames they drop
+/// are skipped in the plugin, they are never filtered, converted or copied.
+#[derive(Debug, Clone, Default)]
+pub struct DecodeOptions {
+    /// Only decode frames shown from `start` on, counted from the start of the video.
+    /// Videos written from the reader start at 0 all the same.
+    pub start: Option<Duration>,
+    /// Only decode frames shown before `end`
+    pub end: Option<Duration>,
+    /// Keep every Nth decoded frame
+    pub every_nth_frame: Option<u32>,
+    /// Resample to this frame rate based on the frame timestamps, e.g. 5 fps of a 60 fps video
+    pub target_fps: Option<f64>,
+    /// Keep intra coded frames only
+    pub key_frames_only: bool,
+    /// Width of the frames handed out, e.g. the input size of a model.
+    /// With only one of `width` and `height` the aspect ratio is kept, both can be up to 16384.
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub resize_mode: ResizeMode,
+    /// RGB colour of the letterbox borders, grey 114 by default
+    pub pad_color: Option<[u8; 3]>,
+    pub interpolation: Interpolation,
+    /// FFmpeg filter string run on the decoded frames before they are resized,
+    /// e.g. `hqdn3d,eq=contrast=1.2,transpose=1`. A filter that cannot be built fails `open`,
+    /// as do filters that could read host files, e.g. `movie` or `drawtext`.
+    pub filter: Option<String>,
+    /// Turn frames of videos with rotation metadata, e.g. from phones, upright before `filter`.
+    /// The rotation shows up in the video info JSON as `rotation` and `applied_rotation`.
+    pub auto_rotate: bool,
+}
+
+impl DecodeOptions {
+    pub(crate) fn to_json(&self) -> String {
+        let mut fields = Vec::new();
+        if let Some(start) = self.start {
+            fields.push(format!("\"start_us\":{}", start.as_micros()));
+        }
+        if let Some(end) = self.end {
+            fields.push(format!("\"end_us\":{}", end.as_micros()));
+        }
+        if let Some(every_nth_frame) = self.every_nth_frame {
+            fields.push(format!("\"every_nth_frame\":{every_nth_frame}"));
+        }
+        if let Some(target_fps) = self.target_fps {
+            fields.push(format!("\"target_fps\":{target_fps}"));
+        }
+        if self.key_frames_only {
+            fields.push("\"key_frames_only\":true".to_string());
+        }
+        if let Some(width) = self.width {
+            fields.push(format!("\"width\":{width}"));
+        }
+        if let Some(height) = self.height {
+            fields.push(format!("\"height\":{height}"));
+        }
+        push_resize_fields(
+            &mut fields,
+            self.resize_mode,
+            self.pad_color,
+            self.interpolation,
+        );
+        if let Some(filter) = self.filter.as_deref() {
+            fields.push(format!("\"filter\":{}", json_string(filter)));
+        }
+        if self.auto_rotate {
+            fields.push("\"auto_rotate\":true".to_string());
+        }
+        format!("{{{}}}", fields.join(","))
+    }
+}
+
+/// How `VideoReader::frame_tensor` turns a frame into model input.
+/// Pixels are scaled to 0..1 and then normalised with `(value - mean) / std` per channel,
+/// `mean` and `std` are given in the channel order of the tensor.
+#[derive(Debug, Clone)]
+pub struct TensorOptions {
+    pub data_type: TensorDataType,
+    pub channel_order: ChannelOrder,
+    pub mean: [f32; 3],
+    pub std: [f32; 3],
+    /// Size of the tensor, the frame is resized into it when it differs from the frame size.
+    /// Up to 8192 each.
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub resize_mode: ResizeMode,
+    /// RGB colour of the letterbox borders, grey 114 by default
+    pub pad_color: Option<[u8; 3]>,
+    pub interpolation: Interpolation,
+}
+
+impl Default for TensorOptions {
+    fn default() -> Self {
+        TensorOptions {
+            data_type: TensorDataType::default(),
+            channel_order: ChannelOrder::default(),
+            mean: [0.0; 3],
+            std: [1.0; 3],
+            width: None,
+            height: None,
+            resize_mode: ResizeMode::default(),
+            pad_color: None,
+            interpolation: Interpolation::default(),
+        }
+    }
+}
+
+impl TensorOptions {
+    /// Letterboxed square input of YOLO models, e.g. 640
+    pub fn yolo(size: u32) -> Self {
+        TensorOptions {
+            width: Some(size),
+            height: Some(size),
+            resize_mode: ResizeMode::Letterbox,
+            ..TensorOptions::default()
+        }
+    }
+
+    pub(crate) fn to_json(&self) -> String {
+        let data_type = match self.data_type {
+            TensorDataType::F32 => "f32",
+            TensorDataType::F16 => "f16",
+        };
+        let channel_order = match self.channel_order {
+            ChannelOrder::Rgb => "rgb",
+            ChannelOrder::Bgr => "bgr",
+        };
+        let [mean_0, mean_1, mean_2] = self.mean;
+        let [std_0, std_1, std_2] = self.std;
+        let mut fields = vec![
+            format!("\"data_type\":\"{data_type}\""),
+            format!("\"channel_order\":\"{channel_order}\""),
+            format!("\"mean\":[{mean_0},{mean_1},{mean_2}]"),
+            format!("\"std\":[{std_0},{std_1},{std_2}]"),
+        ];
+        if let Some(width) = self.width {
+            fields.push(format!("\"width\":{width}"));
+        }
+        if let Some(height) = self.height {
+            fields.push(format!("\"height\":{height}"));
+        }
+        push_resize_fields(
+            &mut fields,
+            self.resize_mode,
+            self.pad_color,
+            self.interpolation,
+        );
+        format!("{{{}}}", fields.join(","))
+    }
+}
+
+fn push_resize_fields(
+    fields: &mut Vec<String>,
+    resize_mode: ResizeMode,
+    pad_color: Option<[u8; 3]>,
+    interpolation: Interpolation,
+) {
+    fields.push(format!("\"resize_mode\":\"{}\"", resize_mode.json_name()));
+    if let Some([red, green, blue]) = pad_color {
+        fields.push(format!("\"pad_color\":[{red},{green},{blue}]"));
+    }
+    fields.push(format!(
+        "\"interpolation\":\"{}\"",
+        interpolation.json_name()
+    ));
+}
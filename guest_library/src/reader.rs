@@ -0,0 +1,534 @@
+use std::{rc::Rc, time::Duration};
+
+use image::RgbImage;
+
+use crate::{
+    check_abi_version,
+    detection::annotation_options_json,
+    error::{check, plugin_error, read_plugin_string, ErrorCode},
+    ffi, AnnotationFormat, DecodeOptions, Detection, DrawStyle, Error, FrameMetadata,
+    FrameTransform, InputBuffer, RedactStyle, Region, Result, Session, StreamWriter, Tensor,
+    TensorOptions, TrackerOptions, VideoWriter,
+};
+
+enum Source {
+    // Every frame was decoded into the memory of the plugin by `open`
+    Loaded { frame_count: u32 },
+    // Frames are decoded one at a time as they are iterated
+    Stream { finished: bool },
+}
+
+/// A video opened in the plugin, iterating over it yields the index and pixels of every frame
+pub struct VideoReader {
+    session: Rc<Session>,
+    width: u32,
+    height: u32,
+    source: Source,
+    // Index of the frame the iterator hands out next
+    next_index: u32,
+}
+
+impl VideoReader {
+    /// Decodes every frame of the video at `path` into the memory of the plugin
+    pub fn open(path: &str) -> Result<Self> {
+        check_abi_version()?;
+
+        let (mut width, mut height, mut frame_count, mut session) = (0, 0, 0, 0);
+        check(unsafe {
+            ffi::load_video_to_host_memory(
+                path.as_ptr() as usize as i32,
+                path.len() as i32,
+                std::ptr::addr_of_mut!(width),
+                std::ptr::addr_of_mut!(height),
+                std::ptr::addr_of_mut!(frame_count),
+                std::ptr::addr_of_mut!(session),
+            )
+        })?;
+
+        Ok(VideoReader {
+            session: Rc::new(Session(session)),
+            width: width as u32,
+            height: height as u32,
+            source: Source::Loaded {
+                frame_count: frame_count as u32,
+            },
+            next_index: 0,
+        })
+    }
+
+    /// Decodes only the frames shown from `start` up to `end` into the memory of the plugin,
+    /// counted from the start of the video. Without `end` the rest of the video is read.
+    /// A video written from the reader starts at 0, not at `start`.
+    pub fn open_range(path: &str, start: Duration, end: Option<Duration>) -> Result<Self> {
+        check_abi_version()?;
+
+        let (mut width, mut height, mut frame_count, mut session) = (0, 0, 0, 0);
+        check(unsafe {
+            ffi::load_video_range_to_host_memory(
+                path.as_ptr() as usize as i32,
+                path.len() as i32,
+                start.as_micros() as i64,
+                end.map_or(-1, |end| end.as_micros() as i64),
+                std::ptr::addr_of_mut!(width),
+                std::ptr::addr_of_mut!(height),
+                std::ptr::addr_of_mut!(frame_count),
+                std::ptr::addr_of_mut!(session),
+            )
+        })?;
+
+        Ok(VideoReader {
+            session: Rc::new(Session(session)),
+            width: width as u32,
+            height: height as u32,
+            source: Source::Loaded {
+                frame_count: frame_count as u32,
+            },
+            next_index: 0,
+        })
+    }
+
+    /// Decodes the frames of the video at `path` selected by `options` into the memory of the
+    /// plugin, e.g. only 5 frames per second for inference at a reduced rate
+    pub fn open_with(path: &str, options: &DecodeOptions) -> Result<Self> {
+        check_abi_version()?;
+
+        let options_json = options.to_json();
+        let (mut width, mut height, mut frame_count, mut session) = (0, 0, 0, 0);
+        check(unsafe {
+            ffi::load_video_with_options_to_host_memory(
+                path.as_ptr() as usize as i32,
+                path.len() as i32,
+                options_json.as_ptr() as usize as i32,
+                options_json.len() as i32,
+                std::ptr::addr_of_mut!(width),
+                std::ptr::addr_of_mut!(height),
+                std::ptr::addr_of_mut!(frame_count),
+                std::ptr::addr_of_mut!(session),
+            )
+        })?;
+
+        Ok(VideoReader {
+            session: Rc::new(Session(session)),
+            width: width as u32,
+            height: height as u32,
+            source: Source::Loaded {
+                frame_count: frame_count as u32,
+            },
+            next_index: 0,
+        })
+    }
+
+    /// Opens the video at `path` to decode its frames one at a time while iterating,
+    /// for videos too long to hold in memory. Use `stream_writer` for the output.
+    pub fn open_stream(path: &str) -> Result<Self> {
+        check_abi_version()?;
+
+        let (mut width, mut height, mut session) = (0, 0, 0);
+        check(unsafe {
+            ffi::open_video_stream(
+                path.as_ptr() as usize as i32,
+                path.len() as i32,
+                std::ptr::addr_of_mut!(width),
+                std::ptr::addr_of_mut!(height),
+                std::ptr::addr_of_mut!(session),
+            )
+        })?;
+
+        Ok(VideoReader {
+            session: Rc::new(Session(session)),
+            width: width as u32,
+            height: height as u32,
+            source: Source::Stream { finished: false },
+            next_index: 0,
+        })
+    }
+
+    /// Like `open_stream`, but only the frames selected by `options` are handed out
+    pub fn open_stream_with(path: &str, options: &DecodeOptions) -> Result<Self> {
+        check_abi_version()?;
+
+        let options_json = options.to_json();
+        let (mut width, mut height, mut session) = (0, 0, 0);
+        check(unsafe {
+            ffi::open_video_stream_with_options(
+                path.as_ptr() as usize as i32,
+                path.len() as i32,
+                options_json.as_ptr() as usize as i32,
+                options_json.len() as i32,
+                std::ptr::addr_of_mut!(width),
+                std::ptr::addr_of_mut!(height),
+                std::ptr::addr_of_mut!(session),
+            )
+        })?;
+
+        Ok(VideoReader {
+            session: Rc::new(Session(session)),
+            width: width as u32,
+            height: height as u32,
+            source: Source::Stream { finished: false },
+            next_index: 0,
+        })
+    }
+
+    /// Like `open_with` for a video file held in memory, e.g. an upload
+    pub fn open_bytes(bytes: &[u8], options: &DecodeOptions) -> Result<Self> {
+        let mut buffer = InputBuffer::new()?;
+        buffer.push(bytes)?;
+        Self::open_buffer(buffer, options)
+    }
+
+    /// Like `open_with` for a video file handed to the plugin in chunks
+    pub fn open_buffer(buffer: InputBuffer, options: &DecodeOptions) -> Result<Self> {
+        check_abi_version()?;
+
+        let options_json = options.to_json();
+        let (mut width, mut height, mut frame_count, mut session) = (0, 0, 0, 0);
+        check(unsafe {
+            ffi::load_video_from_input_buffer(
+                buffer.into_handle(),
+                options_json.as_ptr() as usize as i32,
+                options_json.len() as i32,
+                std::ptr::addr_of_mut!(width),
+                std::ptr::addr_of_mut!(height),
+                std::ptr::addr_of_mut!(frame_count),
+                std::ptr::addr_of_mut!(session),
+            )
+        })?;
+
+        Ok(VideoReader {
+            session: Rc::new(Session(session)),
+            width: width as u32,
+            height: height as u32,
+            source: Source::Loaded {
+                frame_count: frame_count as u32,
+            },
+            next_index: 0,
+        })
+    }
+
+    /// Like `open_stream_with` for a video file held in memory
+    pub fn open_stream_bytes(bytes: &[u8], options: &DecodeOptions) -> Result<Self> {
+        let mut buffer = InputBuffer::new()?;
+        buffer.push(bytes)?;
+        Self::open_stream_buffer(buffer, options)
+    }
+
+    /// Like `open_stream_with` for a video file handed to the plugin in chunks
+    pub fn open_stream_buffer(buffer: InputBuffer, options: &DecodeOptions) -> Result<Self> {
+        check_abi_version()?;
+
+        let options_json = options.to_json();
+        let (mut width, mut height, mut session) = (0, 0, 0);
+        check(unsafe {
+            ffi::open_video_stream_from_input_buffer(
+                buffer.into_handle(),
+                options_json.as_ptr() as usize as i32,
+                options_json.len() as i32,
+                std::ptr::addr_of_mut!(width),
+                std::ptr::addr_of_mut!(height),
+                std::ptr::addr_of_mut!(session),
+            )
+        })?;
+
+        Ok(VideoReader {
+            session: Rc::new(Session(session)),
+            width: width as u32,
+            height: height as u32,
+            source: Source::Stream { finished: false },
+            next_index: 0,
+        })
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// Number of frames of a video opened with `open`, unknown for streams
+    pub fn frame_count(&self) -> Option<u32> {
+        match self.source {
+            Source::Loaded { frame_count } => Some(frame_count),
+            Source::Stream { .. } => None,
+        }
+    }
+
+    /// Continues iterating a stream at the frame shown `time` after the start of the video
+    pub fn seek_to_time(&mut self, time: Duration) -> Result<()> {
+        check(unsafe { ffi::seek_to_timestamp(self.session.0, time.as_micros() as i64) })?;
+        self.source = Source::Stream { finished: false };
+        Ok(())
+    }
+
+    /// Continues iterating a stream at frame `frame_number`, counted at the nominal frame rate
+    pub fn seek_to_frame(&mut self, frame_number: u64) -> Result<()> {
+        check(unsafe { ffi::seek_to_frame(self.session.0, frame_number as i64) })?;
+        self.source = Source::Stream { finished: false };
+        Ok(())
+    }
+
+    /// Pixels of any frame of a video opened with `open`
+    pub fn frame(&self, index: u32) -> Result<RgbImage> {
+        let mut image = RgbImage::new(self.width, self.height);
+        check(unsafe {
+            ffi::get_frame(
+                self.session.0,
+                index as i32,
+                image.as_mut_ptr() as usize as i32,
+                image.len() as i32,
+            )
+        })?;
+        Ok(image)
+    }
+
+    /// Draws boxes and labels of `detections` into a frame in the plugin.
+    /// They end up in the frame written by a `VideoWriter`, or in the decoded frame before that.
+    pub fn draw_detections(
+        &self,
+        index: u32,
+        detections: &[Detection],
+        style: &DrawStyle,
+    ) -> Result<()> {
+        let json = style.to_json(detections);
+        check(unsafe {
+            ffi::draw_detections(
+                self.session.0,
+                index as i32,
+                json.as_ptr() as usize as i32,
+                json.len() as i32,
+            )
+        })
+    }
+
+    /// Blurs, pixelates or fills regions of a frame in the plugin, e.g. faces or licence plates.
+    /// Works on the same frame as `draw_detections`, detections convert into regions.
+    pub fn redact_regions(
+        &self,
+        index: u32,
+        regions: &[Region],
+        style: &RedactStyle,
+    ) -> Result<()> {
+        let json = style.to_json(regions);
+        check(unsafe {
+            ffi::redact_regions(
+                self.session.0,
+                index as i32,
+                json.as_ptr() as usize as i32,
+                json.len() as i32,
+            )
+        })
+    }
+
+    /// Records the detections of a frame for the annotation export, replacing earlier ones
+    pub fn set_frame_detections(&self, index: u32, detections: &[Detection]) -> Result<()> {
+        let detections: Vec<String> = detections.iter().map(Detection::to_json).collect();
+        let json = format!("[{}]", detections.join(","));
+        check(unsafe {
+            ffi::set_frame_detections(
+                self.session.0,
+                index as i32,
+                json.as_ptr() as usize as i32,
+                json.len() as i32,
+            )
+        })
+    }
+
+    /// Starts tracking over, track ids begin at 1 again.
+    /// Without it `track_detections` uses the default `TrackerOptions`.
+    pub fn init_tracker(&self, options: &TrackerOptions) -> Result<()> {
+        let json = options.to_json();
+        check(unsafe {
+            ffi::init_tracker(
+                self.session.0,
+                json.as_ptr() as usize as i32,
+                json.len() as i32,
+            )
+        })
+    }
+
+    /// Sets the `track_id` of the detections of a frame. Frames have to be tracked in order
+    /// without gaps, a frame without detections with an empty slice, or this fails with
+    /// `InvalidState`.
+    /// Detections of tracks that are not confirmed yet get none. The detections are recorded
+    /// with their ids for the annotation export, like `set_frame_detections` does.
+    pub fn track_detections(&self, index: u32, detections: &mut [Detection]) -> Result<()> {
+        let json: Vec<String> = detections.iter().map(Detection::to_json).collect();
+        let json = format!("[{}]", json.join(","));
+        let mut ids = vec![-1i32; detections.len()];
+        check(unsafe {
+            ffi::track_detections(
+                self.session.0,
+                index as i32,
+                json.as_ptr() as usize as i32,
+                json.len() as i32,
+                ids.as_mut_ptr(),
+                ids.len() as i32,
+            )
+        })?;
+
+        for (detection, id) in detections.iter_mut().zip(ids) {
+            detection.track_id = u32::try_from(id).ok();
+        }
+        Ok(())
+    }
+
+    /// Writes the recorded detections in `formats` next to the output video once a writer has
+    /// finished it. `class_names` names the class ids in the COCO export.
+    pub fn set_annotation_export(
+        &self,
+        formats: &[AnnotationFormat],
+        class_names: &[&str],
+    ) -> Result<()> {
+        let json = annotation_options_json(formats, class_names);
+        check(unsafe {
+            ffi::set_annotation_options(
+                self.session.0,
+                json.as_ptr() as usize as i32,
+                json.len() as i32,
+            )
+        })
+    }
+
+    /// A frame of a video opened with `open` as model input, converted in the plugin
+    pub fn frame_tensor(&self, index: u32, options: &TensorOptions) -> Result<Tensor> {
+        let options_json = options.to_json();
+        let width = options.width.unwrap_or(self.width);
+        let height = options.height.unwrap_or(self.height);
+        let mut data = vec![0; 3 * width as usize * height as usize * options.data_type.size()];
+        let mut tensor_len = 0;
+        let mut frame_transform = FrameTransform::default();
+        check(unsafe {
+            ffi::get_frame_tensor(
+                self.session.0,
+                index as i32,
+                options_json.as_ptr() as usize as i32,
+                options_json.len() as i32,
+                data.as_mut_ptr() as usize as i32,
+                data.len() as i32,
+                std::ptr::addr_of_mut!(tensor_len),
+                std::ptr::addr_of_mut!(frame_transform),
+            )
+        })?;
+        data.truncate(tensor_len as usize);
+
+        Ok(Tensor {
+            data,
+            width,
+            height,
+            data_type: options.data_type,
+            frame_transform,
+        })
+    }
+
+    /// Timestamps, picture type and key frame flag of a frame that has been decoded
+    pub fn frame_metadata(&self, index: u32) -> Result<FrameMetadata> {
+        let mut metadata = FrameMetadata::default();
+        check(unsafe {
+            ffi::get_frame_metadata(
+                self.session.0,
+                index as i32,
+                std::ptr::addr_of_mut!(metadata),
+            )
+        })?;
+        Ok(metadata)
+    }
+
+    /// Where the source video ended up in the frames handed out, after resizing with
+    /// `DecodeOptions`
+    pub fn frame_transform(&self) -> Result<FrameTransform> {
+        let mut frame_transform = FrameTransform::default();
+        check(unsafe {
+            ffi::get_frame_transform(self.session.0, std::ptr::addr_of_mut!(frame_transform))
+        })?;
+        Ok(frame_transform)
+    }
+
+    /// Versioned JSON with everything the plugin knows about the video
+    pub fn video_info_json(&self) -> Result<String> {
+        read_plugin_string(|buf_ptr, buf_len, len_ptr| unsafe {
+            ffi::get_video_info(self.session.0, buf_ptr, buf_len, len_ptr)
+        })
+        .map_err(plugin_error)
+    }
+
+    /// Codec, pixel format, quality, container, filter string and `output_rotation`
+    /// (`"upright"` or `"tagged"`) of the output, as JSON for `EncoderOptions` of the plugin.
+    /// Has to be set before a writer encodes anything.
+    pub fn set_encoder_options(&self, options_json: &str) -> Result<()> {
+        check(unsafe {
+            ffi::set_encoder_options(
+                self.session.0,
+                options_json.as_ptr() as usize as i32,
+                options_json.len() as i32,
+            )
+        })
+    }
+
+    /// Writer for a video opened with `open`, frames are encoded once it is finished
+    pub fn writer(&self) -> VideoWriter {
+        VideoWriter::new(self.session.clone(), self.width, self.height)
+    }
+
+    /// Writer for a video opened with `open_stream`, frames are encoded to `path` as they are written
+    pub fn stream_writer(&self, path: &str) -> Result<StreamWriter> {
+        StreamWriter::open(self.session.clone(), self.width, self.height, path)
+    }
+
+    /// Like `stream_writer`, but frames are encoded into memory, see `StreamWriter::take_bytes`
+    pub fn stream_writer_to_bytes(&self) -> Result<StreamWriter> {
+        StreamWriter::open_to_memory(self.session.clone(), self.width, self.height)
+    }
+
+    fn next_loaded(&mut self, frame_count: u32) -> Option<Result<(u32, RgbImage)>> {
+        if self.next_index >= frame_count {
+            return None;
+        }
+        let index = self.next_index;
+        self.next_index += 1;
+        Some(self.frame(index).map(|image| (index, image)))
+    }
+
+    fn next_streamed(&mut self) -> Option<Result<(u32, RgbImage)>> {
+        let mut image = RgbImage::new(self.width, self.height);
+        let mut frame_index = 0;
+        let result = unsafe {
+            ffi::next_frame(
+                self.session.0,
+                image.as_mut_ptr() as usize as i32,
+                image.len() as i32,
+                std::ptr::addr_of_mut!(frame_index),
+            )
+        };
+
+        match check(result) {
+            Ok(()) => {
+                self.next_index = frame_index as u32 + 1;
+                Some(Ok((frame_index as u32, image)))
+            }
+            Err(err) => {
+                self.source = Source::Stream { finished: true };
+                match err {
+                    Error::Plugin {
+                        code: ErrorCode::EndOfStream,
+                        ..
+                    } => None,
+                    err => Some(Err(err)),
+                }
+            }
+        }
+    }
+}
+
+impl Iterator for VideoReader {
+    type Item = Result<(u32, RgbImage)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.source {
+            Source::Loaded { frame_count } => self.next_loaded(frame_count),
+            Source::Stream { finished: true } => None,
+            Source::Stream { finished: false } => self.next_streamed(),
+        }
+    }
+}
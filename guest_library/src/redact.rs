@@ -0,0 +1,93 @@
+use crate::Detection;
+
+/// Area of a frame to redact, in pixels of the frame
+#[derive(Debug, Clone, PartialEq)]
+pub enum Region {
+    Rect {
+        x: f32,
+        y: f32,
+        width: f32,
+        height: f32,
+    },
+    /// Corners of a polygon, at least three
+    Polygon(Vec<[f32; 2]>),
+}
+
+impl From<&Detection> for Region {
+    fn from(detection: &Detection) -> Self {
+        Region::Rect {
+            x: detection.x,
+            y: detection.y,
+            width: detection.width,
+            height: detection.height,
+        }
+    }
+}
+
+impl Region {
+    fn to_json(&self) -> String {
+        match self {
+            Region::Rect {
+                x,
+                y,
+                width,
+                height,
+            } => format!("{{\"x\":{x},\"y\":{y},\"width\":{width},\"height\":{height}}}"),
+            Region::Polygon(points) => {
+                let points: Vec<String> =
+                    points.iter().map(|[x, y]| format!("[{x},{y}]")).collect();
+                format!("{{\"points\":[{}]}}", points.join(","))
+            }
+        }
+    }
+}
+/// What `VideoReader::redact_regions` does to the regions. Blur radius goes from 1 to 64, block
+/// size from 1 up to the longer side of the frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RedactMethod {
+    /// Gaussian blur with the given radius in pixels
+    Blur { radius: u32 },
+    /// Blocks of the given size in pixels filled with their average colour
+    Pixelate { block_size: u32 },
+    /// Solid RGB colour
+    Fill([u8; 3]),
+}
+
+impl Default for RedactMethod {
+    fn default() -> Self {
+        RedactMethod::Blur { radius: 15 }
+    }
+}
+
+/// How `VideoReader::redact_regions` redacts
+#[derive(Debug, Clone, Default)]
+pub struct RedactStyle {
+    pub method: RedactMethod,
+    /// Pixels around each region that are redacted as well
+    pub padding: f32,
+    /// Width in pixels over which the redaction fades out around the padded region
+    pub feather: f32,
+}
+
+impl RedactStyle {
+    pub(crate) fn to_json(&self, regions: &[Region]) -> String {
+        let regions: Vec<String> = regions.iter().map(Region::to_json).collect();
+        let method = match self.method {
+            RedactMethod::Blur { radius } => {
+                format!("\"method\":\"blur\",\"blur_radius\":{radius}")
+            }
+            RedactMethod::Pixelate { block_size } => {
+                format!("\"method\":\"pixelate\",\"block_size\":{block_size}")
+            }
+            RedactMethod::Fill([red, green, blue]) => {
+                format!("\"method\":\"fill\",\"color\":[{red},{green},{blue}]")
+            }
+        };
+        format!(
+            "{{\"regions\":[{}],{method},\"padding\":{},\"feather\":{}}}",
+            regions.join(","),
+            self.padding,
+            self.feather
+        )
+    }
+}
@@ -0,0 +1,34 @@
+use crate::{FrameTransform, TensorDataType};
+
+/// A frame as planar NCHW tensor with a batch of one, ready to be fed to a model
+#[derive(Debug, Clone)]
+pub struct Tensor {
+    /// Little endian elements of `data_type`, one plane of `width` x `height` per channel
+    pub data: Vec<u8>,
+    pub width: u32,
+    pub height: u32,
+    pub data_type: TensorDataType,
+    /// Where the frame ended up in the tensor, apply it before `VideoReader::frame_transform`
+    /// to map a point of the tensor back to the source video
+    pub frame_transform: FrameTransform,
+}
+
+impl Tensor {
+    /// Shape as models expect it, `[1, 3, height, width]`
+    pub fn shape(&self) -> [usize; 4] {
+        [1, 3, self.height as usize, self.width as usize]
+    }
+
+    /// Elements of an `F32` tensor, `None` for other data types
+    pub fn to_f32(&self) -> Option<Vec<f32>> {
+        if self.data_type != TensorDataType::F32 {
+            return None;
+        }
+        Some(
+            self.data
+                .chunks_exact(4)
+                .map(|bytes| f32::from_le_bytes(bytes.try_into().expect("chunk of 4 bytes")))
+                .collect(),
+        )
+    }
+}
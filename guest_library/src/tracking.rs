@@ -0,0 +1,32 @@
+/// Settings of the tracker that assigns ids to detections across frames
+#[derive(Debug, Clone)]
+pub struct TrackerOptions {
+    /// Frames a track survives without a matching detection
+    pub max_age: u32,
+    /// Matches a track needs before its id is handed out
+    pub min_hits: u32,
+    /// Minimum overlap of a detection and the predicted box of a track to match them
+    pub iou_threshold: f32,
+    /// Only match detections and tracks of the same class
+    pub class_aware: bool,
+}
+
+impl Default for TrackerOptions {
+    fn default() -> Self {
+        TrackerOptions {
+            max_age: 30,
+            min_hits: 3,
+            iou_threshold: 0.3,
+            class_aware: true,
+        }
+    }
+}
+
+impl TrackerOptions {
+    pub(crate) fn to_json(&self) -> String {
+        format!(
+            "{{\"max_age\":{},\"min_hits\":{},\"iou_threshold\":{},\"class_aware\":{}}}",
+            self.max_age, self.min_hits, self.iou_threshold, self.class_aware
+        )
+    }
+}
@@ -0,0 +1,142 @@
+use std::rc::Rc;
+
+use image::RgbImage;
+
+use crate::{error::check, ffi, Error, Result, Session};
+
+/// Collects the output frames of a video opened with `VideoReader::open`,
+/// every frame has to be written before `finish` encodes them
+pub struct VideoWriter {
+    session: Rc<Session>,
+    width: u32,
+    height: u32,
+}
+
+impl VideoWriter {
+    pub(crate) fn new(session: Rc<Session>, width: u32, height: u32) -> Self {
+        VideoWriter {
+            session,
+            width,
+            height,
+        }
+    }
+
+    /// Replaces the frame at `index` in the output
+    pub fn write(&mut self, index: u32, image: &RgbImage) -> Result<()> {
+        write_frame(&self.session, (self.width, self.height), index, image)
+    }
+
+    /// Encodes all written frames into the video at `path`
+    pub fn finish(self, path: &str) -> Result<()> {
+        check(unsafe {
+            ffi::assemble_output_frames_to_video(
+                self.session.0,
+                path.as_ptr() as usize as i32,
+                path.len() as i32,
+            )
+        })
+    }
+
+    /// Encodes all written frames into a video held in memory, e.g. to send it in a response.
+    /// The container is the one of the encoder options, MP4 if there is none.
+    pub fn finish_to_bytes(self) -> Result<Vec<u8>> {
+        check(unsafe { ffi::assemble_output_frames_to_memory(self.session.0) })?;
+        read_output_bytes(&self.session)
+    }
+}
+
+/// Encodes the output frames of a video opened with `VideoReader::open_stream`
+/// as soon as they are written, which has to happen in order
+pub struct StreamWriter {
+    session: Rc<Session>,
+    width: u32,
+    height: u32,
+}
+
+impl StreamWriter {
+    pub(crate) fn open(session: Rc<Session>, width: u32, height: u32, path: &str) -> Result<Self> {
+        check(unsafe {
+            ffi::open_video_encoder(session.0, path.as_ptr() as usize as i32, path.len() as i32)
+        })?;
+        Ok(StreamWriter {
+            session,
+            width,
+            height,
+        })
+    }
+
+    /// Like `open`, but the video is encoded into memory, MP4s are fragmented
+    pub(crate) fn open_to_memory(session: Rc<Session>, width: u32, height: u32) -> Result<Self> {
+        check(unsafe { ffi::open_video_encoder_to_memory(session.0) })?;
+        Ok(StreamWriter {
+            session,
+            width,
+            height,
+        })
+    }
+
+    /// Encodes the output for the frame at `index`, the frame after the last one written
+    pub fn write(&mut self, index: u32, image: &RgbImage) -> Result<()> {
+        write_frame(&self.session, (self.width, self.height), index, image)
+    }
+
+    /// Bytes of a video encoded into memory that were written since the last call,
+    /// to send them on while encoding continues
+    pub fn take_bytes(&mut self) -> Result<Vec<u8>> {
+        read_output_bytes(&self.session)
+    }
+
+    /// Flushes the encoder and finalises the output file
+    pub fn finish(self) -> Result<()> {
+        check(unsafe { ffi::finish_video_encoder(self.session.0) })
+    }
+
+    /// Flushes the encoder of a video encoded into memory and returns the bytes
+    /// not taken yet with `take_bytes`
+    pub fn finish_to_bytes(self) -> Result<Vec<u8>> {
+        check(unsafe { ffi::finish_video_encoder(self.session.0) })?;
+        read_output_bytes(&self.session)
+    }
+}
+
+// Reads the encoded bytes the plugin holds for the session until it has none left
+fn read_output_bytes(session: &Session) -> Result<Vec<u8>> {
+    const CHUNK_SIZE: usize = 1 << 20;
+
+    let mut bytes = Vec::new();
+    loop {
+        bytes.reserve(CHUNK_SIZE);
+        let mut len = 0;
+        check(unsafe {
+            ffi::read_output_bytes(
+                session.0,
+                bytes.spare_capacity_mut().as_mut_ptr() as usize as i32,
+                CHUNK_SIZE as i32,
+                std::ptr::addr_of_mut!(len),
+            )
+        })?;
+        if len == 0 {
+            return Ok(bytes);
+        }
+        // The plugin has initialised `len` bytes of the spare capacity
+        unsafe { bytes.set_len(bytes.len() + len as usize) };
+    }
+}
+
+fn write_frame(session: &Session, size: (u32, u32), index: u32, image: &RgbImage) -> Result<()> {
+    if image.dimensions() != size {
+        return Err(Error::ImageSize {
+            expected: size,
+            actual: image.dimensions(),
+        });
+    }
+
+    check(unsafe {
+        ffi::write_frame(
+            session.0,
+            index as i32,
+            image.as_ptr() as usize as i32,
+            image.len() as i32,
+        )
+    })
+}
@@ -0,0 +1,477 @@
+use std::{
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::detection::Detection;
+
+/// Sidecar formats the detections of a session can be written in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AnnotationFormat {
+    // One JSON object per frame with its timestamp, `<video>.jsonl`
+    JsonLines,
+    // CocoVID JSON as used by video tracking tools, `<video>.coco.json`
+    CocoVideo,
+    // MOT Challenge CSV, `<video>.mot.txt`
+    Mot,
+    // One YOLO label file per frame, `<video>_labels/<frame>.txt`
+    Yolo,
+}
+
+/// Which sidecar files are written next to the output video, sent by the guest as JSON
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AnnotationOptions {
+    pub formats: Vec<AnnotationFormat>,
+    // Names of the class ids for the COCO categories, ids without a name are called by their id
+    pub class_names: Vec<String>,
+}
+
+/// Detections of a single frame together with its position in the video
+pub struct AnnotatedFrame<'a> {
+    pub index: usize,
+    // Timestamp in the time base of the input video stream
+    pub timestamp: Option<i64>,
+    pub time_seconds: Option<f64>,
+    pub detections: &'a [Detection],
+}
+
+#[derive(Serialize)]
+struct JsonLinesRecord<'a> {
+    frame: usize,
+    timestamp: Option<i64>,
+    time_seconds: Option<f64>,
+    detections: &'a [Detection],
+}
+
+/// Writes every format of `options` next to `video_path`.
+/// `frames` holds every frame of the video, also those without detections.
+pub fn write_annotations(
+    video_path: &str,
+    options: &AnnotationOptions,
+    frames: &[AnnotatedFrame],
+    width: u32,
+    height: u32,
+) -> std::io::Result<()> {
+    let video_path = Path::new(video_path);
+    for format in &options.formats {
+        match format {
+            AnnotationFormat::JsonLines => {
+                write_json_lines(&video_path.with_extension("jsonl"), frames)?
+            }
+            AnnotationFormat::CocoVideo => write_coco_video(
+                &video_path.with_extension("coco.json"),
+                video_path,
+                options,
+                frames,
+                width,
+                height,
+            )?,
+            AnnotationFormat::Mot => write_mot(&video_path.with_extension("mot.txt"), frames)?,
+            AnnotationFormat::Yolo => {
+                write_yolo(&labels_directory(video_path), frames, width, height)?
+            }
+        }
+    }
+    Ok(())
+}
+
+fn write_json_lines(path: &Path, frames: &[AnnotatedFrame]) -> std::io::Result<()> {
+    let mut writer = BufWriter::new(create_file(path)?);
+    for frame in frames {
+        let record = JsonLinesRecord {
+            frame: frame.index,
+            timestamp: frame.timestamp,
+            time_seconds: frame.time_seconds,
+            detections: frame.detections,
+        };
+        serde_json::to_writer(&mut writer, &record)?;
+        writeln!(writer)?;
+    }
+    writer.flush()
+}
+
+// The whole output video is one CocoVID video, every frame one of its images
+fn write_coco_video(
+    path: &Path,
+    video_path: &Path,
+    options: &AnnotationOptions,
+    frames: &[AnnotatedFrame],
+    width: u32,
+    height: u32,
+) -> std::io::Result<()> {
+    let video_name = video_path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    let images: Vec<_> = frames
+        .iter()
+        .map(|frame| {
+            json!({
+                "id": frame.index + 1,
+                "video_id": 1,
+                "frame_id": frame.index,
+                "file_name": format!("{video_name}/{:06}", frame.index),
+                "width": width,
+                "height": height,
+                "time_seconds": frame.time_seconds,
+            })
+        })
+        .collect();
+
+    let mut class_ids: Vec<u32> = Vec::new();
+    let mut annotations = Vec::new();
+    for frame in frames {
+        for detection in frame.detections {
+            if !class_ids.contains(&detection.class_id) {
+                class_ids.push(detection.class_id);
+            }
+            annotations.push(json!({
+                "id": annotations.len() + 1,
+                "image_id": frame.index + 1,
+                "video_id": 1,
+                "category_id": detection.class_id,
+                "instance_id": detection.track_id,
+                "bbox": [detection.x, detection.y, detection.width, detection.height],
+                "area": detection.width * detection.height,
+                "score": detection.score,
+                "iscrowd": 0,
+            }));
+        }
+    }
+    class_ids.sort_unstable();
+
+    let categories: Vec<_> = class_ids
+        .iter()
+        .map(|&class_id| {
+            let name = options
+                .class_names
+                .get(class_id as usize)
+                .cloned()
+                .unwrap_or_else(|| class_id.to_string());
+            json!({ "id": class_id, "name": name })
+        })
+        .collect();
+
+    let coco = json!({
+        "videos": [{ "id": 1, "name": video_name, "width": width, "height": height }],
+        "images": images,
+        "annotations": annotations,
+        "categories": categories,
+    });
+
+    let mut writer = BufWriter::new(create_file(path)?);
+    serde_json::to_writer(&mut writer, &coco)?;
+    writer.flush()
+}
+
+// frame,id,bb_left,bb_top,bb_width,bb_height,conf,x,y,z with frames counted from 1
+fn write_mot(path: &Path, frames: &[AnnotatedFrame]) -> std::io::Result<()> {
+    let mut writer = BufWriter::new(create_file(path)?);
+    for frame in frames {
+        for detection in frame.detections {
+            writeln!(
+                writer,
+                "{},{},{:.2},{:.2},{:.2},{:.2},{:.4},-1,-1,-1",
+                frame.index + 1,
+                detection.track_id.map_or(-1, i64::from),
+                detection.x,
+                detection.y,
+                detection.width,
+                detection.height,
+                detection.score
+            )?;
+        }
+    }
+    writer.flush()
+}
+
+// `class center_x center_y width height` relative to the frame size, one file per frame
+fn write_yolo(
+    directory: &Path,
+    frames: &[AnnotatedFrame],
+    width: u32,
+    height: u32,
+) -> std::io::Result<()> {
+    refuse_symlink(directory)?;
+    fs::create_dir_all(directory)?;
+    let (width, height) = (width as f32, height as f32);
+    for frame in frames {
+        let mut writer = BufWriter::new(create_file(
+            &directory.join(format!("{:06}.txt", frame.index)),
+        )?);
+        for detection in frame.detections {
+            writeln!(
+                writer,
+                "{} {:.6} {:.6} {:.6} {:.6}",
+                detection.class_id,
+                (detection.x + detection.width / 2.0) / width,
+                (detection.y + detection.height / 2.0) / height,
+                detection.width / width,
+                detection.height / height
+            )?;
+        }
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+fn labels_directory(video_path: &Path) -> PathBuf {
+    let stem = video_path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    video_path.with_file_name(format!("{stem}_labels"))
+}
+
+// The files are written next to a video inside the directories of the plugin,
+// a symlink already lying there could lead out of them
+fn create_file(path: &Path) -> std::io::Result<File> {
+    refuse_symlink(path)?;
+    File::create(path)
+}
+
+fn refuse_symlink(path: &Path) -> std::io::Result<()> {
+    if fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_symlink()) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            format!("{} is a symlink", path.display()),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+
+    use serde_json::Value;
+
+    use super::*;
+
+    // A fresh directory per test, tests run in parallel
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("annotations-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn detection(class_id: u32, track_id: Option<u32>) -> Detection {
+        Detection {
+            x: 10.0,
+            y: 20.0,
+            width: 40.0,
+            height: 60.0,
+            class_id,
+            score: 0.5,
+            label: None,
+            color: None,
+            track_id,
+        }
+    }
+
+    // Writes a 200 x 100 video of the frames in one format
+    fn write(
+        dir: &Path,
+        format: AnnotationFormat,
+        class_names: &[&str],
+        frames: &[AnnotatedFrame],
+    ) -> std::io::Result<()> {
+        let options = AnnotationOptions {
+            formats: vec![format],
+            class_names: class_names.iter().map(|name| name.to_string()).collect(),
+        };
+        write_annotations(
+            dir.join("out.mp4").to_str().unwrap(),
+            &options,
+            frames,
+            200,
+            100,
+        )
+    }
+
+    #[test]
+    fn json_lines_hold_one_frame_per_line() {
+        let dir = temp_dir("jsonl");
+        let detections = [Detection {
+            label: Some("person".to_string()),
+            ..detection(0, Some(3))
+        }];
+        let frames = [
+            AnnotatedFrame {
+                index: 0,
+                timestamp: Some(512),
+                time_seconds: Some(0.5),
+                detections: &detections,
+            },
+            AnnotatedFrame {
+                index: 1,
+                timestamp: None,
+                time_seconds: None,
+                detections: &[],
+            },
+        ];
+        write(&dir, AnnotationFormat::JsonLines, &[], &frames).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(dir.join("out.jsonl")).unwrap(),
+            concat!(
+                r#"{"frame":0,"timestamp":512,"time_seconds":0.5,"detections":[{"x":10.0,"y":20.0,"#,
+                r#""width":40.0,"height":60.0,"class_id":0,"score":0.5,"label":"person","track_id":3}]}"#,
+                "\n",
+                r#"{"frame":1,"timestamp":null,"time_seconds":null,"detections":[]}"#,
+                "\n"
+            )
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn mot_counts_from_one_and_fills_unused_fields() {
+        let dir = temp_dir("mot");
+        let first = [detection(0, Some(3))];
+        let second = [detection(1, Some(0)), detection(0, None)];
+        let frames = [
+            AnnotatedFrame {
+                index: 0,
+                timestamp: None,
+                time_seconds: None,
+                detections: &first,
+            },
+            AnnotatedFrame {
+                index: 1,
+                timestamp: None,
+                time_seconds: None,
+                detections: &second,
+            },
+        ];
+        write(&dir, AnnotationFormat::Mot, &[], &frames).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(dir.join("out.mot.txt")).unwrap(),
+            "1,3,10.00,20.00,40.00,60.00,0.5000,-1,-1,-1\n\
+             2,0,10.00,20.00,40.00,60.00,0.5000,-1,-1,-1\n\
+             2,-1,10.00,20.00,40.00,60.00,0.5000,-1,-1,-1\n"
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn yolo_labels_are_normalised_centers() {
+        let dir = temp_dir("yolo");
+        let detections = [detection(2, None)];
+        let frames = [
+            AnnotatedFrame {
+                index: 0,
+                timestamp: None,
+                time_seconds: None,
+                detections: &detections,
+            },
+            AnnotatedFrame {
+                index: 1,
+                timestamp: None,
+                time_seconds: None,
+                detections: &[],
+            },
+        ];
+        write(&dir, AnnotationFormat::Yolo, &[], &frames).unwrap();
+
+        let labels = dir.join("out_labels");
+        assert_eq!(
+            fs::read_to_string(labels.join("000000.txt")).unwrap(),
+            "2 0.150000 0.500000 0.200000 0.600000\n"
+        );
+        // Frames without detections still get their file
+        assert_eq!(fs::read_to_string(labels.join("000001.txt")).unwrap(), "");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn coco_maps_frames_to_images_and_classes_to_categories() {
+        let dir = temp_dir("coco");
+        let first = [detection(5, Some(1))];
+        let second = [detection(1, Some(2))];
+        let frames = [
+            AnnotatedFrame {
+                index: 0,
+                timestamp: None,
+                time_seconds: Some(0.0),
+                detections: &first,
+            },
+            AnnotatedFrame {
+                index: 1,
+                timestamp: None,
+                time_seconds: Some(0.04),
+                detections: &second,
+            },
+        ];
+        write(
+            &dir,
+            AnnotationFormat::CocoVideo,
+            &["person", "car"],
+            &frames,
+        )
+        .unwrap();
+
+        let coco: Value =
+            serde_json::from_str(&fs::read_to_string(dir.join("out.coco.json")).unwrap()).unwrap();
+        let ids = |key: &str, field: &str| -> Vec<Value> {
+            coco[key]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|item| item[field].clone())
+                .collect()
+        };
+        assert_eq!(ids("images", "id"), [1, 2]);
+        assert_eq!(ids("images", "frame_id"), [0, 1]);
+        assert_eq!(ids("annotations", "image_id"), [1, 2]);
+        assert_eq!(ids("annotations", "category_id"), [5, 1]);
+        assert_eq!(ids("annotations", "instance_id"), [1, 2]);
+        // Sorted by id, class 5 has no name
+        assert_eq!(
+            coco["categories"],
+            json!([{ "id": 1, "name": "car" }, { "id": 5, "name": "5" }])
+        );
+        assert_eq!(
+            coco["videos"],
+            json!([{ "id": 1, "name": "out.mp4", "width": 200, "height": 100 }])
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn symlinked_targets_are_refused() {
+        let dir = temp_dir("symlinks");
+        let outside = temp_dir("symlinks-outside");
+        fs::write(outside.join("secret"), b"secret").unwrap();
+        symlink(outside.join("secret"), dir.join("out.jsonl")).unwrap();
+        symlink(&outside, dir.join("out_labels")).unwrap();
+        let frames = [AnnotatedFrame {
+            index: 0,
+            timestamp: None,
+            time_seconds: None,
+            detections: &[],
+        }];
+
+        for format in [AnnotationFormat::JsonLines, AnnotationFormat::Yolo] {
+            let error = write(&dir, format, &[], &frames).unwrap_err();
+            assert_eq!(
+                error.kind(),
+                std::io::ErrorKind::PermissionDenied,
+                "{format:?}"
+            );
+        }
+        assert_eq!(fs::read(outside.join("secret")).unwrap(), b"secret");
+        assert!(!outside.join("000000.txt").exists());
+        fs::remove_dir_all(dir).unwrap();
+        fs::remove_dir_all(outside).unwrap();
+    }
+}
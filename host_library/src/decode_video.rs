@@ -1,23 +1,38 @@
 use ffmpeg::{
     codec, dictionary, encoder,
-    format::{input, Pixel},
+    format::{context::Input, input_with_dictionary, Pixel},
     frame,
     media::Type,
-    software::scaling::{context::Context, flag::Flags},
-    util::frame::video::Video,
+    picture, Packet,
 };
 
+use ffmpeg::util::mathematics::rescale::Rescale;
 use ffmpeg::Error as FFmpegError;
+use std::time::Duration;
 
 use log::debug;
+use serde::Deserialize;
 
 use crate::{
-    AspectRatio, BitRate, FrameMap, FrameRate, Frames, Height, MaxBitRate, VideoInfo, Width,
+    filter_graph::{FilterGraph, FilterInput},
+    memory_io::{self, MemoryInput},
+    resize::{FrameTransform, Interpolation, ResizeMode, Resizer, DEFAULT_PAD_COLOR},
+    rotation::{display_rotation, rotation_filter},
+    sandbox, AspectRatio, BitRate, FrameMap, FrameRate, Frames, Height, MaxBitRate,
+    PassthroughStream, VideoInfo, Width,
 };
+
+// Largest width and height of the frames handed out, FFmpeg's encoders and the frame
+// sizes of common codecs stop here as well
+const MAX_FRAME_SIZE: u32 = 16384;
+
 #[derive(Debug)]
 pub enum VideoDecoderError {
     FFMpegError(FFmpegError),
     CodecError(String),
+    InvalidOption(String),
+    // The filter string of DecodeOptions could not be turned into a filter graph
+    InvalidFilter(String),
 }
 
 impl From<FFmpegError> for VideoDecoderError {
@@ -26,121 +41,599 @@ impl From<FFmpegError> for VideoDecoderError {
     }
 }
 
-pub fn dump_frames(filename: &String) -> Result<(Frames, VideoInfo), VideoDecoderError> {
-    ffmpeg::init()?;
+/// Options the guest can send as JSON when opening a video, everything is optional.
+/// The sampling options pick from the decoded frames, the ones they drop are never filtered
+/// or converted to RGB.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DecodeOptions {
+    // Only decode the frames shown from start_us up to end_us, in microseconds from the start
+    pub start_us: Option<u64>,
+    pub end_us: Option<u64>,
+    // Keep every Nth decoded frame
+    pub every_nth_frame: Option<u32>,
+    // Resample to this frame rate based on the frame timestamps
+    pub target_fps: Option<f64>,
+    // Keep intra coded frames only
+    pub key_frames_only: bool,
+    // Size of the frames handed out, with only one of them the aspect ratio is kept
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub resize_mode: ResizeMode,
+    // RGB colour of the letterbox borders
+    pub pad_color: Option<[u8; 3]>,
+    pub interpolation: Interpolation,
+    // FFmpeg filter string applied to the decoded frames before they are resized,
+    // e.g. "hqdn3d,eq=contrast=1.2,transpose=1"
+    pub filter: Option<String>,
+    // Turn frames of videos with rotation metadata upright, before the filter runs
+    pub auto_rotate: bool,
+}
 
-    let mut frame_index = 0;
-    let mut frames = Vec::new();
-    let codec;
-    let input = input(filename);
-    let (width, height, aspect_ratio, frame_rate, format);
-    let input_stream_meta_data: dictionary::Owned;
-
-    let itcx_number_streams;
-
-    let (bitrate, max_bitrate);
-
-    match input {
-        Ok(mut ictx) => {
-            let input = ictx
-                .streams()
-                .best(Type::Video)
-                .ok_or(ffmpeg::Error::StreamNotFound)?;
-            itcx_number_streams = ictx.nb_streams();
-
-            let video_stream_index: usize = input.index();
-
-            input_stream_meta_data = ictx.metadata().to_owned();
-
-            let mut decoder = input.decoder()?.video()?;
-
-            codec = encoder::find(codec::Id::H264).ok_or(VideoDecoderError::CodecError(
-                "Could not Find Codec h264".into(),
-            ))?;
-
-            debug!("Decoder Codec");
-            debug!("  BitRate {:?}", decoder.bit_rate());
-            debug!("  MaxBitRate {:?}", decoder.max_bit_rate());
-            debug!("  TimeBase {:?}", decoder.time_base());
-            debug!("  Codec");
-            debug!("      Name  {:?}", codec.name());
-            debug!("      Descr {:?}", codec.description());
-
-            // I am wrapping these in Structs so its less likely that I make Type Errors
-            bitrate = BitRate(decoder.bit_rate());
-            max_bitrate = MaxBitRate(decoder.bit_rate());
-            width = Width(decoder.width());
-            height = Height(decoder.height());
-            aspect_ratio = AspectRatio(decoder.aspect_ratio());
-            frame_rate = FrameRate(decoder.frame_rate());
-            format = decoder.format();
-
-            // Scaler to convert YUV420 encoded frame -> RGB Raw frame
-            let mut scaler = Context::get(
-                decoder.format(),
-                decoder.width(),
-                decoder.height(),
-                Pixel::RGB24,
-                decoder.width(),
-                decoder.height(),
-                Flags::BILINEAR,
-            )?;
-
-            // Closure to process out frames
-            let mut receive_and_process_decoded_frames =
-                |decoder: &mut ffmpeg::decoder::Video| -> Result<(), ffmpeg::Error> {
-                    let mut decoded_frame = frame::Video::empty();
-                    while decoder.receive_frame(&mut decoded_frame).is_ok() {
-                        let mut rgb_frame = Video::empty();
-                        scaler.run(&decoded_frame, &mut rgb_frame)?;
-                        debug!(
-                            "R_Frame {frame_index} : {:?} {:?} {:?} {:?} ",
-                            decoded_frame.kind(),
-                            decoded_frame.timestamp(),
-                            decoded_frame.duration(),
-                            decoded_frame.display_number()
-                        );
-
-                        let frame_map = FrameMap {
-                            input_frame: rgb_frame,
-                            frame_type: decoded_frame.kind(),
-                            timestamp: decoded_frame.timestamp(),
-                            output_frame: None,
-                        };
-
-                        frames.push(frame_map);
-                        frame_index += 1;
-                    }
-                    Ok(())
+impl DecodeOptions {
+    pub fn validate(&self) -> Result<(), VideoDecoderError> {
+        if let (Some(start_us), Some(end_us)) = (self.start_us, self.end_us) {
+            if end_us <= start_us {
+                return Err(VideoDecoderError::InvalidOption(format!(
+                    "Range end {end_us}us is not after range start {start_us}us"
+                )));
+            }
+        }
+        if self.every_nth_frame == Some(0) {
+            return Err(VideoDecoderError::InvalidOption(
+                "every_nth_frame has to be at least 1".into(),
+            ));
+        }
+        if let Some(target_fps) = self.target_fps {
+            if !(target_fps > 0.0 && target_fps.is_finite()) {
+                return Err(VideoDecoderError::InvalidOption(format!(
+                    "target_fps {target_fps} has to be a positive number"
+                )));
+            }
+        }
+        if self.width == Some(0) || self.height == Some(0) {
+            return Err(VideoDecoderError::InvalidOption(
+                "Frame width and height have to be at least 1".into(),
+            ));
+        }
+        if self.width.max(self.height) > Some(MAX_FRAME_SIZE) {
+            return Err(VideoDecoderError::InvalidOption(format!(
+                "Frame width and height can be at most {MAX_FRAME_SIZE}"
+            )));
+        }
+        if self
+            .filter
+            .as_deref()
+            .is_some_and(|filter| filter.trim().is_empty())
+        {
+            return Err(VideoDecoderError::InvalidFilter(
+                "Filter string is empty".into(),
+            ));
+        }
+        Ok(())
+    }
+
+    // Where frames of the source size end up in the frames handed out
+    fn frame_transform(&self, source_width: u32, source_height: u32) -> FrameTransform {
+        let scaled = |size: u32, from: u32, to: u32| {
+            ((size as f64 * to as f64 / from as f64).round() as u32).max(1)
+        };
+        let (width, height) = match (self.width, self.height) {
+            (Some(width), Some(height)) => (width, height),
+            (Some(width), None) => (width, scaled(source_height, source_width, width)),
+            (None, Some(height)) => (scaled(source_width, source_height, height), height),
+            (None, None) => return FrameTransform::identity(source_width, source_height),
+        };
+        FrameTransform::new(source_width, source_height, width, height, self.resize_mode)
+    }
+}
+
+/// Keeps the input context, decoder and scaler of a video alive so that frames
+/// can be decoded one at a time instead of dumping the whole file into memory.
+pub(crate) struct VideoDecoder {
+    // Input Context
+    ictx: Input,
+    // Decoder for the best video stream of the input
+    decoder: ffmpeg::decoder::Video,
+    // Filter graph of DecodeOptions, turns the decoded frames into RGB frames
+    filter_graph: Option<FilterGraph>,
+    // Scaler to convert YUV420 encoded frame -> RGB Raw frame at the requested size
+    resizer: Resizer,
+    video_stream_index: usize,
+    video_info: VideoInfo,
+    // Number of frames handed out so far
+    frame_index: usize,
+    // Set once the decoder has been told that there are no more packets
+    eof_sent: bool,
+    // Packets of the audio, subtitle and data streams read so far, copied into the output as they are
+    passthrough_packets: Vec<Packet>,
+    // Timestamp of the first frame of the video stream, in its time base
+    start_time: i64,
+    // After a seek, decoded frames before this timestamp are dropped
+    skip_until: Option<i64>,
+    options: DecodeOptions,
+    // Range of DecodeOptions in the stream time base
+    range_start: Option<i64>,
+    range_end: Option<i64>,
+    // Set once a frame at or after range_end has been decoded
+    range_ended: bool,
+    // Time between two frames for target_fps, in the stream time base
+    sample_interval: Option<f64>,
+    // Timestamp from which on the next frame is kept for target_fps
+    next_sample_time: Option<f64>,
+    // Frames decoded since opening or the last seek, for every_nth_frame
+    decoded_frames: u64,
+    // Set for videos read from memory, declared after ictx so that it is dropped after it
+    _memory_input: Option<MemoryInput>,
+}
+
+// The scaler wraps a raw SwsContext pointer and is therefore not Send,
+// a VideoDecoder only ever lives behind the plugin Mutex so it is never used by two threads at once.
+unsafe impl Send for VideoDecoder {}
+
+impl VideoDecoder {
+    pub fn open(filename: &String, options: &DecodeOptions) -> Result<Self, VideoDecoderError> {
+        options.validate()?;
+        ffmpeg::init()?;
+
+        let ictx = input_with_dictionary(filename, sandbox::input_options(Some("file")))?;
+        Self::open_input(ictx, None, options)
+    }
+
+    /// Like `open` for the bytes of a whole video file instead of a path
+    pub fn open_memory(data: Vec<u8>, options: &DecodeOptions) -> Result<Self, VideoDecoderError> {
+        options.validate()?;
+        ffmpeg::init()?;
+
+        let (ictx, memory_input) = memory_io::open_input(data, sandbox::input_options(None))?;
+        Self::open_input(ictx, Some(memory_input), options)
+    }
+
+    fn open_input(
+        ictx: Input,
+        memory_input: Option<MemoryInput>,
+        options: &DecodeOptions,
+    ) -> Result<Self, VideoDecoderError> {
+        let input = ictx
+            .streams()
+            .best(Type::Video)
+            .ok_or(ffmpeg::Error::StreamNotFound)?;
+        let itcx_number_streams = ictx.nb_streams();
+
+        let video_stream_index: usize = input.index();
+        let time_base = input.time_base().unwrap_or(ffmpeg::rescale::TIME_BASE);
+        let start_time = match input.start_time() {
+            start_time if start_time == ffmpeg::ffi::AV_NOPTS_VALUE => 0,
+            start_time => start_time,
+        };
+
+        let input_stream_meta_data: dictionary::Owned = ictx.metadata().to_owned();
+
+        // Container duration is in AV_TIME_BASE (microseconds), negative when unknown
+        let duration = match ictx.duration() {
+            duration if duration >= 0 => Some(Duration::from_micros(duration as u64)),
+            _ => None,
+        };
+
+        // Every other audio, subtitle and data stream is remuxed into the output unchanged
+        let passthrough_streams: Vec<PassthroughStream> = ictx
+            .streams()
+            .filter(|stream| stream.index() != video_stream_index)
+            .filter(|stream| {
+                matches!(
+                    stream.parameters().medium(),
+                    Type::Audio | Type::Subtitle | Type::Data
+                )
+            })
+            .map(|stream| PassthroughStream {
+                index: stream.index(),
+                parameters: stream.parameters().clone(),
+                time_base: stream.time_base().unwrap_or(ffmpeg::rescale::TIME_BASE),
+            })
+            .collect();
+
+        let rotation = display_rotation(&input);
+        let applied_rotation = if options.auto_rotate { rotation } else { 0 };
+
+        let decoder = input.decoder()?.video()?;
+
+        let codec = encoder::find(codec::Id::H264).ok_or(VideoDecoderError::CodecError(
+            "Could not Find Codec h264".into(),
+        ))?;
+
+        debug!("Decoder Codec");
+        debug!("  BitRate {:?}", decoder.bit_rate());
+        debug!("  MaxBitRate {:?}", decoder.max_bit_rate());
+        debug!("  TimeBase {:?}", decoder.time_base());
+        debug!("  Codec");
+        debug!("      Name  {:?}", codec.name());
+        debug!("      Descr {:?}", codec.description());
+
+        // Filters may change the size, aspect ratio and frame rate of the frames
+        let filter: Vec<&str> = [rotation_filter(applied_rotation), options.filter.as_deref()]
+            .into_iter()
+            .flatten()
+            .collect();
+        let mut filter_graph = (!filter.is_empty())
+            .then(|| {
+                let filter_input = FilterInput {
+                    format: decoder.format(),
+                    width: decoder.width(),
+                    height: decoder.height(),
+                    time_base,
+                    aspect_ratio: decoder.aspect_ratio(),
+                    frame_rate: decoder.frame_rate(),
                 };
+                FilterGraph::new(&filter.join(","), filter_input, Pixel::RGB24)
+            })
+            .transpose()
+            .map_err(VideoDecoderError::InvalidFilter)?;
+        let (source_format, (source_width, source_height), aspect_ratio, frame_rate) =
+            match filter_graph.as_mut() {
+                Some(filter_graph) => (
+                    Pixel::RGB24,
+                    filter_graph.output_size(),
+                    filter_graph.output_aspect_ratio(),
+                    filter_graph.output_frame_rate(),
+                ),
+                None => (
+                    decoder.format(),
+                    (decoder.width(), decoder.height()),
+                    decoder.aspect_ratio(),
+                    decoder.frame_rate(),
+                ),
+            };
+
+        let frame_transform = options.frame_transform(source_width, source_height);
+
+        // I am wrapping these in Structs so its less likely that I make Type Errors
+        let video_info = VideoInfo {
+            codec,
+            format: decoder.format(),
+            width: Width(frame_transform.width),
+            height: Height(frame_transform.height),
+            aspect_ratio: AspectRatio(aspect_ratio),
+            frame_rate: FrameRate(frame_rate),
+            input_stream_meta_data,
+            itcx_number_streams,
+            bitrate: BitRate(decoder.bit_rate()),
+            max_bitrate: MaxBitRate(decoder.bit_rate()),
+            codec_name: decoder.id().name().to_string(),
+            duration,
+            time_base,
+            passthrough_streams,
+            frame_transform,
+            rotation,
+            applied_rotation,
+            range_offset: 0,
+        };
+
+        let resizer = Resizer::new(
+            source_format,
+            frame_transform,
+            options.interpolation,
+            options.pad_color.unwrap_or(DEFAULT_PAD_COLOR),
+        )?;
+
+        let mut video_decoder = VideoDecoder {
+            ictx,
+            decoder,
+            filter_graph,
+            resizer,
+            video_stream_index,
+            video_info,
+            frame_index: 0,
+            eof_sent: false,
+            passthrough_packets: Vec::new(),
+            start_time,
+            skip_until: None,
+            options: options.clone(),
+            range_start: None,
+            range_end: None,
+            range_ended: false,
+            sample_interval: None,
+            next_sample_time: None,
+            decoded_frames: 0,
+            _memory_input: memory_input,
+        };
+
+        video_decoder.range_start = options
+            .start_us
+            .map(|start_us| video_decoder.stream_timestamp(Duration::from_micros(start_us)));
+        video_decoder.range_end = options
+            .end_us
+            .map(|end_us| video_decoder.stream_timestamp(Duration::from_micros(end_us)));
+        video_decoder.sample_interval = options
+            .target_fps
+            .map(|target_fps| 1.0 / (target_fps * f64::from(time_base)));
+
+        if let Some(range_start) = video_decoder.range_start {
+            video_decoder.video_info.range_offset = range_start - video_decoder.start_time;
+            video_decoder.seek(range_start)?;
+        }
+
+        Ok(video_decoder)
+    }
+
+    pub fn video_info(&self) -> &VideoInfo {
+        &self.video_info
+    }
+
+    /// Hands over the passthrough packets read since the last call, in the order they were read.
+    pub fn take_passthrough_packets(&mut self) -> Vec<Packet> {
+        std::mem::take(&mut self.passthrough_packets)
+    }
+
+    /// Timestamp of the frame shown `time` after the start of the stream, in the stream time base
+    pub fn stream_timestamp(&self, time: Duration) -> i64 {
+        let micros = time.as_micros().min(i64::MAX as u128) as i64;
+        self.start_time + micros.rescale(ffmpeg::rescale::TIME_BASE, self.video_info.time_base)
+    }
+
+    /// Timestamp of frame `frame_number`, counted from the start of the stream at its nominal
+    /// frame rate. `None` if the container does not tell the frame rate.
+    pub fn frame_timestamp(&self, frame_number: u64) -> Option<i64> {
+        let frame_rate = self.video_info.frame_rate.0?;
+        let offset = (frame_number as i64).rescale(frame_rate.invert(), self.video_info.time_base);
+        Some(self.start_time + offset)
+    }
+
+    /// Seeks so that the next frame returned is the one shown at `timestamp`, in the stream time base.
+    /// Demuxing restarts at the key frame before it, the frames in between are decoded and dropped.
+    pub fn seek(&mut self, timestamp: i64) -> Result<(), VideoDecoderError> {
+        let seek_timestamp =
+            timestamp.rescale(self.video_info.time_base, ffmpeg::rescale::TIME_BASE);
+        self.ictx.seek(seek_timestamp, ..seek_timestamp)?;
+
+        self.decoder.flush();
+        if let Some(filter_graph) = self.filter_graph.as_mut() {
+            filter_graph.reset()?;
+        }
+        self.eof_sent = false;
+        self.passthrough_packets.clear();
+        self.skip_until = Some(timestamp);
+        self.range_ended = false;
+        self.next_sample_time = None;
+        self.decoded_frames = 0;
+        Ok(())
+    }
+
+    /// Decodes the next frame of the video stream.
+    /// Returns `Ok(None)` once the end of the stream has been reached and the decoder is drained.
+    pub fn next_frame(&mut self) -> Result<Option<FrameMap>, VideoDecoderError> {
+        loop {
+            if let Some(frame_map) = self.receive_decoded_frame()? {
+                return Ok(Some(frame_map));
+            }
 
-            // Iterator over Input Context Packets
-            for (idx, res) in ictx.packets().enumerate() {
-                let (stream, packet) = res?;
-                if stream.index() == video_stream_index {
-                    debug!("PKT {idx} PTS{:?}   DTS:{:?}", packet.pts(), packet.dts());
-                    decoder.send_packet(&packet)?;
-                    receive_and_process_decoded_frames(&mut decoder)?;
+            if self.eof_sent || self.range_ended {
+                return Ok(None);
+            }
+
+            let mut packet = Packet::empty();
+            match packet.read(&mut self.ictx) {
+                Ok(()) => {
+                    if packet.stream() == self.video_stream_index {
+                        debug!("PKT PTS{:?}   DTS:{:?}", packet.pts(), packet.dts());
+                        self.decoder.send_packet(&packet)?;
+                    } else if self.passthrough_packet_in_range(&packet) {
+                        self.passthrough_packets.push(packet);
+                    }
+                }
+                Err(FFmpegError::Eof) => {
+                    self.decoder.send_eof()?;
+                    self.eof_sent = true;
+                }
+                Err(err) => return Err(VideoDecoderError::from(err)),
+            }
+        }
+    }
+
+    fn receive_decoded_frame(&mut self) -> Result<Option<FrameMap>, VideoDecoderError> {
+        let Some((frame, timestamp)) = self.receive_filtered_frame()? else {
+            return Ok(None);
+        };
+
+        let rgb_frame = self.resizer.run(&frame)?;
+        debug!(
+            "R_Frame {} : {:?} {:?} {:?} {:?} ",
+            self.frame_index,
+            frame.kind(),
+            timestamp,
+            frame.duration(),
+            frame.display_number()
+        );
+
+        self.frame_index += 1;
+
+        Ok(Some(FrameMap {
+            input_frame: Some(rgb_frame),
+            frame_type: frame.kind(),
+            timestamp,
+            duration: frame.packet().duration,
+            key_frame: frame.is_key(),
+            output_frame: None,
+        }))
+    }
+
+    // Next frame out of the filter graph, or straight from the decoder without one,
+    // together with its timestamp in the stream time base
+    fn receive_filtered_frame(
+        &mut self,
+    ) -> Result<Option<(frame::Video, Option<i64>)>, VideoDecoderError> {
+        loop {
+            let Some(filter_graph) = self.filter_graph.as_mut() else {
+                return Ok(self.receive_sampled_frame()?.map(|frame| {
+                    let timestamp = frame.timestamp();
+                    (frame, timestamp)
+                }));
+            };
+
+            if let Some(frame) = filter_graph.receive()? {
+                // Filters such as fps hand out frames in a time base of their own
+                let output_time_base = filter_graph.output_time_base();
+                let timestamp = frame
+                    .pts()
+                    .map(|pts| pts.rescale(output_time_base, self.video_info.time_base));
+                return Ok(Some((frame, timestamp)));
+            }
+            if filter_graph.is_flushed() {
+                return Ok(None);
+            }
+
+            let frame = self.receive_sampled_frame()?;
+            let Some(filter_graph) = self.filter_graph.as_mut() else {
+                continue;
+            };
+            match frame {
+                Some(frame) => filter_graph.send(&frame)?,
+                // The decoder is drained, the filter graph can hand out what it still holds
+                None if self.eof_sent || self.range_ended => filter_graph.flush()?,
+                None => return Ok(None),
+            }
+        }
+    }
+
+    // Next decoded frame in range the sampling options keep
+    fn receive_sampled_frame(&mut self) -> Result<Option<frame::Video>, VideoDecoderError> {
+        while let Some(frame) = self.receive_frame_in_range()? {
+            if self.keep_sampled_frame(&frame) {
+                return Ok(Some(frame));
+            }
+        }
+        Ok(None)
+    }
+
+    // Next decoded frame from the requested position up to the end of the range
+    fn receive_frame_in_range(&mut self) -> Result<Option<frame::Video>, VideoDecoderError> {
+        let mut decoded_frame = frame::Video::empty();
+        loop {
+            if self.decoder.receive_frame(&mut decoded_frame).is_err() {
+                return Ok(None);
+            }
+            let timestamp = decoded_frame.timestamp();
+
+            // Still between the key frame the seek landed on and the requested frame
+            if let (Some(skip_until), Some(timestamp)) = (self.skip_until, timestamp) {
+                if timestamp < skip_until {
+                    continue;
+                }
+            }
+            self.skip_until = None;
+
+            if let (Some(range_end), Some(timestamp)) = (self.range_end, timestamp) {
+                if timestamp >= range_end {
+                    self.range_ended = true;
+                    return Ok(None);
                 }
             }
-            decoder.send_eof()?;
-            receive_and_process_decoded_frames(&mut decoder)?;
-        }
-        Err(err) => return Err(VideoDecoderError::from(err)),
-    };
-
-    let video_info = VideoInfo {
-        codec,
-        format,
-        width,
-        height,
-        aspect_ratio,
-        frame_rate,
-        input_stream_meta_data,
-        itcx_number_streams,
-        bitrate,
-        max_bitrate,
-    };
-
-    Ok((frames, video_info))
+
+            return Ok(Some(decoded_frame));
+        }
+    }
+
+    // Applies the sampling options, frames dropped here never reach the filter graph or the scaler
+    fn keep_sampled_frame(&mut self, decoded_frame: &frame::Video) -> bool {
+        let timestamp = decoded_frame.timestamp();
+        let decoded_index = self.decoded_frames;
+        self.decoded_frames += 1;
+
+        if self.options.key_frames_only && decoded_frame.kind() != picture::Type::I {
+            return false;
+        }
+
+        if let Some(every_nth_frame) = self.options.every_nth_frame {
+            if decoded_index % u64::from(every_nth_frame) != 0 {
+                return false;
+            }
+        }
+
+        if let (Some(sample_interval), Some(timestamp)) = (self.sample_interval, timestamp) {
+            let timestamp = timestamp as f64;
+            if self
+                .next_sample_time
+                .is_some_and(|next_sample_time| timestamp < next_sample_time)
+            {
+                return false;
+            }
+            // Sample times stay on a fixed grid so the kept frames average out to the target rate,
+            // unless the stream skipped ahead by more than one interval
+            let next_sample_time = self.next_sample_time.unwrap_or(timestamp) + sample_interval;
+            self.next_sample_time = Some(if next_sample_time <= timestamp {
+                timestamp + sample_interval
+            } else {
+                next_sample_time
+            });
+        }
+
+        true
+    }
+
+    // Whether a packet belongs to a passthrough stream and lies within the decoded range
+    fn passthrough_packet_in_range(&self, packet: &Packet) -> bool {
+        let Some(stream) = self
+            .video_info
+            .passthrough_streams
+            .iter()
+            .find(|stream| stream.index == packet.stream())
+        else {
+            return false;
+        };
+        let Some(timestamp) = packet.pts().or(packet.dts()) else {
+            return true;
+        };
+        let timestamp = timestamp.rescale(stream.time_base, self.video_info.time_base);
+        self.range_start.map_or(true, |start| timestamp >= start)
+            && self.range_end.map_or(true, |end| timestamp < end)
+    }
+}
+
+/// Decodes every frame `video_decoder` hands out into memory
+pub fn dump_frames(
+    mut video_decoder: VideoDecoder,
+) -> Result<(Frames, VideoInfo, Vec<Packet>), VideoDecoderError> {
+    let mut frames = Vec::new();
+    while let Some(frame_map) = video_decoder.next_frame()? {
+        frames.push(frame_map);
+    }
+
+    Ok((
+        frames,
+        video_decoder.video_info().clone(),
+        video_decoder.take_passthrough_packets(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_size_is_bounded() {
+        for (width, height) in [
+            (Some(0), None),
+            (None, Some(0)),
+            (Some(MAX_FRAME_SIZE + 1), None),
+            (Some(640), Some(MAX_FRAME_SIZE + 1)),
+            (Some(u32::MAX), Some(u32::MAX)),
+        ] {
+            let options = DecodeOptions {
+                width,
+                height,
+                ..Default::default()
+            };
+            assert!(
+                matches!(options.validate(), Err(VideoDecoderError::InvalidOption(_))),
+                "{width:?} x {height:?}"
+            );
+        }
+        for (width, height) in [(None, None), (Some(MAX_FRAME_SIZE), Some(MAX_FRAME_SIZE))] {
+            let options = DecodeOptions {
+                width,
+                height,
+                ..Default::default()
+            };
+            assert!(options.validate().is_ok(), "{width:?} x {height:?}");
+        }
+    }
 }
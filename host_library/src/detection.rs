@@ -0,0 +1,86 @@
+use serde::{Deserialize, Serialize};
+
+use crate::error::{PluginError, VideoProcessingPluginError};
+
+/// A detected object in frame coordinates, as sent by the guest in JSON
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Detection {
+    // Top left corner and size of the box in pixels of the frame
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+    pub class_id: u32,
+    pub score: f32,
+    // Class name, e.g. "person"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    // RGB colour, picked from the class id when not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub color: Option<[u8; 3]>,
+    // Identity of the object across frames, written to the MOT and COCO exports
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub track_id: Option<u32>,
+}
+
+// Colours of the Ultralytics YOLO palette, so boxes look like those of the Python tools
+const CLASS_PALETTE: [[u8; 3]; 20] = [
+    [255, 56, 56],
+    [255, 157, 151],
+    [255, 112, 31],
+    [255, 178, 29],
+    [207, 210, 49],
+    [72, 249, 10],
+    [146, 204, 23],
+    [61, 219, 134],
+    [26, 147, 52],
+    [0, 212, 187],
+    [44, 153, 168],
+    [0, 194, 255],
+    [52, 69, 147],
+    [100, 115, 255],
+    [0, 24, 236],
+    [132, 56, 255],
+    [82, 0, 133],
+    [203, 56, 255],
+    [255, 149, 200],
+    [255, 55, 199],
+];
+
+impl Detection {
+    /// Rejects boxes the tracker and the drawing cannot work with. JSON numbers too large for
+    /// an f32 are read as infinity, which would turn the IoU of the tracker into NaN.
+    pub fn validate(&self) -> Result<(), PluginError> {
+        let finite = [self.x, self.y, self.width, self.height, self.score]
+            .iter()
+            .all(|value| value.is_finite());
+        if !finite || self.width < 0.0 || self.height < 0.0 {
+            return Err(PluginError::new(
+                VideoProcessingPluginError::InvalidArgument,
+                format!(
+                    "Detection box x {} y {} width {} height {} score {} is not a finite box",
+                    self.x, self.y, self.width, self.height, self.score
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn color(&self) -> [u8; 3] {
+        self.color
+            .unwrap_or(CLASS_PALETTE[self.class_id as usize % CLASS_PALETTE.len()])
+    }
+
+    /// Text drawn above the box, e.g. "#4 person 0.87" with the track id in front
+    pub fn caption(&self) -> String {
+        let caption = match self.label.as_deref() {
+            Some(label) => format!("{label} {:.2}", self.score),
+            None => format!("{} {:.2}", self.class_id, self.score),
+        };
+        match self.track_id {
+            Some(track_id) => format!("#{track_id} {caption}"),
+            None => caption,
+        }
+    }
+}
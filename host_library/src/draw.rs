@@ -0,0 +1,333 @@
+use ffmpeg::util::frame::video::Video;
+use font8x8::{UnicodeFonts, BASIC_FONTS};
+use serde::Deserialize;
+
+use crate::{
+    detection::Detection,
+    error::{PluginError, VideoProcessingPluginError},
+};
+
+// Glyphs of font8x8 are 8 x 8 pixels before scaling
+const GLYPH_SIZE: i32 = 8;
+// Limits of thickness and font_scale, far beyond what is readable
+const MAX_THICKNESS: u32 = 256;
+const MAX_FONT_SCALE: u32 = 64;
+
+/// What `draw_detections` renders into a frame, sent by the guest as JSON
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DrawOptions {
+    pub detections: Vec<Detection>,
+    // Width of the box outlines in pixels
+    pub thickness: u32,
+    // Glyphs are 8 x 8 pixels times this factor
+    pub font_scale: u32,
+    // Whether to draw the label with its filled background above each box
+    pub labels: bool,
+}
+
+impl Default for DrawOptions {
+    fn default() -> Self {
+        DrawOptions {
+            detections: Vec::new(),
+            thickness: 2,
+            font_scale: 2,
+            labels: true,
+        }
+    }
+}
+
+impl DrawOptions {
+    pub fn validate(&self) -> Result<(), PluginError> {
+        if self.thickness == 0 || self.font_scale == 0 {
+            return Err(PluginError::new(
+                VideoProcessingPluginError::InvalidArgument,
+                "Thickness and font scale have to be at least 1",
+            ));
+        }
+        if self.thickness > MAX_THICKNESS || self.font_scale > MAX_FONT_SCALE {
+            return Err(PluginError::new(
+                VideoProcessingPluginError::InvalidArgument,
+                format!(
+                    "Thickness can be at most {MAX_THICKNESS} and font scale at most \
+                     {MAX_FONT_SCALE}"
+                ),
+            ));
+        }
+        for detection in &self.detections {
+            detection.validate()?;
+        }
+        Ok(())
+    }
+}
+
+/// Draws the outline of every detection and its label into an RGB24 frame.
+/// Whatever falls outside the frame is clipped, coordinates far outside saturate.
+pub fn draw_detections(frame: &mut Video, options: &DrawOptions) {
+    let thickness = options.thickness as i32;
+    let font_scale = options.font_scale as i32;
+
+    for detection in &options.detections {
+        let color = detection.color();
+        let left = detection.x.round() as i32;
+        let top = detection.y.round() as i32;
+        let right = (detection.x + detection.width).round() as i32;
+        let bottom = (detection.y + detection.height).round() as i32;
+
+        fill_rect(
+            frame,
+            left,
+            top,
+            right,
+            top.saturating_add(thickness),
+            color,
+        );
+        fill_rect(
+            frame,
+            left,
+            bottom.saturating_sub(thickness),
+            right,
+            bottom,
+            color,
+        );
+        fill_rect(
+            frame,
+            left,
+            top,
+            left.saturating_add(thickness),
+            bottom,
+            color,
+        );
+        fill_rect(
+            frame,
+            right.saturating_sub(thickness),
+            top,
+            right,
+            bottom,
+            color,
+        );
+
+        if !options.labels {
+            continue;
+        }
+
+        let caption = detection.caption();
+        let padding = font_scale;
+        let label_width = i32::try_from(caption.chars().count())
+            .unwrap_or(i32::MAX)
+            .saturating_mul(GLYPH_SIZE * font_scale)
+            .saturating_add(2 * padding);
+        let label_height = GLYPH_SIZE * font_scale + 2 * padding;
+        // Above the box, or inside it when the box touches the top of the frame
+        let label_top = if top.saturating_sub(label_height) >= 0 {
+            top - label_height
+        } else {
+            top
+        };
+
+        fill_rect(
+            frame,
+            left,
+            label_top,
+            left.saturating_add(label_width),
+            label_top.saturating_add(label_height),
+            color,
+        );
+        draw_text(
+            frame,
+            left.saturating_add(padding),
+            label_top.saturating_add(padding),
+            &caption,
+            font_scale,
+            text_color(color),
+        );
+    }
+}
+
+// Black or white, whichever reads better on the background
+fn text_color([red, green, blue]: [u8; 3]) -> [u8; 3] {
+    let luma = 0.299 * red as f32 + 0.587 * green as f32 + 0.114 * blue as f32;
+    if luma > 150.0 {
+        [0, 0, 0]
+    } else {
+        [255, 255, 255]
+    }
+}
+
+fn draw_text(frame: &mut Video, x: i32, y: i32, text: &str, font_scale: i32, color: [u8; 3]) {
+    for (index, character) in text.chars().enumerate() {
+        let glyph = BASIC_FONTS
+            .get(character)
+            .or_else(|| BASIC_FONTS.get('?'))
+            .unwrap_or_default();
+        let glyph_x = i32::try_from(index)
+            .unwrap_or(i32::MAX)
+            .saturating_mul(GLYPH_SIZE * font_scale)
+            .saturating_add(x);
+        // The rest of the text is right of the frame
+        if glyph_x >= frame.width() as i32 {
+            break;
+        }
+
+        for (row, bits) in glyph.iter().enumerate() {
+            for column in 0..GLYPH_SIZE {
+                // Bit 0 is the leftmost pixel of a glyph row
+                if bits & (1 << column) == 0 {
+                    continue;
+                }
+                let pixel_x = glyph_x.saturating_add(column * font_scale);
+                let pixel_y = y.saturating_add(row as i32 * font_scale);
+                fill_rect(
+                    frame,
+                    pixel_x,
+                    pixel_y,
+                    pixel_x.saturating_add(font_scale),
+                    pixel_y.saturating_add(font_scale),
+                    color,
+                );
+            }
+        }
+    }
+}
+
+/// Fills the pixels from `(left, top)` up to but excluding `(right, bottom)` of an RGB24 frame,
+/// clipped to the frame
+pub fn fill_rect(frame: &mut Video, left: i32, top: i32, right: i32, bottom: i32, color: [u8; 3]) {
+    let (width, height) = (frame.width() as i32, frame.height() as i32);
+    let (left, right) = (left.clamp(0, width), right.clamp(0, width));
+    let (top, bottom) = (top.clamp(0, height), bottom.clamp(0, height));
+    if left >= right || top >= bottom {
+        return;
+    }
+
+    let stride = frame.stride(0);
+    let data = frame.data_mut(0);
+    for y in top as usize..bottom as usize {
+        let row = &mut data[y * stride + left as usize * 3..y * stride + right as usize * 3];
+        for pixel in row.chunks_exact_mut(3) {
+            pixel.copy_from_slice(&color);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ffmpeg::format::Pixel;
+
+    use crate::frame_buffer;
+
+    use super::*;
+
+    const BLUE: [u8; 3] = [0, 0, 255];
+
+    fn black_frame(width: u32, height: u32) -> Video {
+        let mut frame = Video::new(Pixel::RGB24, width, height);
+        frame_buffer::fill_rgb(&mut frame, [0, 0, 0]);
+        frame
+    }
+
+    fn pixel(frame: &Video, x: usize, y: usize) -> [u8; 3] {
+        let offset = y * frame.stride(0) + x * 3;
+        let data = frame.data(0);
+        [data[offset], data[offset + 1], data[offset + 2]]
+    }
+
+    // Whether each pixel, row by row, was drawn on
+    fn painted(frame: &Video) -> Vec<bool> {
+        let (width, height) = (frame.width() as usize, frame.height() as usize);
+        (0..width * height)
+            .map(|index| pixel(frame, index % width, index / width) != [0, 0, 0])
+            .collect()
+    }
+
+    fn detection(x: f32, y: f32, width: f32, height: f32) -> Detection {
+        Detection {
+            x,
+            y,
+            width,
+            height,
+            class_id: 0,
+            score: 0.9,
+            label: None,
+            color: Some(BLUE),
+            track_id: None,
+        }
+    }
+
+    fn draw(frame: &mut Video, detections: Vec<Detection>, thickness: u32, labels: bool) {
+        let options = DrawOptions {
+            detections,
+            thickness,
+            font_scale: 1,
+            labels,
+        };
+        options.validate().unwrap();
+        draw_detections(frame, &options);
+    }
+
+    #[test]
+    fn outlines_are_as_thick_as_asked() {
+        for thickness in 1..=4 {
+            let mut frame = black_frame(20, 20);
+            draw(
+                &mut frame,
+                vec![detection(4.0, 4.0, 10.0, 10.0)],
+                thickness,
+                false,
+            );
+
+            let inner = 4 + thickness as usize..14 - thickness as usize;
+            let expected: Vec<bool> = (0..400)
+                .map(|index| {
+                    let (x, y) = (index % 20, index / 20);
+                    (4..14).contains(&x)
+                        && (4..14).contains(&y)
+                        && !(inner.contains(&x) && inner.contains(&y))
+                })
+                .collect();
+            assert_eq!(painted(&frame), expected, "thickness {thickness}");
+            assert_eq!(pixel(&frame, 4, 4), BLUE);
+        }
+    }
+
+    #[test]
+    fn boxes_outside_the_frame_are_clipped() {
+        let mut frame = black_frame(20, 20);
+        draw(
+            &mut frame,
+            vec![
+                // Only the right edge is inside
+                detection(-5.0, -5.0, 10.0, 30.0),
+                detection(1e9, 1e9, 1e9, 1e9),
+                detection(-1e9, 5.0, 1e9, 1.0),
+            ],
+            2,
+            false,
+        );
+
+        let expected: Vec<bool> = (0..400)
+            .map(|index| (3..5).contains(&(index % 20)))
+            .collect();
+        assert_eq!(painted(&frame), expected);
+    }
+
+    #[test]
+    fn labels_of_boxes_at_the_top_stay_inside_the_frame() {
+        // Labels are 10 pixels high with a font scale of 1, the caption "0 0.90" 50 wide
+        let mut frame = black_frame(64, 48);
+        draw(&mut frame, vec![detection(2.0, 0.0, 10.0, 8.0)], 1, true);
+        // Padding rows at the top and bottom of the label background
+        assert_eq!(pixel(&frame, 40, 0), BLUE);
+        assert_eq!(pixel(&frame, 40, 9), BLUE);
+        assert_eq!(pixel(&frame, 40, 10), [0, 0, 0]);
+        assert_eq!(pixel(&frame, 52, 0), [0, 0, 0]);
+
+        // With room above the box the label goes there
+        let mut frame = black_frame(64, 48);
+        draw(&mut frame, vec![detection(2.0, 20.0, 10.0, 8.0)], 1, true);
+        assert_eq!(pixel(&frame, 40, 9), [0, 0, 0]);
+        assert_eq!(pixel(&frame, 40, 10), BLUE);
+        assert_eq!(pixel(&frame, 40, 19), BLUE);
+        assert_eq!(pixel(&frame, 40, 20), [0, 0, 0]);
+    }
+}
@@ -1,26 +1,44 @@
-use std::time::Duration;
+use std::{
+    ffi::CString,
+    ops::{Deref, DerefMut},
+    time::Duration,
+};
 
 use ffmpeg::{
     codec,
     format::{self, Pixel},
-    frame, picture, Dictionary, Packet, Rational,
+    frame, picture, Codec, Dictionary, Packet, Rational,
 };
 
 use ffmpeg::software::scaling::{Context as Scaler, Flags};
 use ffmpeg::util::frame::video::Video as AVFrame;
+use ffmpeg::util::mathematics::rescale::Rescale;
 use log::{debug, error, warn};
 
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
 
 use ffmpeg::encoder::Video as AVEncoder;
 use ffmpeg::Error as FFmpegError;
 
-use crate::{time::Time, VideoInfo};
+use serde::Deserialize;
+
+use crate::{
+    faststart::move_moov_to_front,
+    filter_graph::{FilterGraph, FilterInput},
+    memory_io::MemoryOutput,
+    rotation::{rotation_filter, set_display_rotation},
+    time::Time,
+    VideoInfo,
+};
 
 #[derive(Debug)]
 pub enum VideoEncoderError {
     FFMpegError(FFmpegError),
     CodecError(String),
+    // EncoderOptions sent by the guest that cannot work, e.g. a pixel format the codec lacks
+    InvalidOption(String),
+    // The filter string of EncoderOptions could not be turned into a filter graph
+    InvalidFilter(String),
 }
 
 impl From<FFmpegError> for VideoEncoderError {
@@ -29,69 +47,431 @@ impl From<FFmpegError> for VideoEncoderError {
     }
 }
 
+/// How the output keeps the rotation metadata of the input video
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputRotation {
+    // Frames are stored upright and the output has no rotation metadata
+    #[default]
+    Upright,
+    // Frames are stored like in the input and the output gets its rotation metadata
+    Tagged,
+}
+
+/// Output settings the guest picks with `set_encoder_options`, sent over as JSON.
+/// Anything left out keeps the defaults: H.264 in YUV420P with preset `slow`,
+/// a bit rate of half the uncompressed size and the container inferred from the file name,
+/// MP4 for videos encoded into memory.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EncoderOptions {
+    // Name of the encoder ("libx264", "libsvtav1") or of the codec
+    // ("h264", "hevc", "vp9", "av1", "mpeg4", "mjpeg", "ffv1")
+    pub codec: Option<String>,
+    // FFmpeg name of the pixel format, e.g. "yuv420p" or "yuv444p"
+    pub pixel_format: Option<String>,
+    // Private options of the encoder, those it does not know are rejected when it is opened
+    pub crf: Option<u32>,
+    pub qp: Option<u32>,
+    // Target bit rate in bits per second
+    pub bit_rate: Option<usize>,
+    pub preset: Option<String>,
+    pub tune: Option<String>,
+    // Maximum number of frames between two key frames
+    pub gop_size: Option<u32>,
+    // Short name of the output container, e.g. "mp4", "matroska", "webm"
+    pub container: Option<String>,
+    // FFmpeg filter string applied to the written frames before they are encoded,
+    // e.g. "unsharp,vflip". The output size is whatever the filters make of the frames
+    pub filter: Option<String>,
+    pub output_rotation: OutputRotation,
+}
+
+impl EncoderOptions {
+    /// Finds the encoder for `codec` in the linked FFmpeg
+    pub fn codec(&self) -> Result<Codec, VideoEncoderError> {
+        let Some(name) = self.codec.as_deref() else {
+            return ffmpeg::encoder::find(codec::Id::H264).ok_or(VideoEncoderError::CodecError(
+                "Could not Find Codec h264".into(),
+            ));
+        };
+
+        // Encoders are looked up by their own name first, then by the codec they implement
+        let codec = ffmpeg::encoder::find_by_name(name)
+            .or_else(|| {
+                let c_name = CString::new(name).ok()?;
+                let descriptor =
+                    unsafe { ffmpeg::ffi::avcodec_descriptor_get_by_name(c_name.as_ptr()) };
+                if descriptor.is_null() {
+                    return None;
+                }
+                ffmpeg::encoder::find(codec::Id::from(unsafe { (*descriptor).id }))
+            })
+            .ok_or_else(|| {
+                VideoEncoderError::CodecError(format!(
+                    "No encoder for codec {name} in the linked FFmpeg"
+                ))
+            })?;
+
+        if !codec.is_video() {
+            return Err(VideoEncoderError::InvalidOption(format!(
+                "Codec {name} is not a video codec"
+            )));
+        }
+        Ok(codec)
+    }
+
+    /// Picks `pixel_format`, or YUV420P when not set, and checks that `codec` can encode it.
+    /// Codecs that cannot take YUV420P fall back to the first format they support.
+    pub fn pixel_format(&self, codec: Codec) -> Result<Pixel, VideoEncoderError> {
+        let supported: Option<Vec<Pixel>> =
+            codec.video()?.formats().map(|formats| formats.collect());
+
+        let Some(name) = self.pixel_format.as_deref() else {
+            return match supported {
+                Some(supported) if !supported.contains(&Pixel::YUV420P) => {
+                    supported.first().copied().ok_or_else(|| {
+                        VideoEncoderError::CodecError(format!(
+                            "Codec {} supports no pixel formats",
+                            codec.name()
+                        ))
+                    })
+                }
+                _ => Ok(Pixel::YUV420P),
+            };
+        };
+
+        let pixel_format: Pixel = name.parse().map_err(|_| {
+            VideoEncoderError::InvalidOption(format!("Unknown pixel format {name}"))
+        })?;
+        if supported.is_some_and(|supported| !supported.contains(&pixel_format)) {
+            return Err(VideoEncoderError::InvalidOption(format!(
+                "Codec {} does not support pixel format {name}",
+                codec.name()
+            )));
+        }
+        Ok(pixel_format)
+    }
+
+    /// Checks the options against the linked FFmpeg before any encoder is opened with them
+    pub fn validate(&self) -> Result<(), VideoEncoderError> {
+        let codec = self.codec()?;
+        self.pixel_format(codec)?;
+
+        if self.crf.is_some() && self.qp.is_some() {
+            return Err(VideoEncoderError::InvalidOption(
+                "Only one of crf and qp can be set".into(),
+            ));
+        }
+
+        if self
+            .filter
+            .as_deref()
+            .is_some_and(|filter| filter.trim().is_empty())
+        {
+            return Err(VideoEncoderError::InvalidFilter(
+                "Filter string is empty".into(),
+            ));
+        }
+
+        if let Some(container) = self.container.as_deref() {
+            let c_container = CString::new(container).map_err(|_| {
+                VideoEncoderError::InvalidOption(format!("Invalid container name {container}"))
+            })?;
+            let output_format = unsafe {
+                ffmpeg::ffi::av_guess_format(
+                    c_container.as_ptr(),
+                    std::ptr::null(),
+                    std::ptr::null(),
+                )
+            };
+            if output_format.is_null() {
+                return Err(VideoEncoderError::CodecError(format!(
+                    "No muxer for container {container} in the linked FFmpeg"
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Builds the graph of `filter` and of the rotation `output_rotation` needs for frames of
+    /// the video, converting to `pixel_format`. `None` if there is nothing to filter.
+    pub fn filter_graph(
+        &self,
+        v_info: &VideoInfo,
+        pixel_format: Pixel,
+    ) -> Result<Option<FilterGraph>, VideoEncoderError> {
+        // Clockwise rotation from the orientation of the written frames to the stored one
+        let rotation = match self.output_rotation {
+            OutputRotation::Upright => v_info.rotation + 360 - v_info.applied_rotation,
+            OutputRotation::Tagged => 360 - v_info.applied_rotation,
+        };
+        let filter: Vec<&str> = [self.filter.as_deref(), rotation_filter(rotation)]
+            .into_iter()
+            .flatten()
+            .collect();
+        if filter.is_empty() {
+            return Ok(None);
+        }
+        // Frames go in with the timestamps of the input, `encode_frame` keeps them in its time base
+        let filter_input = FilterInput {
+            format: Pixel::RGB24,
+            width: v_info.width.0,
+            height: v_info.height.0,
+            time_base: v_info.time_base,
+            aspect_ratio: v_info.aspect_ratio.0,
+            frame_rate: v_info.frame_rate.0,
+        };
+        FilterGraph::new(&filter.join(","), filter_input, pixel_format)
+            .map(Some)
+            .map_err(VideoEncoderError::InvalidFilter)
+    }
+
+    /// Checks that `filter` can be built for frames of the video
+    pub fn validate_filter(&self, v_info: &VideoInfo) -> Result<(), VideoEncoderError> {
+        let pixel_format = self.pixel_format(self.codec()?)?;
+        self.filter_graph(v_info, pixel_format)?;
+        Ok(())
+    }
+}
+
+/// Where an encoder writes the muxed video
+#[derive(Debug, Clone, Copy)]
+pub enum OutputTarget<'a> {
+    File(&'a str),
+    // Kept in memory for `read_output_bytes`, in the container of EncoderOptions or MP4
+    Memory,
+}
+
+// Container of memory outputs when EncoderOptions names none
+const MEMORY_CONTAINER: &str = "mp4";
+
+// Muxers that write the moov atom last unless told otherwise
+const MOV_MUXERS: [&str; 6] = ["mp4", "mov", "ipod", "3gp", "3g2", "psp"];
+
+enum OutputContext {
+    File(format::context::Output),
+    Memory(MemoryOutput),
+}
+
+impl Deref for OutputContext {
+    type Target = format::context::Output;
+
+    fn deref(&self) -> &Self::Target {
+        match self {
+            OutputContext::File(octx) => octx,
+            OutputContext::Memory(memory_output) => memory_output,
+        }
+    }
+}
+
+impl DerefMut for OutputContext {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        match self {
+            OutputContext::File(octx) => octx,
+            OutputContext::Memory(memory_output) => memory_output,
+        }
+    }
+}
+
+// Turns the RGB24 frames of the guest into frames in the pixel format of the encoder
+enum FrameConverter {
+    Scaler(Scaler),
+    // Filter graph of EncoderOptions, it converts the pixel format as well
+    FilterGraph(FilterGraph),
+}
+
 pub(crate) struct VideoEncoder {
     // Encoder
     encoder: ffmpeg::encoder::Video,
     // Output Context
-    octx: ffmpeg::format::context::output::Output,
+    octx: OutputContext,
+    // Set for MP4s in memory, their moov is moved to the front once finished
+    faststart: bool,
     // Output Time Base
     _packet_order_map: BTreeMap<i64, Packet>, // ost_time_bases: Vec<Rational>,
     // Frame scaler / Converter between formats
-    scaler: Scaler,
-    // FrameRate
-    frame_rate: i32,
+    converter: FrameConverter,
+    // Time base of the input video stream, the timestamps of the decoded frames are in it
+    input_time_base: Rational,
+    // Subtracted from input timestamps, in the input time base, so a decoded range starts at 0
+    range_offset: i64,
+    // Duration of a single frame, used for frames the decoder gave no timestamp
+    frame_duration: Time,
+    // Presentation time of the next frame should it have no timestamp
+    position: Time,
+    // Last presentation timestamp sent to the encoder, in the encoder time base
+    last_pts: Option<i64>,
+    // Number of frames sent to the encoder
+    frames_encoded: usize,
+    // Set once the trailer has been written
+    finished: bool,
+    // Input stream index -> (output stream index, input time base) of the remuxed streams
+    passthrough_streams: HashMap<usize, (usize, Rational)>,
 }
 
-impl VideoEncoder {
-    pub fn new(v_info: &VideoInfo, output_file: &String) -> Result<Self, VideoEncoderError> {
-        let mut octx = format::output(&output_file)?;
+// The scaler wraps a raw SwsContext pointer and is therefore not Send,
+// a VideoEncoder only ever lives behind the plugin Mutex so it is never used by two threads at once.
+unsafe impl Send for VideoEncoder {}
 
-        let global_header = octx.format().flags().contains(format::Flags::GLOBAL_HEADER);
-        let mut ost: ffmpeg::StreamMut<'_> = octx.add_stream()?;
+impl VideoEncoder {
+    pub fn new(
+        v_info: &VideoInfo,
+        target: OutputTarget,
+        options: &EncoderOptions,
+    ) -> Result<Self, VideoEncoderError> {
+        Self::open(v_info, target, options, Dictionary::new(), true)
+    }
 
-        // let codec = ffmpeg::encoder::find_by_name("libx264").unwrap();
-        let codec = ffmpeg::encoder::find(codec::Id::H264).ok_or(VideoEncoderError::CodecError(
-            "Could not Find Codec h264".into(),
-        ))?;
+    /// Opens an encoder that frames are pushed to one at a time with `encode_frame`.
+    /// MP4/MOV outputs are fragmented and every packet is flushed to disk straight away,
+    /// so that the frames written so far can still be played back if the trailer is never written.
+    /// In memory the output is never gone back to, so its bytes can be read while encoding.
+    pub fn new_streaming(
+        v_info: &VideoInfo,
+        target: OutputTarget,
+        options: &EncoderOptions,
+    ) -> Result<Self, VideoEncoderError> {
+        let mut header_options = Dictionary::new();
+        header_options.set("movflags", "frag_keyframe+empty_moov+default_base_moof");
+        header_options.set("flush_packets", "1");
+        Self::open(v_info, target, options, header_options, false)
+    }
 
-        let mut encoder = ffmpeg::codec::Encoder::new(codec)?.video()?;
+    fn open(
+        v_info: &VideoInfo,
+        target: OutputTarget,
+        options: &EncoderOptions,
+        header_options: Dictionary,
+        seekable: bool,
+    ) -> Result<Self, VideoEncoderError> {
+        let mut octx = match (target, options.container.as_deref()) {
+            (OutputTarget::File(output_file), Some(container)) => {
+                OutputContext::File(format::output_as(output_file, container)?)
+            }
+            (OutputTarget::File(output_file), None) => {
+                OutputContext::File(format::output(output_file)?)
+            }
+            (OutputTarget::Memory, container) => OutputContext::Memory(MemoryOutput::open(
+                container.unwrap_or(MEMORY_CONTAINER),
+                seekable,
+            )?),
+        };
+        let faststart = matches!(octx, OutputContext::Memory(_))
+            && seekable
+            && MOV_MUXERS.contains(&octx.format().name());
 
-        encoder.set_height(v_info.height.0);
-        encoder.set_width(v_info.width.0);
-        encoder.set_format(v_info.format);
-        encoder.set_time_base(Some(ffmpeg::rescale::TIME_BASE));
-        encoder.set_frame_rate(v_info.frame_rate.0);
+        let global_header = octx.format().flags().contains(format::Flags::GLOBAL_HEADER);
+        let mut ost: ffmpeg::StreamMut<'_> = octx.add_stream()?;
 
-        // Keeping the Bit Rate VERY high to not loose information
-        let bitrate_uncompressed = (3 * 8 * v_info.height.0 * v_info.width.0) as usize;
-        encoder.set_bit_rate(bitrate_uncompressed / 2);
+        let codec = options.codec()?;
+        let pixel_format = options.pixel_format(codec)?;
 
-        let mut dict = Dictionary::new();
-        dict.set("preset", "slow");
-        // dict.set("preset", "medium");
+        let mut filter_graph = options.filter_graph(v_info, pixel_format)?;
+        let (width, height) = match filter_graph.as_mut() {
+            Some(filter_graph) => filter_graph.output_size(),
+            None => (v_info.width.0, v_info.height.0),
+        };
 
-        let mut encoder: AVEncoder = encoder.open_with(dict)?;
+        // Keep the time base of the input stream so that its timestamps can be reused as they are.
+        // Some encoders cannot take every time base, e.g. mpeg4 none above 1/65535, they get
+        // one frame as their time base and the timestamps are rescaled into it.
+        let size = (width, height);
+        let frame_rate = v_info.frame_rate.0;
+        let mut encoder = match open_encoder(
+            codec,
+            options,
+            size,
+            pixel_format,
+            v_info.time_base,
+            frame_rate,
+        ) {
+            Ok(encoder) => encoder,
+            Err(VideoEncoderError::FFMpegError(err)) => {
+                let Some(fallback_time_base) = frame_rate
+                    .map(Rational::invert)
+                    .filter(|time_base| *time_base != v_info.time_base)
+                else {
+                    return Err(err.into());
+                };
+                warn!(
+                    "Encoder {} rejected time base {}, falling back to {fallback_time_base}: {err}",
+                    codec.name(),
+                    v_info.time_base
+                );
+                open_encoder(
+                    codec,
+                    options,
+                    size,
+                    pixel_format,
+                    fallback_time_base,
+                    frame_rate,
+                )?
+            }
+            Err(err) => return Err(err),
+        };
 
         ost.set_parameters(encoder.parameters());
 
+        if options.output_rotation == OutputRotation::Tagged && v_info.rotation != 0 {
+            set_display_rotation(&mut ost, v_info.rotation)?;
+        }
+
         if global_header {
             encoder.set_flags(codec::Flags::GLOBAL_HEADER);
         }
 
+        let mut passthrough_streams = HashMap::new();
+        for stream in v_info.passthrough_streams.iter() {
+            // Not every container can hold every codec, e.g. PCM audio or SRT subtitles in MP4
+            // FF_COMPLIANCE_NORMAL = 0
+            let supported = unsafe {
+                ffmpeg::ffi::avformat_query_codec(
+                    octx.format().as_ptr(),
+                    stream.parameters.id().into(),
+                    0,
+                )
+            };
+            if supported == 0 {
+                warn!(
+                    "Dropping input stream {} as {:?} is not supported by the output container",
+                    stream.index,
+                    stream.parameters.id()
+                );
+                continue;
+            }
+
+            let mut passthrough_ost = octx.add_stream()?;
+            passthrough_ost.set_parameters(stream.parameters.clone());
+            // The codec tag of the input container may mean something else in the output one
+            unsafe {
+                (*(*passthrough_ost.as_mut_ptr()).codecpar).codec_tag = 0;
+            }
+            passthrough_streams.insert(stream.index, (passthrough_ost.index(), stream.time_base));
+        }
+
         octx.set_metadata(v_info.input_stream_meta_data.clone());
-        format::context::output::dump(&octx, 0, Some(&output_file));
-        octx.write_header()?;
+        let url = match target {
+            OutputTarget::File(output_file) => Some(output_file),
+            OutputTarget::Memory => None,
+        };
+        format::context::output::dump(&octx, 0, url);
+        octx.write_header_with(header_options)?;
 
         // Write Every Frame out to encoder packet
-        let scaler = Scaler::get(
-            Pixel::RGB24,
-            v_info.width.0,
-            v_info.height.0,
-            Pixel::YUV420P,
-            v_info.width.0,
-            v_info.height.0,
-            Flags::empty(),
-        )?;
+        let converter = match filter_graph {
+            Some(filter_graph) => FrameConverter::FilterGraph(filter_graph),
+            None => FrameConverter::Scaler(Scaler::get(
+                Pixel::RGB24,
+                width,
+                height,
+                pixel_format,
+                width,
+                height,
+                Flags::empty(),
+            )?),
+        };
 
         encoder.set_threading(codec::threading::Config {
             kind: codec::threading::Type::None,
@@ -125,51 +505,47 @@ impl VideoEncoder {
         Ok(VideoEncoder {
             encoder,
             octx,
+            faststart,
             _packet_order_map: BTreeMap::new(),
-            scaler,
-            frame_rate: frame_rate.0,
+            converter,
+            input_time_base: v_info.time_base,
+            range_offset: v_info.range_offset,
+            frame_duration: Duration::from_secs_f64(f64::from(frame_rate.invert())).into(),
+            position: Time::zero(),
+            last_pts: None,
+            frames_encoded: 0,
+            finished: false,
+            passthrough_streams,
         })
     }
 
+    pub fn frames_encoded(&self) -> usize {
+        self.frames_encoded
+    }
+
     pub fn receive_and_process_decoded_frames(
         &mut self,
         frames: &mut Vec<(frame::Video, picture::Type, Option<i64>)>,
+        passthrough_packets: Vec<Packet>,
     ) -> Result<(), VideoEncoderError> {
-        let duration: Time = Duration::from_nanos(1_000_000_000 / self.frame_rate as u64).into();
-
-        let mut position = Time::zero();
-
-        for (_idx, (out_frame_rgb, _frame_type, _)) in frames.iter_mut().enumerate() {
-            let frame_timestamp_rescale = position
-                .aligned_with_rational(
-                    self.encoder
-                        .time_base()
-                        .unwrap_or(ffmpeg::rescale::TIME_BASE),
-                )
-                .into_value();
-
-            out_frame_rgb.set_pts(frame_timestamp_rescale);
-
-            let mut frame_yuv420 = self.scale(out_frame_rgb)?;
-
-            // TODO Fix Encoding here
-            frame_yuv420.set_kind(picture::Type::I);
-
-            debug!(
-                "F Send {:?} {}",
-                frame_yuv420.pts(),
-                frame_yuv420.display_number()
-            );
-            self.encoder.send_frame(&frame_yuv420)?;
-
-            if let Some(mut packet) = self.encoder_receive_packet()? {
-                // Leaving this here should i want to try reorder the packets again in the futue
-                // self.packet_order_map.insert(packet.pts().unwrap(), packet);
-                self.write_encoded_packets(&mut packet, 0);
+        let mut passthrough_packets = passthrough_packets.into_iter().peekable();
+
+        for (_idx, (out_frame_rgb, _frame_type, timestamp)) in frames.iter_mut().enumerate() {
+            // Write the passthrough packets due before the frame so the muxer does not buffer them all
+            if let Some(timestamp) = *timestamp {
+                let frame_time =
+                    timestamp.rescale(self.input_time_base, ffmpeg::rescale::TIME_BASE);
+                while let Some(packet) = passthrough_packets
+                    .next_if(|packet| self.passthrough_packet_time(packet) <= frame_time)
+                {
+                    self.write_passthrough_packet(packet)?;
+                }
             }
+            self.encode_frame(out_frame_rgb, *timestamp)?;
+        }
 
-            let aligned_position = position.aligned_with(&duration);
-            position = aligned_position.add();
+        for packet in passthrough_packets {
+            self.write_passthrough_packet(packet)?;
         }
 
         // Leaving this here should i want to try reorder the packets again in the futue
@@ -183,14 +559,151 @@ impl VideoEncoder {
         return Ok(());
     }
 
-    fn scale(&mut self, frame: &mut AVFrame) -> Result<AVFrame, FFmpegError> {
-        let mut frame_scaled = AVFrame::empty();
-        self.scaler.run(&frame, &mut frame_scaled)?;
+    /// Converts a single RGB24 frame and sends it to the encoder,
+    /// writing out any packets the encoder has ready.
+    /// `timestamp` is the timestamp of the decoded input frame, in the input stream time base.
+    /// The start of a decoded range ends up at 0 in the output.
+    pub fn encode_frame(
+        &mut self,
+        out_frame_rgb: &mut AVFrame,
+        timestamp: Option<i64>,
+    ) -> Result<(), VideoEncoderError> {
+        let encoder_time_base = self
+            .encoder
+            .time_base()
+            .unwrap_or(ffmpeg::rescale::TIME_BASE);
+
+        // Stays in the input time base up to the encoder, the filter graph is built with it
+        let timestamp = timestamp.map(|timestamp| timestamp - self.range_offset);
+        let frame_timestamp = match timestamp {
+            // Reuse the timing of the source so variable and fractional frame rates stay in sync
+            Some(timestamp) => timestamp,
+            // No timestamp from the decoder, carry on one frame after the previous one
+            None => self
+                .position
+                .aligned_with_rational(self.input_time_base)
+                .into_value()
+                .unwrap_or(0),
+        };
+        out_frame_rgb.set_pts(Some(frame_timestamp));
+
+        match &mut self.converter {
+            FrameConverter::Scaler(scaler) => {
+                let mut frame_yuv420 = scale(scaler, out_frame_rgb)?;
+                frame_yuv420.set_pts(Some(
+                    frame_timestamp.rescale(self.input_time_base, encoder_time_base),
+                ));
+                self.send_frame(frame_yuv420)?;
+            }
+            FrameConverter::FilterGraph(filter_graph) => {
+                filter_graph.send(out_frame_rgb)?;
+                self.send_filtered_frames()?;
+            }
+        }
+
+        let aligned_position = Time::new(Some(frame_timestamp), self.input_time_base)
+            .aligned_with(&self.frame_duration);
+        self.position = aligned_position.add();
+        self.frames_encoded += 1;
+
+        Ok(())
+    }
+
+    /// Copies a packet of an audio, subtitle or data stream of the input into the output unchanged.
+    /// Packets of streams the output container could not take are dropped.
+    pub fn write_passthrough_packet(
+        &mut self,
+        mut packet: Packet,
+    ) -> Result<(), VideoEncoderError> {
+        let Some(&(ost_index, input_time_base)) = self.passthrough_streams.get(&packet.stream())
+        else {
+            return Ok(());
+        };
+
+        // TODO: Will Defaulting to TIME_BASE cause a potential source of errors here ?
+        let output_time_base = self
+            .octx
+            .stream(ost_index)
+            .expect("Could not Find Stream at index")
+            .time_base()
+            .unwrap_or(ffmpeg::rescale::TIME_BASE);
+
+        let range_offset = self
+            .range_offset
+            .rescale(self.input_time_base, input_time_base);
+        packet.set_pts(packet.pts().map(|pts| pts - range_offset));
+        packet.set_dts(packet.dts().map(|dts| dts - range_offset));
+        packet.rescale_ts(input_time_base, output_time_base);
+        packet.set_stream(ost_index);
+        packet.set_position(-1);
+        packet.write_interleaved(&mut self.octx)?;
+
+        Ok(())
+    }
+
+    // Time of a passthrough packet in microseconds, to interleave it with the video frames
+    fn passthrough_packet_time(&self, packet: &Packet) -> i64 {
+        let time_base = self
+            .passthrough_streams
+            .get(&packet.stream())
+            .map(|(_, time_base)| *time_base)
+            .unwrap_or(ffmpeg::rescale::TIME_BASE);
+        packet
+            .dts()
+            .or(packet.pts())
+            .unwrap_or(0)
+            .rescale(time_base, ffmpeg::rescale::TIME_BASE)
+    }
+
+    // Sends a frame in the pixel format and time base of the encoder, writing out the packets
+    // it has ready
+    fn send_frame(&mut self, mut frame: AVFrame) -> Result<(), FFmpegError> {
+        // Encoders reject timestamps that do not increase, timestamps of a variable frame rate
+        // can also end up on the same tick of a coarser encoder time base
+        if let (Some(pts), Some(last_pts)) = (frame.pts(), self.last_pts) {
+            if pts <= last_pts {
+                warn!("Frame timestamp {pts} not after previous timestamp {last_pts}");
+                frame.set_pts(Some(last_pts + 1));
+            }
+        }
+        self.last_pts = frame.pts().or(self.last_pts);
+
+        // TODO Fix Encoding here
+        frame.set_kind(picture::Type::I);
 
-        // Copy over PTS from old frame.
-        frame_scaled.set_pts(frame.pts());
+        debug!("F Send {:?} {}", frame.pts(), frame.display_number());
+        self.encoder.send_frame(&frame)?;
 
-        Ok(frame_scaled)
+        while let Some(mut packet) = self.encoder_receive_packet()? {
+            // Leaving this here should i want to try reorder the packets again in the futue
+            // self.packet_order_map.insert(packet.pts().unwrap(), packet);
+            self.write_encoded_packets(&mut packet, 0);
+        }
+        Ok(())
+    }
+
+    // Sends every frame the filter graph has ready to the encoder
+    fn send_filtered_frames(&mut self) -> Result<(), FFmpegError> {
+        let encoder_time_base = self
+            .encoder
+            .time_base()
+            .unwrap_or(ffmpeg::rescale::TIME_BASE);
+        loop {
+            let FrameConverter::FilterGraph(filter_graph) = &mut self.converter else {
+                return Ok(());
+            };
+            let Some(mut frame) = filter_graph.receive()? else {
+                return Ok(());
+            };
+            // Filters such as fps hand out frames in a time base of their own
+            let output_time_base = filter_graph.output_time_base();
+            frame.set_pts(
+                frame
+                    .pts()
+                    .map(|pts| pts.rescale(output_time_base, encoder_time_base)),
+            );
+            self.send_frame(frame)?;
+        }
     }
 
     fn flush(&mut self) -> Result<(), FFmpegError> {
@@ -198,6 +711,12 @@ impl VideoEncoder {
         // to drain the items still on the queue before giving up.
         const MAX_DRAIN_ITERATIONS: u32 = 100;
 
+        // Frames still held by the filter graph go before the end of the stream
+        if let FrameConverter::FilterGraph(filter_graph) = &mut self.converter {
+            filter_graph.flush()?;
+            self.send_filtered_frames()?;
+        }
+
         // Notify the encoder that the last frame has been sent.
         self.encoder.send_eof()?;
 
@@ -214,11 +733,32 @@ impl VideoEncoder {
     }
 
     pub fn finish(&mut self) -> Result<(), FFmpegError> {
+        if self.finished {
+            return Ok(());
+        }
+        self.finished = true;
         self.flush()?;
         self.octx.write_trailer()?;
         Ok(())
     }
 
+    /// Takes the bytes written so far to a memory output, `None` when writing to a file.
+    /// A seekable output hands out all of its bytes at once after `finish`.
+    pub fn take_output_bytes(&mut self) -> Result<Option<Vec<u8>>, VideoEncoderError> {
+        let OutputContext::Memory(memory_output) = &mut self.octx else {
+            return Ok(None);
+        };
+        if memory_output.is_seekable() && !self.finished {
+            return Ok(Some(Vec::new()));
+        }
+
+        let bytes = memory_output.take_bytes();
+        if self.faststart {
+            return Ok(Some(move_moov_to_front(bytes)?));
+        }
+        Ok(Some(bytes))
+    }
+
     fn encoder_receive_packet(&mut self) -> Result<Option<Packet>, FFmpegError> {
         let mut packet = Packet::empty();
         let encode_result = self.encoder.receive_packet(&mut packet);
@@ -259,3 +799,205 @@ impl VideoEncoder {
         };
     }
 }
+
+// Opens `codec` with the rate control and preset settings of `options`
+fn open_encoder(
+    codec: Codec,
+    options: &EncoderOptions,
+    (width, height): (u32, u32),
+    pixel_format: Pixel,
+    time_base: Rational,
+    frame_rate: Option<Rational>,
+) -> Result<AVEncoder, VideoEncoderError> {
+    let mut encoder = ffmpeg::codec::Encoder::new(codec)?.video()?;
+
+    encoder.set_height(height);
+    encoder.set_width(width);
+    encoder.set_format(pixel_format);
+    encoder.set_time_base(Some(time_base));
+    encoder.set_frame_rate(frame_rate);
+
+    match (options.bit_rate, options.crf.or(options.qp)) {
+        (Some(bit_rate), _) => encoder.set_bit_rate(bit_rate),
+        // Constant quality, the encoder picks the bit rate
+        (None, Some(_)) => encoder.set_bit_rate(0),
+        (None, None) => {
+            // Keeping the Bit Rate VERY high to not loose information
+            let bitrate_uncompressed = 3 * 8 * u64::from(height) * u64::from(width);
+            encoder.set_bit_rate(usize::try_from(bitrate_uncompressed / 2).unwrap_or(usize::MAX));
+        }
+    }
+
+    if let Some(gop_size) = options.gop_size {
+        encoder.set_gop(gop_size);
+    }
+
+    let mut dict = Dictionary::new();
+    // Presets are encoder specific, `slow` is only a sensible default for H.264
+    let default_preset = options.preset.is_none() && options.codec.is_none();
+    match options.preset.as_deref() {
+        Some(preset) => dict.set("preset", preset),
+        None if default_preset => dict.set("preset", "slow"),
+        None => {}
+    }
+    if let Some(tune) = options.tune.as_deref() {
+        dict.set("tune", tune);
+    }
+    if let Some(crf) = options.crf {
+        dict.set("crf", &crf.to_string());
+    }
+    if let Some(qp) = options.qp {
+        dict.set("qp", &qp.to_string());
+    }
+
+    // Opened like `open_with` does, which throws away the options the encoder did not take
+    let mut unused = dict;
+    let result = unsafe {
+        let mut options_ptr = unused.disown();
+        let result =
+            ffmpeg::ffi::avcodec_open2(encoder.as_mut_ptr(), std::ptr::null(), &mut options_ptr);
+        unused = Dictionary::own(options_ptr);
+        result
+    };
+    if result < 0 {
+        return Err(FFmpegError::from(result).into());
+    }
+
+    // The H.264 encoder found by default may not be libx264 and know no presets
+    let unused: Vec<&str> = unused
+        .iter()
+        .map(|(key, _)| key)
+        .filter(|key| !(default_preset && *key == "preset"))
+        .collect();
+    if !unused.is_empty() {
+        return Err(VideoEncoderError::InvalidOption(format!(
+            "Encoder {} does not take the options {}",
+            codec.name(),
+            unused.join(", ")
+        )));
+    }
+    Ok(ffmpeg::encoder::video::Encoder(encoder))
+}
+
+fn scale(scaler: &mut Scaler, frame: &AVFrame) -> Result<AVFrame, FFmpegError> {
+    let mut frame_scaled = AVFrame::empty();
+    scaler.run(frame, &mut frame_scaled)?;
+
+    // Copy over PTS from old frame.
+    frame_scaled.set_pts(frame.pts());
+
+    Ok(frame_scaled)
+}
+
+impl Drop for VideoEncoder {
+    fn drop(&mut self) {
+        // Make sure a streaming output is still finalised when it is dropped early
+        if let Err(err) = self.finish() {
+            error!("Error Finishing Encoder {:?}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ffmpeg::{codec::Id, dictionary};
+
+    use super::*;
+    use crate::{
+        decode_video::{dump_frames, DecodeOptions, VideoDecoder},
+        frame_buffer,
+        resize::FrameTransform,
+        AspectRatio, BitRate, FrameRate, Height, MaxBitRate, Width,
+    };
+
+    fn video_info(time_base: Rational, rotation: u32) -> VideoInfo {
+        VideoInfo::new(
+            ffmpeg::decoder::find(Id::MPEG4).unwrap(),
+            Pixel::YUV420P,
+            Width(64),
+            Height(48),
+            AspectRatio(Rational::new(1, 1)),
+            FrameRate(Some(Rational::new(25, 1))),
+            dictionary::Owned::new(),
+            1,
+            BitRate(0),
+            MaxBitRate(0),
+            "mpeg4".into(),
+            None,
+            time_base,
+            Vec::new(),
+            FrameTransform::identity(64, 48),
+            rotation,
+            0,
+            0,
+        )
+    }
+
+    #[test]
+    fn filtered_frames_keep_their_timing_in_a_fallback_time_base() {
+        // mpeg4 takes no time base above 1/65535, so 1/90000 falls back to one frame. The
+        // rotation of the input makes the frames go through a filter graph. They are two
+        // frames apart, timestamps rescaled twice would all end up one frame apart.
+        let v_info = video_info(Rational::new(1, 90000), 90);
+        let options = EncoderOptions {
+            codec: Some("mpeg4".into()),
+            ..Default::default()
+        };
+        let mut encoder = VideoEncoder::new(&v_info, OutputTarget::Memory, &options).unwrap();
+        assert_eq!(encoder.encoder.time_base(), Some(Rational::new(1, 25)));
+        assert!(matches!(encoder.converter, FrameConverter::FilterGraph(_)));
+
+        for index in 0..10u8 {
+            let mut frame = AVFrame::new(Pixel::RGB24, 64, 48);
+            frame_buffer::fill_rgb(&mut frame, [index * 20, 0, 0]);
+            encoder
+                .encode_frame(&mut frame, Some(i64::from(index) * 7200))
+                .unwrap();
+        }
+        encoder.finish().unwrap();
+        let bytes = encoder.take_output_bytes().unwrap().unwrap();
+
+        let decoder = VideoDecoder::open_memory(bytes, &DecodeOptions::default()).unwrap();
+        let (frames, video_info, _) = dump_frames(decoder).unwrap();
+        assert_eq!(frames.len(), 10);
+        assert_eq!((video_info.width(), video_info.height()), (48, 64));
+        let first = frames[0].timestamp.unwrap();
+        for (index, frame) in frames.iter().enumerate() {
+            let seconds =
+                (frame.timestamp.unwrap() - first) as f64 * f64::from(video_info.time_base);
+            assert!((seconds - index as f64 * 0.08).abs() < 1e-6, "{seconds}");
+        }
+    }
+
+    #[test]
+    fn options_the_encoder_does_not_take_are_rejected() {
+        let v_info = video_info(Rational::new(1, 25), 0);
+        for (option, options) in [
+            (
+                "crf",
+                EncoderOptions {
+                    crf: Some(23),
+                    ..Default::default()
+                },
+            ),
+            (
+                "preset",
+                EncoderOptions {
+                    preset: Some("fast".into()),
+                    ..Default::default()
+                },
+            ),
+        ] {
+            let options = EncoderOptions {
+                codec: Some("mpeg4".into()),
+                ..options
+            };
+            let Err(VideoEncoderError::InvalidOption(message)) =
+                VideoEncoder::new(&v_info, OutputTarget::Memory, &options)
+            else {
+                panic!("mpeg4 took {option}");
+            };
+            assert!(message.contains(option), "{message}");
+        }
+    }
+}
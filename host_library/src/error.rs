@@ -0,0 +1,92 @@
+use std::fmt::Display;
+
+use ffmpeg::util::error::ENOENT;
+use ffmpeg::Error as FFmpegError;
+
+use crate::{decode_video::VideoDecoderError, encode_video::VideoEncoderError};
+
+/// Codes handed back to the guest by the host functions, 0 is returned on success.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(i32)]
+pub enum VideoProcessingPluginError {
+    /// Not a failure, returned by `next_frame` once every frame has been decoded
+    EndOfStream = 1,
+    FileNotFound = 2,
+    UnsupportedCodec = 3,
+    NoVideoStream = 4,
+    EmptyVideo = 5,
+    FrameIndexOutOfRange = 6,
+    BufferTooSmall = 7,
+    MissingOutputFrames = 8,
+    DecoderError = 9,
+    EncoderError = 10,
+    InvalidSession = 11,
+    InvalidGuestMemory = 12,
+    /// The call does not fit the current state of the session, e.g. `next_frame` without a stream
+    InvalidState = 13,
+    /// An argument sent by the guest could not be parsed, e.g. malformed JSON
+    InvalidArgument = 14,
+    /// A file next to the output video, e.g. an annotation export, could not be written
+    IoError = 15,
+    /// A path sent by the guest leads outside of the directories the plugin may access
+    PermissionDenied = 16,
+}
+
+/// An error code together with a readable message for `get_last_error_message`
+#[derive(Debug, Clone)]
+pub struct PluginError {
+    pub code: VideoProcessingPluginError,
+    pub message: String,
+}
+
+impl PluginError {
+    pub fn new(code: VideoProcessingPluginError, message: impl Into<String>) -> Self {
+        PluginError {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl Display for PluginError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}: {}", self.code, self.message)
+    }
+}
+
+impl From<VideoDecoderError> for PluginError {
+    fn from(value: VideoDecoderError) -> Self {
+        let code = match &value {
+            VideoDecoderError::FFMpegError(FFmpegError::Other { errno }) if *errno == ENOENT => {
+                VideoProcessingPluginError::FileNotFound
+            }
+            VideoDecoderError::FFMpegError(FFmpegError::StreamNotFound) => {
+                VideoProcessingPluginError::NoVideoStream
+            }
+            VideoDecoderError::FFMpegError(FFmpegError::DecoderNotFound)
+            | VideoDecoderError::FFMpegError(FFmpegError::DemuxerNotFound)
+            | VideoDecoderError::FFMpegError(FFmpegError::PatchWelcome)
+            | VideoDecoderError::CodecError(_) => VideoProcessingPluginError::UnsupportedCodec,
+            VideoDecoderError::InvalidOption(_) | VideoDecoderError::InvalidFilter(_) => {
+                VideoProcessingPluginError::InvalidArgument
+            }
+            VideoDecoderError::FFMpegError(_) => VideoProcessingPluginError::DecoderError,
+        };
+        PluginError::new(code, format!("{:?}", value))
+    }
+}
+
+impl From<VideoEncoderError> for PluginError {
+    fn from(value: VideoEncoderError) -> Self {
+        let code = match &value {
+            VideoEncoderError::FFMpegError(FFmpegError::EncoderNotFound)
+            | VideoEncoderError::FFMpegError(FFmpegError::MuxerNotFound)
+            | VideoEncoderError::CodecError(_) => VideoProcessingPluginError::UnsupportedCodec,
+            VideoEncoderError::InvalidOption(_) | VideoEncoderError::InvalidFilter(_) => {
+                VideoProcessingPluginError::InvalidArgument
+            }
+            VideoEncoderError::FFMpegError(_) => VideoProcessingPluginError::EncoderError,
+        };
+        PluginError::new(code, format!("{:?}", value))
+    }
+}
@@ -0,0 +1,240 @@
+use ffmpeg::Error as FFmpegError;
+
+// Atoms on the way from moov down to the chunk offset tables
+const CONTAINER_ATOMS: [&[u8; 4]; 5] = [b"moov", b"trak", b"mdia", b"minf", b"stbl"];
+
+struct Atom {
+    kind: [u8; 4],
+    start: usize,
+    header_len: usize,
+    end: usize,
+}
+
+/// Moves the moov atom of an MP4 written to memory in front of its media data, so that
+/// players can start before the whole file has arrived. This is what the `faststart` flag
+/// of the muxer does, which needs to reopen the output as a file.
+pub fn move_moov_to_front(data: Vec<u8>) -> Result<Vec<u8>, FFmpegError> {
+    let atoms = atoms(&data, 0, data.len())?;
+    let moov = atoms.iter().find(|atom| &atom.kind == b"moov");
+    let mdat = atoms.iter().find(|atom| &atom.kind == b"mdat");
+    let (Some(moov), Some(mdat)) = (moov, mdat) else {
+        return Ok(data);
+    };
+    if moov.start < mdat.start {
+        return Ok(data);
+    }
+
+    // Everything from the first mdat up to the moov ends up behind it
+    let mut moov_data = data[moov.start..moov.end].to_vec();
+    let moov_len = moov_data.len();
+    shift_chunk_offsets(
+        &mut moov_data,
+        0,
+        moov_len,
+        mdat.start as u64..moov.start as u64,
+        moov_len as u64,
+    )?;
+
+    let mut moved = Vec::with_capacity(data.len());
+    moved.extend_from_slice(&data[..mdat.start]);
+    moved.extend_from_slice(&moov_data);
+    moved.extend_from_slice(&data[mdat.start..moov.start]);
+    moved.extend_from_slice(&data[moov.end..]);
+    Ok(moved)
+}
+
+// Atoms from `start` up to `end`, not descending into them
+fn atoms(data: &[u8], start: usize, end: usize) -> Result<Vec<Atom>, FFmpegError> {
+    let mut atoms = Vec::new();
+    let mut position = start;
+    while position + 8 <= end {
+        let size = read_u32(data, position) as u64;
+        let kind: [u8; 4] = data[position + 4..position + 8]
+            .try_into()
+            .expect("slice of 4 bytes");
+        let (size, header_len) = match size {
+            // Extends to the end of the file
+            0 => ((end - position) as u64, 8),
+            // 64 bit size after the type
+            1 if position + 16 <= end => (read_u64(data, position + 8), 16),
+            size => (size, 8),
+        };
+        if size < header_len as u64 || size > (end - position) as u64 {
+            return Err(FFmpegError::InvalidData);
+        }
+
+        atoms.push(Atom {
+            kind,
+            start: position,
+            header_len,
+            end: position + size as usize,
+        });
+        position += size as usize;
+    }
+    Ok(atoms)
+}
+
+// Adds `shift` to every chunk offset in the stco and co64 tables that falls into `moved`
+fn shift_chunk_offsets(
+    data: &mut [u8],
+    start: usize,
+    end: usize,
+    moved: std::ops::Range<u64>,
+    shift: u64,
+) -> Result<(), FFmpegError> {
+    for atom in atoms(data, start, end)? {
+        let body = atom.start + atom.header_len;
+        if CONTAINER_ATOMS.contains(&&atom.kind) {
+            shift_chunk_offsets(data, body, atom.end, moved.clone(), shift)?;
+            continue;
+        }
+
+        let entry_len = match &atom.kind {
+            b"stco" => 4,
+            b"co64" => 8,
+            _ => continue,
+        };
+        // Version and flags, then the number of entries
+        if body + 8 > atom.end {
+            return Err(FFmpegError::InvalidData);
+        }
+        let entry_count = read_u32(data, body + 4) as usize;
+        let entries = body + 8;
+        if entries + entry_count * entry_len > atom.end {
+            return Err(FFmpegError::InvalidData);
+        }
+
+        for entry in (entries..entries + entry_count * entry_len).step_by(entry_len) {
+            if entry_len == 4 {
+                let offset = read_u32(data, entry) as u64;
+                if moved.contains(&offset) {
+                    // Files this large would need their stco turned into a co64
+                    let shifted =
+                        u32::try_from(offset + shift).map_err(|_| FFmpegError::InvalidData)?;
+                    data[entry..entry + 4].copy_from_slice(&shifted.to_be_bytes());
+                }
+            } else {
+                let offset = read_u64(data, entry);
+                if moved.contains(&offset) {
+                    data[entry..entry + 8].copy_from_slice(&(offset + shift).to_be_bytes());
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn read_u32(data: &[u8], position: usize) -> u32 {
+    u32::from_be_bytes(
+        data[position..position + 4]
+            .try_into()
+            .expect("slice of 4 bytes"),
+    )
+}
+
+fn read_u64(data: &[u8], position: usize) -> u64 {
+    u64::from_be_bytes(
+        data[position..position + 8]
+            .try_into()
+            .expect("slice of 8 bytes"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn atom(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut atom = ((body.len() + 8) as u32).to_be_bytes().to_vec();
+        atom.extend_from_slice(kind);
+        atom.extend_from_slice(body);
+        atom
+    }
+
+    // Version and flags, the number of entries and the entries
+    fn offset_table(offsets: &[u64], entry_len: usize) -> Vec<u8> {
+        let mut table = vec![0; 4];
+        table.extend_from_slice(&(offsets.len() as u32).to_be_bytes());
+        for &offset in offsets {
+            table.extend_from_slice(&offset.to_be_bytes()[8 - entry_len..]);
+        }
+        table
+    }
+
+    fn find(data: &[u8], kind: &[u8; 4]) -> usize {
+        data.windows(4).position(|window| window == kind).unwrap() - 4
+    }
+
+    // ftyp, mdat with two chunks and then a moov with an stco and a co64 table
+    fn moov_at_end() -> (Vec<u8>, Vec<u8>, [u64; 2]) {
+        let ftyp = atom(b"ftyp", b"isom0000");
+        let mdat = atom(b"mdat", &[[1; 10], [2; 10]].concat());
+        let first = ftyp.len() as u64 + 8;
+        let chunks = [first, first + 10];
+        let stbl = atom(
+            b"stbl",
+            &[
+                atom(b"stco", &offset_table(&chunks, 4)),
+                atom(b"co64", &offset_table(&chunks, 8)),
+            ]
+            .concat(),
+        );
+        let moov = atom(
+            b"moov",
+            &atom(b"trak", &atom(b"mdia", &atom(b"minf", &stbl))),
+        );
+        ([ftyp, mdat, moov.clone()].concat(), moov, chunks)
+    }
+
+    #[test]
+    fn moov_moves_in_front_of_mdat() {
+        let (data, moov, chunks) = moov_at_end();
+        let moved = move_moov_to_front(data.clone()).unwrap();
+
+        assert_eq!(moved.len(), data.len());
+        assert_eq!(find(&moved, b"ftyp"), 0);
+        assert!(find(&moved, b"moov") < find(&moved, b"mdat"));
+
+        let shift = moov.len() as u64;
+        let stco = find(&moved, b"stco") + 16;
+        let co64 = find(&moved, b"co64") + 16;
+        for (index, &chunk) in chunks.iter().enumerate() {
+            let offset = read_u32(&moved, stco + index * 4) as u64;
+            assert_eq!(offset, chunk + shift);
+            assert_eq!(read_u64(&moved, co64 + index * 8), chunk + shift);
+            // The shifted offsets still point at the same chunk
+            assert_eq!(moved[offset as usize], index as u8 + 1);
+        }
+    }
+
+    #[test]
+    fn moov_in_front_is_left_alone() {
+        let (data, _, _) = moov_at_end();
+        let moved = move_moov_to_front(data).unwrap();
+        assert_eq!(move_moov_to_front(moved.clone()).unwrap(), moved);
+
+        let without_moov = atom(b"mdat", &[0; 4]);
+        assert_eq!(
+            move_moov_to_front(without_moov.clone()).unwrap(),
+            without_moov
+        );
+    }
+
+    #[test]
+    fn malformed_atoms_are_rejected() {
+        // Bigger than the file, and smaller than its own header
+        let mut too_big = atom(b"moov", &[]);
+        too_big[3] = 50;
+        assert!(move_moov_to_front(too_big).is_err());
+        let mut too_small = atom(b"moov", &[]);
+        too_small[3] = 4;
+        assert!(move_moov_to_front(too_small).is_err());
+
+        // An stco with more entries than it holds
+        let mut table = offset_table(&[8], 4);
+        table[7] = 3;
+        let moov = atom(b"moov", &atom(b"stco", &table));
+        let data = [atom(b"mdat", &[0; 4]), moov].concat();
+        assert!(move_moov_to_front(data).is_err());
+    }
+}
@@ -0,0 +1,357 @@
+use ffmpeg::{
+    filter,
+    format::Pixel,
+    util::{error::EAGAIN, frame::video::Video},
+    Error as FFmpegError, Rational,
+};
+
+// AV_BUFFERSRC_FLAG_KEEP_REF, the buffer source takes a new reference instead of the frame
+const BUFFERSRC_FLAG_KEEP_REF: i32 = 8;
+
+// Filters the guest may use. They only work on the frames going through them, filters with
+// a file option or a second input (movie, drawtext, subtitles, lut3d, curves, ...) could read
+// or write files of the host outside of the directories of the plugin.
+const ALLOWED_FILTERS: [&str; 54] = [
+    "bwdif",
+    "boxblur",
+    "colorbalance",
+    "colorchannelmixer",
+    "colorlevels",
+    "colorspace",
+    "convolution",
+    "copy",
+    "crop",
+    "deband",
+    "deblock",
+    "deflicker",
+    "dejudder",
+    "drawbox",
+    "drawgrid",
+    "edgedetect",
+    "eq",
+    "fade",
+    "fieldmatch",
+    "format",
+    "fps",
+    "framestep",
+    "gblur",
+    "hflip",
+    "hqdn3d",
+    "hue",
+    "kerndeint",
+    "lutrgb",
+    "lutyuv",
+    "minterpolate",
+    "negate",
+    "nlmeans",
+    "noise",
+    "null",
+    "pad",
+    "pp7",
+    "removegrain",
+    "rotate",
+    "scale",
+    "select",
+    "setdar",
+    "setpts",
+    "setsar",
+    "smartblur",
+    "tpad",
+    "transpose",
+    "trim",
+    "unsharp",
+    "vflip",
+    "vibrance",
+    "vignette",
+    "w3fdif",
+    "yadif",
+    "zscale",
+];
+
+/// Frames going into a filter graph
+#[derive(Debug, Clone, Copy)]
+pub struct FilterInput {
+    pub format: Pixel,
+    pub width: u32,
+    pub height: u32,
+    pub time_base: Rational,
+    pub aspect_ratio: Rational,
+    pub frame_rate: Option<Rational>,
+}
+
+/// An FFmpeg filter graph with a single video input and output built from a filter string,
+/// e.g. `hqdn3d,eq=contrast=1.2,transpose=1`. Frames come out in `output_format`,
+/// FFmpeg inserts the conversion where needed.
+pub struct FilterGraph {
+    graph: filter::Graph,
+    spec: String,
+    input: FilterInput,
+    output_format: Pixel,
+    // Set once the end of the input has been sent
+    flushed: bool,
+}
+
+impl FilterGraph {
+    /// Parses and configures the graph, errors tell which filter string could not be used
+    pub fn new(spec: &str, input: FilterInput, output_format: Pixel) -> Result<Self, String> {
+        if spec.trim().is_empty() {
+            return Err("Filter string is empty".into());
+        }
+        for name in filter_names(spec)? {
+            if !ALLOWED_FILTERS.contains(&name) {
+                return Err(format!("Filter {name} is not allowed in \"{spec}\""));
+            }
+        }
+        let graph = Self::build(spec, &input, output_format)
+            .map_err(|err| format!("Could not build filter graph \"{spec}\": {err}"))?;
+        Ok(FilterGraph {
+            graph,
+            spec: spec.to_string(),
+            input,
+            output_format,
+            flushed: false,
+        })
+    }
+
+    fn build(
+        spec: &str,
+        input: &FilterInput,
+        output_format: Pixel,
+    ) -> Result<filter::Graph, FFmpegError> {
+        let mut graph = filter::Graph::new();
+
+        let aspect_ratio = match input.aspect_ratio {
+            aspect_ratio if aspect_ratio.numerator() == 0 => Rational::new(1, 1),
+            aspect_ratio => aspect_ratio,
+        };
+        let mut args = format!(
+            "video_size={}x{}:pix_fmt={}:time_base={}/{}:pixel_aspect={}/{}",
+            input.width,
+            input.height,
+            ffmpeg::ffi::AVPixelFormat::from(input.format) as i32,
+            input.time_base.numerator(),
+            input.time_base.denominator(),
+            aspect_ratio.numerator(),
+            aspect_ratio.denominator(),
+        );
+        if let Some(frame_rate) = input.frame_rate {
+            args.push_str(&format!(
+                ":frame_rate={}/{}",
+                frame_rate.numerator(),
+                frame_rate.denominator()
+            ));
+        }
+
+        let buffer = filter::find("buffer").ok_or(FFmpegError::FilterNotFound)?;
+        let buffer_sink = filter::find("buffersink").ok_or(FFmpegError::FilterNotFound)?;
+        graph.add(&buffer, "in", &args)?;
+        graph.add(&buffer_sink, "out", "")?;
+        graph
+            .get("out")
+            .ok_or(FFmpegError::FilterNotFound)?
+            .set_pixel_format(output_format);
+
+        graph.output("in", 0)?.input("out", 0)?.parse(spec)?;
+        graph.validate()?;
+        Ok(graph)
+    }
+
+    /// Starts over with a fresh graph, e.g. after a seek or once it has been flushed
+    pub fn reset(&mut self) -> Result<(), FFmpegError> {
+        self.graph = Self::build(&self.spec, &self.input, self.output_format)?;
+        self.flushed = false;
+        Ok(())
+    }
+
+    fn sink(&mut self) -> filter::Context {
+        self.graph
+            .get("out")
+            .expect("filter graph is built with an out filter")
+    }
+
+    pub fn output_size(&mut self) -> (u32, u32) {
+        let sink = self.sink();
+        unsafe {
+            (
+                ffmpeg::ffi::av_buffersink_get_w(sink.as_ptr()) as u32,
+                ffmpeg::ffi::av_buffersink_get_h(sink.as_ptr()) as u32,
+            )
+        }
+    }
+
+    pub fn output_time_base(&mut self) -> Rational {
+        self.sink().sink().time_base()
+    }
+
+    pub fn output_aspect_ratio(&mut self) -> Rational {
+        let sink = self.sink();
+        Rational::from(unsafe { ffmpeg::ffi::av_buffersink_get_sample_aspect_ratio(sink.as_ptr()) })
+    }
+
+    /// Frame rate of the output, `None` if the graph does not know it
+    pub fn output_frame_rate(&mut self) -> Option<Rational> {
+        let sink = self.sink();
+        let frame_rate =
+            Rational::from(unsafe { ffmpeg::ffi::av_buffersink_get_frame_rate(sink.as_ptr()) });
+        (frame_rate.numerator() > 0 && frame_rate.denominator() > 0).then_some(frame_rate)
+    }
+
+    pub fn is_flushed(&self) -> bool {
+        self.flushed
+    }
+
+    /// Sends a frame into the graph, the frame itself is left untouched
+    pub fn send(&mut self, frame: &Video) -> Result<(), FFmpegError> {
+        let mut source = self
+            .graph
+            .get("in")
+            .expect("filter graph is built with an in filter");
+        match unsafe {
+            ffmpeg::ffi::av_buffersrc_add_frame_flags(
+                source.as_mut_ptr(),
+                frame.as_ptr() as *mut _,
+                BUFFERSRC_FLAG_KEEP_REF,
+            )
+        } {
+            0 => Ok(()),
+            err => Err(FFmpegError::from(err)),
+        }
+    }
+
+    /// Tells the graph that no more frames follow so it hands out the frames it still holds
+    pub fn flush(&mut self) -> Result<(), FFmpegError> {
+        self.flushed = true;
+        self.graph
+            .get("in")
+            .expect("filter graph is built with an in filter")
+            .source()
+            .flush()
+    }
+
+    /// Next filtered frame, `None` if the graph needs more input or is drained
+    pub fn receive(&mut self) -> Result<Option<Video>, FFmpegError> {
+        let mut frame = Video::empty();
+        match self.sink().sink().frame(&mut frame) {
+            Ok(()) => Ok(Some(frame)),
+            Err(FFmpegError::Other { errno: EAGAIN }) | Err(FFmpegError::Eof) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+// Names of the filters in a filter string, following the syntax libavfilter parses:
+// chains separated by `;` of filters separated by `,`, each `[in]name@id=args[out]`
+// where the arguments may be quoted with `'` or escaped with `\`
+fn filter_names(spec: &str) -> Result<Vec<&str>, String> {
+    let bytes = spec.as_bytes();
+    let mut names = Vec::new();
+    let mut position = 0;
+
+    let skip_labels = |mut position: usize| -> Result<usize, String> {
+        loop {
+            while bytes.get(position).is_some_and(u8::is_ascii_whitespace) {
+                position += 1;
+            }
+            if bytes.get(position) != Some(&b'[') {
+                return Ok(position);
+            }
+            position += spec[position..]
+                .find(']')
+                .ok_or_else(|| format!("Unterminated link label in \"{spec}\""))?
+                + 1;
+        }
+    };
+
+    loop {
+        position = skip_labels(position)?;
+        let start = position;
+        while bytes
+            .get(position)
+            .is_some_and(|byte| !b"=,;[ \t\r\n".contains(byte))
+        {
+            position += 1;
+        }
+        let name = &spec[start..position];
+        if name.is_empty() {
+            return Err(format!("Missing filter name in \"{spec}\""));
+        }
+        // `name@id` names an instance of the filter
+        names.push(name.split('@').next().unwrap_or(name));
+
+        if bytes.get(position) == Some(&b'=') {
+            position += 1;
+            let mut quoted = false;
+            while let Some(&byte) = bytes.get(position) {
+                match byte {
+                    b'\\' if !quoted => position += 1,
+                    b'\'' => quoted = !quoted,
+                    b'[' | b']' | b',' | b';' if !quoted => break,
+                    _ => {}
+                }
+                position += 1;
+            }
+            if quoted {
+                return Err(format!("Unterminated quote in \"{spec}\""));
+            }
+        }
+
+        position = skip_labels(position)?;
+        match bytes.get(position) {
+            None => return Ok(names),
+            Some(b',') | Some(b';') => position += 1,
+            Some(_) => return Err(format!("Unexpected character in \"{spec}\"")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filter_names_of_chains() {
+        assert_eq!(
+            filter_names("[in]scale=640:-2 , hqdn3d[a];[a] eq=contrast=1.2@x").unwrap(),
+            vec!["scale", "hqdn3d", "eq"]
+        );
+        assert_eq!(
+            filter_names("drawbox@box=x=10:color='red,blue',vflip").unwrap(),
+            vec!["drawbox", "vflip"]
+        );
+        assert_eq!(
+            filter_names("scale=w=1\\,movie=/etc/passwd").unwrap(),
+            vec!["scale"]
+        );
+    }
+
+    #[test]
+    fn filter_names_rejects_malformed_strings() {
+        assert!(filter_names("scale=w='1").is_err());
+        assert!(filter_names("[in scale").is_err());
+        assert!(filter_names("scale,,vflip").is_err());
+        assert!(filter_names("sca le").is_err());
+    }
+
+    #[test]
+    fn file_reading_filters_are_not_allowed() {
+        let input = FilterInput {
+            format: Pixel::RGB24,
+            width: 64,
+            height: 64,
+            time_base: Rational::new(1, 25),
+            aspect_ratio: Rational::new(1, 1),
+            frame_rate: None,
+        };
+        for spec in [
+            "movie=/etc/passwd",
+            "scale=64:64,drawtext=textfile=/etc/passwd",
+            "subtitles=/etc/passwd",
+            "[in]ass=x.ass",
+            "lut3d=file=/etc/passwd",
+            "'movie'=/etc/passwd",
+        ] {
+            let err = FilterGraph::new(spec, input, Pixel::RGB24).err().unwrap();
+            assert!(err.contains("not allowed"), "{spec}: {err}");
+        }
+    }
+}
@@ -0,0 +1,161 @@
+use ffmpeg::util::frame::video::Video;
+
+/// Size in bytes of a tightly packed RGB24 image, as the guest sees frames
+pub fn packed_frame_size(width: u32, height: u32) -> usize {
+    width as usize * height as usize * 3
+}
+
+/// Copies an RGB24 frame into `buf` row by row, leaving out the padding FFmpeg adds
+/// at the end of each row for alignment, e.g. for odd widths like 1366 or 854.
+/// `buf` must hold at least `packed_frame_size` bytes.
+pub fn copy_frame_to_packed(frame: &Video, buf: &mut [u8]) {
+    let row_len = frame.width() as usize * 3;
+    let rows = frame
+        .data(0)
+        .chunks(frame.stride(0))
+        .zip(buf.chunks_exact_mut(row_len))
+        .take(frame.height() as usize);
+
+    for (frame_row, packed_row) in rows {
+        packed_row.copy_from_slice(&frame_row[..row_len]);
+    }
+}
+
+/// Copies a tightly packed RGB24 image from `buf` into a frame, row by row, respecting its stride.
+/// `buf` must hold at least `packed_frame_size` bytes.
+pub fn copy_packed_to_frame(buf: &[u8], frame: &mut Video) {
+    let row_len = frame.width() as usize * 3;
+    let height = frame.height() as usize;
+    let stride = frame.stride(0);
+    let rows = frame
+        .data_mut(0)
+        .chunks_mut(stride)
+        .zip(buf.chunks_exact(row_len))
+        .take(height);
+
+    for (frame_row, packed_row) in rows {
+        frame_row[..row_len].copy_from_slice(packed_row);
+    }
+}
+
+/// Sets every pixel of an RGB24 frame to `color`
+pub fn fill_rgb(frame: &mut Video, color: [u8; 3]) {
+    let row_len = frame.width() as usize * 3;
+    let height = frame.height() as usize;
+    let stride = frame.stride(0);
+    for frame_row in frame.data_mut(0).chunks_mut(stride).take(height) {
+        for pixel in frame_row[..row_len].chunks_exact_mut(3) {
+            pixel.copy_from_slice(&color);
+        }
+    }
+}
+
+/// Copies an RGB24 frame into another one with its top left corner at `(x, y)`.
+/// The position may be negative or the source bigger than the target, whatever falls outside
+/// the target is left out.
+pub fn copy_rgb_at(source: &Video, target: &mut Video, x: i32, y: i32) {
+    let (source_width, source_height) = (source.width() as i32, source.height() as i32);
+    let (target_width, target_height) = (target.width() as i32, target.height() as i32);
+
+    // Overlap of both frames in target coordinates
+    let (left, right) = (x.max(0), (x + source_width).min(target_width));
+    let (top, bottom) = (y.max(0), (y + source_height).min(target_height));
+    if left >= right || top >= bottom {
+        return;
+    }
+
+    let row_len = (right - left) as usize * 3;
+    let source_left = (left - x) as usize * 3;
+    let target_left = left as usize * 3;
+    let (source_stride, target_stride) = (source.stride(0), target.stride(0));
+    let source_data = source.data(0);
+    let target_data = target.data_mut(0);
+
+    for target_y in top..bottom {
+        let source_start = (target_y - y) as usize * source_stride + source_left;
+        let target_start = target_y as usize * target_stride + target_left;
+        target_data[target_start..target_start + row_len]
+            .copy_from_slice(&source_data[source_start..source_start + row_len]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ffmpeg::format::Pixel;
+
+    use super::*;
+
+    fn pattern(width: u32, height: u32) -> Vec<u8> {
+        (0..packed_frame_size(width, height))
+            .map(|i| (i % 251) as u8)
+            .collect()
+    }
+
+    fn pixel(frame: &Video, x: usize, y: usize) -> [u8; 3] {
+        let start = y * frame.stride(0) + x * 3;
+        frame.data(0)[start..start + 3].try_into().unwrap()
+    }
+
+    #[test]
+    fn packed_round_trip_with_padded_rows() {
+        for (width, height) in [(1366, 768), (854, 480), (1, 3)] {
+            let packed = pattern(width, height);
+            let mut frame = Video::new(Pixel::RGB24, width, height);
+            if width > 1 {
+                assert!(
+                    frame.stride(0) > width as usize * 3,
+                    "{width} has no padding"
+                );
+            }
+
+            copy_packed_to_frame(&packed, &mut frame);
+            let mut copied = vec![0; packed_frame_size(width, height)];
+            copy_frame_to_packed(&frame, &mut copied);
+            assert_eq!(copied, packed, "{width}x{height}");
+        }
+    }
+
+    #[test]
+    fn fill_rgb_reaches_the_last_pixel() {
+        let mut frame = Video::new(Pixel::RGB24, 854, 3);
+        fill_rgb(&mut frame, [1, 2, 3]);
+        let mut packed = vec![0; packed_frame_size(854, 3)];
+        copy_frame_to_packed(&frame, &mut packed);
+        assert!(packed.chunks_exact(3).all(|pixel| pixel == [1, 2, 3]));
+    }
+
+    #[test]
+    fn copy_rgb_at_clips_at_the_edges() {
+        let mut source = Video::new(Pixel::RGB24, 4, 4);
+        fill_rgb(&mut source, [9, 9, 9]);
+
+        for (x, y, inside) in [
+            (-2, -2, [(0, 0), (1, 1)]),
+            (8, 6, [(8, 6), (9, 7)]),
+            (-3, 6, [(0, 6), (0, 7)]),
+        ] {
+            let mut target = Video::new(Pixel::RGB24, 10, 8);
+            fill_rgb(&mut target, [0, 0, 0]);
+            copy_rgb_at(&source, &mut target, x, y);
+
+            let painted = (0..8)
+                .flat_map(|ty| (0..10).map(move |tx| (tx, ty)))
+                .filter(|&(tx, ty)| pixel(&target, tx, ty) == [9, 9, 9])
+                .count();
+            let (left, top) = (x.max(0), y.max(0));
+            let (right, bottom) = ((x + 4).min(10), (y + 4).min(8));
+            assert_eq!(painted as i32, (right - left) * (bottom - top), "{x},{y}");
+            for (tx, ty) in inside {
+                assert_eq!(pixel(&target, tx, ty), [9, 9, 9], "{x},{y}");
+            }
+        }
+
+        // Entirely outside
+        let mut target = Video::new(Pixel::RGB24, 10, 8);
+        fill_rgb(&mut target, [0, 0, 0]);
+        copy_rgb_at(&source, &mut target, 10, -4);
+        let mut packed = vec![0; packed_frame_size(10, 8)];
+        copy_frame_to_packed(&target, &mut packed);
+        assert!(packed.iter().all(|&byte| byte == 0));
+    }
+}
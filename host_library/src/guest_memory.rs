@@ -0,0 +1,124 @@
+use wasmedge_sdk::{Caller, Memory};
+
+use crate::error::{PluginError, VideoProcessingPluginError};
+
+/// Bounds checked access to the linear memory of the guest.
+/// Every pointer the guest hands over is an offset into this memory, sent as an i32 and
+/// reinterpreted as u32. All accesses go through slices so the host never builds owned
+/// `Vec`s or `String`s over memory it does not own.
+pub(crate) struct GuestMemory {
+    memory: Memory,
+}
+
+impl GuestMemory {
+    /// The main memory of the guest that called the host function
+    pub fn from_caller(caller: &Caller) -> Result<Self, PluginError> {
+        let memory = caller.memory(0).ok_or_else(|| {
+            PluginError::new(
+                VideoProcessingPluginError::InvalidGuestMemory,
+                "Could not get main memory of the guest",
+            )
+        })?;
+        Ok(GuestMemory { memory })
+    }
+
+    pub fn slice(&self, offset: i32, len: usize) -> Result<&[u8], PluginError> {
+        if len == 0 {
+            return Ok(&[]);
+        }
+        let ptr = self
+            .memory
+            .data_pointer(offset as u32, Self::len(len)?)
+            .map_err(|err| Self::out_of_bounds(offset, len, err))?;
+        // data_pointer has checked that offset..offset + len lies within the memory
+        Ok(unsafe { std::slice::from_raw_parts(ptr, len) })
+    }
+
+    pub fn slice_mut(&mut self, offset: i32, len: usize) -> Result<&mut [u8], PluginError> {
+        if len == 0 {
+            return Ok(&mut []);
+        }
+        let ptr = self
+            .memory
+            .data_pointer_mut(offset as u32, Self::len(len)?)
+            .map_err(|err| Self::out_of_bounds(offset, len, err))?;
+        // data_pointer_mut has checked that offset..offset + len lies within the memory
+        Ok(unsafe { std::slice::from_raw_parts_mut(ptr, len) })
+    }
+
+    /// A string sent by the guest, e.g. a path, which has to be valid UTF-8
+    pub fn str(&self, offset: i32, len: usize) -> Result<&str, PluginError> {
+        std::str::from_utf8(self.slice(offset, len)?).map_err(|err| {
+            PluginError::new(
+                VideoProcessingPluginError::InvalidArgument,
+                format!("String sent by the guest is not valid UTF-8 {err}"),
+            )
+        })
+    }
+
+    pub fn read_i32(&self, offset: i32) -> Result<i32, PluginError> {
+        let bytes = self.slice(offset, std::mem::size_of::<i32>())?;
+        // Wasm memory is little endian
+        Ok(i32::from_le_bytes(
+            bytes.try_into().expect("slice has the size of an i32"),
+        ))
+    }
+
+    pub fn write_u32(&mut self, offset: i32, value: u32) -> Result<(), PluginError> {
+        self.slice_mut(offset, std::mem::size_of::<u32>())?
+            .copy_from_slice(&value.to_le_bytes());
+        Ok(())
+    }
+
+    /// Writes a `repr(C)` struct the guest mirrors, guest pointers need not be aligned
+    pub fn write_value<T: Copy>(&mut self, offset: i32, value: T) -> Result<(), PluginError> {
+        let bytes = self.slice_mut(offset, std::mem::size_of::<T>())?;
+        unsafe { std::ptr::write_unaligned(bytes.as_mut_ptr() as *mut T, value) };
+        Ok(())
+    }
+
+    /// Copies `bytes` into the guest buffer and writes their length to `len_ptr`.
+    /// If the buffer is too small nothing is copied, the required length is still written
+    /// so that the guest can retry with a bigger buffer.
+    pub fn copy_to_buffer(
+        &mut self,
+        bytes: &[u8],
+        buf_ptr: i32,
+        buf_len: usize,
+        len_ptr: i32,
+    ) -> Result<(), PluginError> {
+        self.write_u32(len_ptr, bytes.len() as u32)?;
+
+        if buf_len < bytes.len() {
+            return Err(PluginError::new(
+                VideoProcessingPluginError::BufferTooSmall,
+                format!(
+                    "Buffer of {buf_len} bytes is too small for {} bytes",
+                    bytes.len()
+                ),
+            ));
+        }
+
+        self.slice_mut(buf_ptr, bytes.len())?.copy_from_slice(bytes);
+        Ok(())
+    }
+
+    fn len(len: usize) -> Result<u32, PluginError> {
+        u32::try_from(len).map_err(|_| {
+            PluginError::new(
+                VideoProcessingPluginError::InvalidGuestMemory,
+                format!("Length {len} does not fit into guest memory"),
+            )
+        })
+    }
+
+    fn out_of_bounds(offset: i32, len: usize, err: impl std::fmt::Display) -> PluginError {
+        PluginError::new(
+            VideoProcessingPluginError::InvalidGuestMemory,
+            format!(
+                "{len} bytes at guest pointer {} are out of bounds {err}",
+                offset as u32
+            ),
+        )
+    }
+}
@@ -1,15 +1,34 @@
-use std::sync::{Arc, Mutex};
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
+mod annotations;
 mod decode_video;
+mod detection;
+mod draw;
 mod encode_video;
+mod error;
+mod faststart;
+mod filter_graph;
+mod frame_buffer;
+mod guest_memory;
+mod memory_io;
+mod redact;
+mod resize;
+mod rotation;
+mod sandbox;
+mod tensor;
 mod time;
+mod tracking;
 
 use ffmpeg::{
-    dictionary,
+    codec, dictionary,
     format::Pixel,
     frame,
     picture::{self},
-    Codec, Rational,
+    Codec, Packet, Rational,
 };
 
 use simplelog::{ColorChoice, CombinedLogger, Config, TermLogger, TerminalMode};
@@ -17,11 +36,17 @@ use wasmedge_sdk::{
     error::HostFuncError,
     host_function,
     plugin::{ffi, PluginDescriptor, PluginModuleBuilder, PluginVersion},
-    Caller, Memory, NeverType, WasmValue,
+    Caller, NeverType, WasmValue,
 };
 
+use serde::{de::DeserializeOwned, Serialize};
 use std::fmt::Debug;
 
+use decode_video::DecodeOptions;
+use error::{PluginError, VideoProcessingPluginError};
+use guest_memory::GuestMemory;
+use resize::FrameTransform;
+
 use log::{debug, error, LevelFilter};
 
 #[derive(Debug, Copy, Clone)]
@@ -38,6 +63,16 @@ pub struct BitRate(pub usize);
 #[derive(Debug, Copy, Clone)]
 pub struct MaxBitRate(pub usize);
 
+/// A non video stream of the input that is copied into the output without being re-encoded
+#[derive(Clone)]
+pub struct PassthroughStream {
+    // Index of the stream in the input
+    pub index: usize,
+    pub parameters: codec::Parameters,
+    // Time base the packets of the stream are read in
+    pub time_base: Rational,
+}
+
 #[derive(Clone)]
 pub struct VideoInfo {
     pub codec: Codec,
@@ -50,10 +85,24 @@ pub struct VideoInfo {
     pub itcx_number_streams: u32,
     pub bitrate: BitRate,
     pub max_bitrate: MaxBitRate,
+    // Name of the codec the input video stream was encoded with
+    pub codec_name: String,
+    pub duration: Option<Duration>,
+    // Time base of the input video stream, frame timestamps and durations are expressed in it
+    pub time_base: Rational,
+    // Audio, subtitle and data streams that are remuxed into the output unchanged
+    pub passthrough_streams: Vec<PassthroughStream>,
+    // How the decoded frames were resized to width x height
+    pub frame_transform: FrameTransform,
+    // Clockwise rotation in degrees the input video is shown with according to its metadata
+    pub rotation: u32,
+    // Part of the rotation already applied to the decoded frames, 0 or rotation
+    pub applied_rotation: u32,
+    // Start of the decoded range in the input time base, 0 without one. Subtracted from the
+    // timestamps of the frames and passthrough packets when encoding, so outputs start at 0
+    pub range_offset: i64,
 }
 
-pub enum VideoProcessingPluginError {}
-
 impl Debug for VideoInfo {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("VideoInfo")
@@ -65,6 +114,21 @@ impl Debug for VideoInfo {
             .field("frame_rate", &self.frame_rate.0)
             .field("input_stream_meta_data", &self.input_stream_meta_data)
             .field("itcx_number_streams", &self.itcx_number_streams)
+            .field("codec_name", &self.codec_name)
+            .field("duration", &self.duration)
+            .field("time_base", &self.time_base)
+            .field(
+                "passthrough_streams",
+                &self
+                    .passthrough_streams
+                    .iter()
+                    .map(|stream| (stream.index, stream.parameters.id()))
+                    .collect::<Vec<_>>(),
+            )
+            .field("frame_transform", &self.frame_transform)
+            .field("rotation", &self.rotation)
+            .field("applied_rotation", &self.applied_rotation)
+            .field("range_offset", &self.range_offset)
             .finish()
     }
 }
@@ -81,6 +145,14 @@ impl VideoInfo {
         itcx_number_streams: u32,
         bitrate: BitRate,
         max_bitrate: MaxBitRate,
+        codec_name: String,
+        duration: Option<Duration>,
+        time_base: Rational,
+        passthrough_streams: Vec<PassthroughStream>,
+        frame_transform: FrameTransform,
+        rotation: u32,
+        applied_rotation: u32,
+        range_offset: i64,
     ) -> Self {
         VideoInfo {
             codec,
@@ -93,6 +165,14 @@ impl VideoInfo {
             itcx_number_streams,
             bitrate,
             max_bitrate,
+            codec_name,
+            duration,
+            time_base,
+            passthrough_streams,
+            frame_transform,
+            rotation,
+            applied_rotation,
+            range_offset,
         }
     }
 
@@ -105,19 +185,105 @@ impl VideoInfo {
     }
 }
 
+/// Version of the host function signatures, returned by `get_abi_version`.
+/// Bumped whenever a host function is removed or its arguments change, so guests can refuse
+/// to run against a plugin they were not built for.
+pub const PLUGIN_ABI_VERSION: i32 = 1;
+
+/// Version of the JSON written by `get_video_info`.
+/// Bumped whenever a field is removed or changes its meaning, new fields may be added at any time.
+pub const VIDEO_INFO_VERSION: u32 = 1;
+
+/// `VideoInfo` as handed to the guest by `get_video_info`
+#[derive(Debug, Serialize)]
+pub struct VideoInfoReport {
+    pub version: u32,
+    pub codec_name: String,
+    pub pixel_format: String,
+    pub width: u32,
+    pub height: u32,
+    // (numerator, denominator)
+    pub aspect_ratio: (i32, i32),
+    // (numerator, denominator)
+    pub frame_rate: Option<(i32, i32)>,
+    pub frames_per_second: Option<f64>,
+    // (numerator, denominator) of the input video stream
+    pub time_base: (i32, i32),
+    pub duration_seconds: Option<f64>,
+    pub bitrate: usize,
+    pub max_bitrate: usize,
+    pub stream_count: u32,
+    pub metadata: BTreeMap<String, String>,
+    // Size of the source and where it ended up in the frames of width x height
+    pub frame_transform: FrameTransform,
+    // Clockwise rotation in degrees from the metadata of the input video
+    pub rotation: u32,
+    // Rotation applied to the frames by auto_rotate, either 0 or rotation
+    pub applied_rotation: u32,
+}
+
+impl From<&VideoInfo> for VideoInfoReport {
+    fn from(video_info: &VideoInfo) -> Self {
+        VideoInfoReport {
+            version: VIDEO_INFO_VERSION,
+            codec_name: video_info.codec_name.clone(),
+            pixel_format: format!("{:?}", video_info.format),
+            width: video_info.width(),
+            height: video_info.height(),
+            aspect_ratio: (
+                video_info.aspect_ratio.0.numerator(),
+                video_info.aspect_ratio.0.denominator(),
+            ),
+            frame_rate: video_info
+                .frame_rate
+                .0
+                .map(|frame_rate| (frame_rate.numerator(), frame_rate.denominator())),
+            frames_per_second: video_info.frame_rate.0.map(f64::from),
+            time_base: (
+                video_info.time_base.numerator(),
+                video_info.time_base.denominator(),
+            ),
+            duration_seconds: video_info.duration.map(|duration| duration.as_secs_f64()),
+            bitrate: video_info.bitrate.0,
+            max_bitrate: video_info.max_bitrate.0,
+            stream_count: video_info.itcx_number_streams,
+            metadata: video_info
+                .input_stream_meta_data
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+            frame_transform: video_info.frame_transform,
+            rotation: video_info.rotation,
+            applied_rotation: video_info.applied_rotation,
+        }
+    }
+}
+
+#[host_function]
+fn get_abi_version(
+    _caller: Caller,
+    _args: Vec<WasmValue>,
+    _data: &mut Arc<Mutex<VideoSessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    Ok(vec![WasmValue::from_i32(PLUGIN_ABI_VERSION)])
+}
+
 #[host_function]
 fn init_plugin_logging(
     caller: Caller,
     args: Vec<WasmValue>,
-    _data: &mut Arc<Mutex<FramesMap>>,
+    _data: &mut Arc<Mutex<VideoSessions>>,
 ) -> Result<Vec<WasmValue>, HostFuncError> {
-    let log_level_ptr = args[0].to_i32() as *mut i32;
+    let log_level_ptr = args[0].to_i32();
 
-    let mut main_memory = caller.memory(0).ok_or(HostFuncError::User(1))?;
+    let log_level = GuestMemory::from_caller(&caller)
+        .and_then(|main_memory| main_memory.read_i32(log_level_ptr))
+        .map_err(|err| {
+            error!("{err}");
+            HostFuncError::User(1)
+        })?;
 
-    let log_level_main_memory = main_memory.try_get_ptr::<u32>(log_level_ptr as u32, 1)?;
-
-    let log_level = match unsafe { *log_level_main_memory } {
+    let log_level = match log_level {
         0 => LevelFilter::Off,
         1 => LevelFilter::Error,
         2 => LevelFilter::Warn,
@@ -138,18 +304,34 @@ fn init_plugin_logging(
     return Ok(vec![WasmValue::from_i32(0)]);
 }
 
-trait TryGetPointer {
-    fn try_get_ptr<T>(&mut self, offset: u32, len: u32) -> Result<*mut T, HostFuncError>;
-}
+/// Locks the plugin state and runs the body of a host function with it.
+/// Failures are logged, kept for `get_last_error_message` and returned to the guest as their code.
+fn with_sessions<F>(
+    data: &mut Arc<Mutex<VideoSessions>>,
+    host_function: F,
+) -> Result<Vec<WasmValue>, HostFuncError>
+where
+    F: FnOnce(&mut VideoSessions) -> Result<Vec<WasmValue>, PluginError>,
+{
+    let mut data_guard = match data.lock() {
+        Ok(x) => x,
+        Err(err) => {
+            error!("Mutex Carrying plugin Data Poisoned {err}");
+            return Err(HostFuncError::Runtime(1));
+        }
+    };
 
-impl TryGetPointer for Memory {
-    fn try_get_ptr<T>(&mut self, offset: u32, len: u32) -> Result<*mut T, HostFuncError> {
-        match self.data_pointer_mut(offset as u32, len) {
-            Ok(x) => Ok(x as *mut T),
-            Err(err) => {
-                error!("Error Getting Value from Pointer {}", err);
-                Err(HostFuncError::User(1))
+    match host_function(&mut data_guard) {
+        Ok(res) => Ok(res),
+        Err(err) => {
+            if err.code == VideoProcessingPluginError::EndOfStream {
+                debug!("{err}");
+            } else {
+                error!("{err}");
             }
+            let code = err.code as i32;
+            data_guard.last_error = Some(err);
+            Ok(vec![WasmValue::from_i32(code)])
         }
     }
 }
@@ -158,241 +340,1005 @@ impl TryGetPointer for Memory {
 fn load_video_to_host_memory(
     caller: Caller,
     args: Vec<WasmValue>,
-    data: &mut Arc<Mutex<FramesMap>>,
+    data: &mut Arc<Mutex<VideoSessions>>,
 ) -> Result<Vec<WasmValue>, HostFuncError> {
     debug!("Load_video");
 
-    let data_guard = match data.lock() {
-        Ok(x) => x,
-        Err(err) => {
-            error!("Mutex Carrying plugin Data Poisoned {err}");
-            return Err(HostFuncError::Runtime(1));
-        }
-    };
+    with_sessions(data, |data_guard| {
+        let mut main_memory = GuestMemory::from_caller(&caller)?;
 
-    let mut main_memory = caller.memory(0).ok_or(HostFuncError::User(1))?;
+        let filename_ptr = args[0].to_i32();
+        let filename_len = args[1].to_i32() as usize;
 
-    let filename_ptr = args[0].to_i32();
-    let filename_len = args[1].to_i32();
-    let filaname_capacity = args[2].to_i32();
+        let filename = main_memory.str(filename_ptr, filename_len)?.to_string();
+        let host_path = data_guard
+            .sandbox
+            .resolve(&filename, sandbox::Access::Read)?;
 
-    let width_ptr = args[3].to_i32() as *mut i32;
-    let height_ptr = args[4].to_i32() as *mut i32;
-    let frames_ptr = args[5].to_i32() as *mut i32;
+        debug!("Call FFMPEG dump Frames");
+
+        let dumped = decode_video::VideoDecoder::open(&host_path, &DecodeOptions::default())
+            .and_then(decode_video::dump_frames);
+        open_loaded_session(data_guard, &mut main_memory, &filename, dumped, &args[2..6])
+    })
+}
 
-    let width_ptr_main_memory = main_memory.try_get_ptr::<u32>(width_ptr as u32, 1)?;
-    let height_ptr_main_memory = main_memory.try_get_ptr::<u32>(height_ptr as u32, 1)?;
-    let frames_ptr_main_memory = main_memory.try_get_ptr::<u32>(frames_ptr as u32, 1)?;
+/// Like `load_video_to_host_memory` with `DecodeOptions` sent by the guest as JSON, e.g.
+/// `{"target_fps": 5.0}` to only keep 5 frames per second of video.
+#[host_function]
+fn load_video_with_options_to_host_memory(
+    caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<Mutex<VideoSessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("load_video_with_options");
+
+    with_sessions(data, |data_guard| {
+        let mut main_memory = GuestMemory::from_caller(&caller)?;
+
+        let filename_ptr = args[0].to_i32();
+        let filename_len = args[1].to_i32() as usize;
+        let options_ptr = args[2].to_i32();
+        let options_len = args[3].to_i32() as usize;
+
+        let filename = main_memory.str(filename_ptr, filename_len)?.to_string();
+        let host_path = data_guard
+            .sandbox
+            .resolve(&filename, sandbox::Access::Read)?;
+        let decode_options = parse_decode_options(&main_memory, options_ptr, options_len)?;
+
+        let dumped = decode_video::VideoDecoder::open(&host_path, &decode_options)
+            .and_then(decode_video::dump_frames);
+        open_loaded_session(data_guard, &mut main_memory, &filename, dumped, &args[4..8])
+    })
+}
+
+/// Like `load_video_to_host_memory`, but only decodes the frames shown between `start_us` and
+/// `end_us`, in microseconds from the start of the video. A negative `end_us` reads to the end.
+/// Videos encoded from the session start at 0, not at `start_us`.
+#[host_function]
+fn load_video_range_to_host_memory(
+    caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<Mutex<VideoSessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("load_video_range");
+
+    with_sessions(data, |data_guard| {
+        let mut main_memory = GuestMemory::from_caller(&caller)?;
+
+        let filename_ptr = args[0].to_i32();
+        let filename_len = args[1].to_i32() as usize;
+        let start_us = args[2].to_i64();
+        let end_us = args[3].to_i64();
+
+        let filename = main_memory.str(filename_ptr, filename_len)?.to_string();
+        let host_path = data_guard
+            .sandbox
+            .resolve(&filename, sandbox::Access::Read)?;
+
+        let decode_options = DecodeOptions {
+            start_us: (start_us > 0).then_some(start_us as u64),
+            end_us: (end_us >= 0).then_some(end_us as u64),
+            ..DecodeOptions::default()
+        };
+        if decode_options.end_us.is_some() && end_us <= start_us {
+            return Err(PluginError::new(
+                VideoProcessingPluginError::InvalidArgument,
+                format!("Range end {end_us}us is not after range start {start_us}us"),
+            ));
+        }
 
-    let filename_ptr_main_memory =
-        main_memory.try_get_ptr::<u8>(filename_ptr as u32, filename_len as u32)?;
+        let dumped = decode_video::VideoDecoder::open(&host_path, &decode_options)
+            .and_then(decode_video::dump_frames);
+        open_loaded_session(data_guard, &mut main_memory, &filename, dumped, &args[4..8])
+    })
+}
 
-    let filename: String = unsafe {
-        String::from_raw_parts(
-            filename_ptr_main_memory,
-            filename_len as usize,
-            filaname_capacity as usize,
+// Parses JSON the guest sent, `what` names it in the error message
+fn parse_options<T: DeserializeOwned>(
+    main_memory: &GuestMemory,
+    json_ptr: i32,
+    json_len: usize,
+    what: &str,
+) -> Result<T, PluginError> {
+    let json = main_memory.slice(json_ptr, json_len)?;
+    serde_json::from_slice(json).map_err(|err| {
+        PluginError::new(
+            VideoProcessingPluginError::InvalidArgument,
+            format!("Could not parse {what} {err}"),
         )
-    };
+    })
+}
 
-    debug!("Call FFMPEG dump Frames");
+// Parses the JSON encoded `DecodeOptions` a guest sends when opening a video
+fn parse_decode_options(
+    main_memory: &GuestMemory,
+    options_ptr: i32,
+    options_len: usize,
+) -> Result<DecodeOptions, PluginError> {
+    let decode_options: DecodeOptions =
+        parse_options(main_memory, options_ptr, options_len, "Decode Options")?;
+    decode_options.validate()?;
+    Ok(decode_options)
+}
 
-    let res = match decode_video::dump_frames(&filename) {
-        Ok((frames, video_info)) => {
+// Opens a session for the frames of `dump_frames` and writes the width, height, frame count and
+// session handle to the guest pointers in `out_ptrs`
+fn open_loaded_session(
+    data_guard: &mut VideoSessions,
+    main_memory: &mut GuestMemory,
+    filename: &str,
+    dumped: Result<(Frames, VideoInfo, Vec<Packet>), decode_video::VideoDecoderError>,
+    out_ptrs: &[WasmValue],
+) -> Result<Vec<WasmValue>, PluginError> {
+    let width_ptr = out_ptrs[0].to_i32();
+    let height_ptr = out_ptrs[1].to_i32();
+    let frames_ptr = out_ptrs[2].to_i32();
+    let session_ptr = out_ptrs[3].to_i32();
+
+    match dumped {
+        Ok((frames, video_info, passthrough_packets)) if frames.len() > 0 => {
             debug!("Input Frame Count {}", frames.len());
-            if frames.len() > 0 {
-                unsafe {
-                    *width_ptr_main_memory = frames[0].input_frame.width();
-                    *height_ptr_main_memory = frames[0].input_frame.height();
-                }
-            } else {
-                error!("Video file {} contained No Frames", filename);
-                return Err(HostFuncError::User(1));
-            }
-
-            let mut vid_gaurd = data_guard;
-            vid_gaurd.video_info = Some(video_info);
-            vid_gaurd.frames = frames;
-            unsafe {
-                *frames_ptr_main_memory = vid_gaurd.frames.len() as u32;
-            }
+            main_memory.write_u32(width_ptr, video_info.width())?;
+            main_memory.write_u32(height_ptr, video_info.height())?;
+
+            main_memory.write_u32(frames_ptr, frames.len() as u32)?;
+            data_guard.open_session(
+                main_memory,
+                session_ptr,
+                FramesMap {
+                    frames,
+                    video_info: Some(video_info),
+                    video_decoder: None,
+                    video_encoder: None,
+                    passthrough_packets,
+                    encoder_options: encode_video::EncoderOptions::default(),
+                    detections: BTreeMap::new(),
+                    annotation_options: annotations::AnnotationOptions::default(),
+                    output_file: None,
+                    output_bytes: VecDeque::new(),
+                    tracker: None,
+                    last_tracked_frame: None,
+                },
+            )?;
             Ok(vec![WasmValue::from_i32(0)])
         }
-        Err(err) => {
-            error!("Error Loading Frames {:?}", err);
-            Err(HostFuncError::User(1))
-        }
-    };
-
-    // Need to forget x otherwise we get a double free
-    std::mem::forget(filename);
-    res
+        Ok(_) => Err(PluginError::new(
+            VideoProcessingPluginError::EmptyVideo,
+            format!("Video file {} contained No Frames", filename),
+        )),
+        Err(err) => Err(PluginError::from(err)),
+    }
 }
 
 #[host_function]
 fn get_frame(
     caller: Caller,
     args: Vec<WasmValue>,
-    data: &mut Arc<Mutex<FramesMap>>,
+    data: &mut Arc<Mutex<VideoSessions>>,
 ) -> Result<Vec<WasmValue>, HostFuncError> {
     debug!("get_frame");
 
-    let data_guard = match data.lock() {
-        Ok(x) => x,
-        Err(err) => {
-            error!("Mutex Carrying plugin Data Poisoned {err}");
-            return Err(HostFuncError::Runtime(1));
+    with_sessions(data, |data_guard| {
+        let mut main_memory = GuestMemory::from_caller(&caller)?;
+
+        let session = args[0].to_i32() as u32;
+        let idx: i32 = args[1].to_i32();
+        let image_buf_ptr = args[2].to_i32();
+        let image_buf_len = args[3].to_i32() as usize;
+
+        debug!("LIB image_buf_ptr {:?}", image_buf_ptr);
+        debug!("LIB image_buf_len {:?}", image_buf_len);
+
+        let video_session = data_guard.session(session)?;
+
+        let frame_map = video_session.frame(idx as usize)?;
+        let input_frame = frame_map.input_frame.as_ref().ok_or_else(|| {
+            PluginError::new(
+                VideoProcessingPluginError::InvalidState,
+                format!(
+                    "Frame {idx} was streamed to the guest and is no longer held by the plugin"
+                ),
+            )
+        })?;
+
+        let frame_size = frame_buffer::packed_frame_size(input_frame.width(), input_frame.height());
+        debug!("LIB data {:?}", frame_size);
+        if image_buf_len < frame_size {
+            return Err(PluginError::new(
+                VideoProcessingPluginError::BufferTooSmall,
+                format!(
+                    "Image buffer of {image_buf_len} bytes is too small for frame of {frame_size} bytes"
+                ),
+            ));
         }
-    };
 
-    let mut main_memory = caller.memory(0).ok_or(HostFuncError::User(1))?;
+        let image_buf = main_memory.slice_mut(image_buf_ptr, frame_size)?;
+        frame_buffer::copy_frame_to_packed(input_frame, image_buf);
 
-    let idx: i32 = args[0].to_i32();
-    let image_buf_ptr = args[1].to_i32();
-    let image_buf_len = args[2].to_i32() as usize;
-    let image_buf_capacity = args[3].to_i32() as usize;
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
 
-    debug!("LIB image_buf_ptr {:?}", image_buf_ptr);
-    debug!("LIB image_buf_len {:?}", image_buf_len);
-    debug!("LIB image_buf_capacity {:?}", image_buf_capacity);
+/// Writes a frame as model input into the guest buffer: a planar NCHW tensor with a batch of one,
+/// scaled to 0..1 and normalised as set by the `TensorOptions` JSON, e.g.
+/// `{"data_type": "f16", "width": 640, "height": 640, "resize_mode": "letterbox"}`.
+/// The required length is written to `tensor_len_ptr` and the `FrameTransform` from the frame to
+/// the tensor to `transform_ptr`, compose it with `get_frame_transform` to get to the source.
+#[host_function]
+fn get_frame_tensor(
+    caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<Mutex<VideoSessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("get_frame_tensor");
+
+    with_sessions(data, |data_guard| {
+        let mut main_memory = GuestMemory::from_caller(&caller)?;
+
+        let session = args[0].to_i32() as u32;
+        let idx = args[1].to_i32();
+        let options_ptr = args[2].to_i32();
+        let options_len = args[3].to_i32() as usize;
+        let tensor_buf_ptr = args[4].to_i32();
+        let tensor_buf_len = args[5].to_i32() as usize;
+        let tensor_len_ptr = args[6].to_i32();
+        let transform_ptr = args[7].to_i32();
+
+        let tensor_options: tensor::TensorOptions =
+            parse_options(&main_memory, options_ptr, options_len, "Tensor Options")?;
+        tensor_options.validate()?;
+
+        let video_session = data_guard.session(session)?;
+        let frame_map = video_session.frame(idx as usize)?;
+        let input_frame = frame_map.input_frame.as_ref().ok_or_else(|| {
+            PluginError::new(
+                VideoProcessingPluginError::InvalidState,
+                format!(
+                    "Frame {idx} was streamed to the guest and is no longer held by the plugin"
+                ),
+            )
+        })?;
+
+        let frame_transform =
+            tensor_options.frame_transform(input_frame.width(), input_frame.height());
+        let tensor_size = tensor_options.tensor_size(&frame_transform);
+        let tensor_len = u32::try_from(tensor_size).map_err(|_| {
+            PluginError::new(
+                VideoProcessingPluginError::InvalidArgument,
+                format!("Tensor of {tensor_size} bytes does not fit into guest memory"),
+            )
+        })?;
+        main_memory.write_u32(tensor_len_ptr, tensor_len)?;
+        main_memory.write_value(transform_ptr, frame_transform)?;
+        if tensor_buf_len < tensor_size {
+            return Err(PluginError::new(
+                VideoProcessingPluginError::BufferTooSmall,
+                format!(
+                    "Tensor buffer of {tensor_buf_len} bytes is too small for {tensor_size} bytes"
+                ),
+            ));
+        }
 
-    let image_ptr_wasm_memory = main_memory
-        .data_pointer_mut(image_buf_ptr as u32, image_buf_len as u32)
-        .expect("Could not get Data pointer");
+        let tensor_buf = main_memory.slice_mut(tensor_buf_ptr, tensor_size)?;
+        tensor::write_tensor(input_frame, &tensor_options, &frame_transform, tensor_buf)?;
 
-    let mut vec =
-        unsafe { Vec::from_raw_parts(image_ptr_wasm_memory, image_buf_len, image_buf_capacity) };
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
 
-    if let Some(frame) = data_guard.frames.get(idx as usize) {
-        debug!("LIB data {:?}", frame.input_frame.data(0).len());
-        vec.copy_from_slice(frame.input_frame.data(0));
-    } else {
-        error!("Return error if frame does not exist");
-    };
+#[host_function]
+fn open_video_stream(
+    caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<Mutex<VideoSessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("open_video_stream");
+
+    with_sessions(data, |data_guard| {
+        let mut main_memory = GuestMemory::from_caller(&caller)?;
+
+        let filename_ptr = args[0].to_i32();
+        let filename_len = args[1].to_i32() as usize;
+
+        let filename = main_memory.str(filename_ptr, filename_len)?.to_string();
+        let host_path = data_guard
+            .sandbox
+            .resolve(&filename, sandbox::Access::Read)?;
+
+        let video_decoder =
+            decode_video::VideoDecoder::open(&host_path, &DecodeOptions::default())?;
+        open_stream_session(data_guard, &mut main_memory, video_decoder, &args[2..5])
+    })
+}
+
+/// Like `open_video_stream` with `DecodeOptions` sent by the guest as JSON
+#[host_function]
+fn open_video_stream_with_options(
+    caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<Mutex<VideoSessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("open_video_stream_with_options");
+
+    with_sessions(data, |data_guard| {
+        let mut main_memory = GuestMemory::from_caller(&caller)?;
+
+        let filename_ptr = args[0].to_i32();
+        let filename_len = args[1].to_i32() as usize;
+        let options_ptr = args[2].to_i32();
+        let options_len = args[3].to_i32() as usize;
+
+        let filename = main_memory.str(filename_ptr, filename_len)?.to_string();
+        let host_path = data_guard
+            .sandbox
+            .resolve(&filename, sandbox::Access::Read)?;
+        let decode_options = parse_decode_options(&main_memory, options_ptr, options_len)?;
+
+        let video_decoder = decode_video::VideoDecoder::open(&host_path, &decode_options)?;
+        open_stream_session(data_guard, &mut main_memory, video_decoder, &args[4..7])
+    })
+}
 
-    // Need to forget x otherwise we get a double free
-    std::mem::forget(vec);
+// Opens a streaming session for `video_decoder` and writes the width, height and session handle
+// to the guest pointers in `out_ptrs`
+fn open_stream_session(
+    data_guard: &mut VideoSessions,
+    main_memory: &mut GuestMemory,
+    video_decoder: decode_video::VideoDecoder,
+    out_ptrs: &[WasmValue],
+) -> Result<Vec<WasmValue>, PluginError> {
+    let width_ptr = out_ptrs[0].to_i32();
+    let height_ptr = out_ptrs[1].to_i32();
+    let session_ptr = out_ptrs[2].to_i32();
+
+    let video_info = video_decoder.video_info().clone();
+    main_memory.write_u32(width_ptr, video_info.width())?;
+    main_memory.write_u32(height_ptr, video_info.height())?;
+
+    data_guard.open_session(
+        main_memory,
+        session_ptr,
+        FramesMap {
+            frames: Vec::new(),
+            video_info: Some(video_info),
+            video_decoder: Some(video_decoder),
+            video_encoder: None,
+            passthrough_packets: Vec::new(),
+            encoder_options: encode_video::EncoderOptions::default(),
+            detections: BTreeMap::new(),
+            annotation_options: annotations::AnnotationOptions::default(),
+            output_file: None,
+            output_bytes: VecDeque::new(),
+            tracker: None,
+            last_tracked_frame: None,
+        },
+    )?;
     Ok(vec![WasmValue::from_i32(0)])
 }
 
+/// Creates an empty buffer on the host that the guest fills with the bytes of a video file through
+/// `append_input_bytes`, e.g. as they arrive over a socket, and writes its handle to `buffer_ptr`.
+/// Videos are then opened from it with `load_video_from_input_buffer` or
+/// `open_video_stream_from_input_buffer` without going through the file system.
+#[host_function]
+fn create_input_buffer(
+    caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<Mutex<VideoSessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("create_input_buffer");
+
+    with_sessions(data, |data_guard| {
+        let mut main_memory = GuestMemory::from_caller(&caller)?;
+
+        let buffer_ptr = args[0].to_i32();
+
+        let buffer = data_guard.next_input_buffer;
+        main_memory.write_u32(buffer_ptr, buffer)?;
+        data_guard.next_input_buffer += 1;
+        data_guard.input_buffers.insert(buffer, Vec::new());
+
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
+
+/// Appends a chunk of guest memory to an input buffer
+#[host_function]
+fn append_input_bytes(
+    caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<Mutex<VideoSessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("append_input_bytes");
+
+    with_sessions(data, |data_guard| {
+        let main_memory = GuestMemory::from_caller(&caller)?;
+
+        let buffer = args[0].to_i32() as u32;
+        let bytes_ptr = args[1].to_i32();
+        let bytes_len = args[2].to_i32() as usize;
+
+        let bytes = main_memory.slice(bytes_ptr, bytes_len)?;
+        data_guard
+            .input_buffer_mut(buffer)?
+            .extend_from_slice(bytes);
+
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
+
+/// Frees an input buffer that is not going to be opened
+#[host_function]
+fn discard_input_buffer(
+    _caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<Mutex<VideoSessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("discard_input_buffer");
+
+    with_sessions(data, |data_guard| {
+        let buffer = args[0].to_i32() as u32;
+        data_guard.take_input_buffer(buffer)?;
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
+
+/// Like `load_video_with_options_to_host_memory` for the video in an input buffer.
+/// The buffer is used up, also when the video cannot be opened.
+#[host_function]
+fn load_video_from_input_buffer(
+    caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<Mutex<VideoSessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("load_video_from_input_buffer");
+
+    with_sessions(data, |data_guard| {
+        let mut main_memory = GuestMemory::from_caller(&caller)?;
+
+        let buffer = args[0].to_i32() as u32;
+        let options_ptr = args[1].to_i32();
+        let options_len = args[2].to_i32() as usize;
+
+        let bytes = data_guard.take_input_buffer(buffer)?;
+        let decode_options = parse_decode_options(&main_memory, options_ptr, options_len)?;
+
+        let dumped = decode_video::VideoDecoder::open_memory(bytes, &decode_options)
+            .and_then(decode_video::dump_frames);
+        let name = format!("in input buffer {buffer}");
+        open_loaded_session(data_guard, &mut main_memory, &name, dumped, &args[3..7])
+    })
+}
+
+/// Like `open_video_stream_with_options` for the video in an input buffer.
+/// The buffer is used up, also when the video cannot be opened.
+#[host_function]
+fn open_video_stream_from_input_buffer(
+    caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<Mutex<VideoSessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("open_video_stream_from_input_buffer");
+
+    with_sessions(data, |data_guard| {
+        let mut main_memory = GuestMemory::from_caller(&caller)?;
+
+        let buffer = args[0].to_i32() as u32;
+        let options_ptr = args[1].to_i32();
+        let options_len = args[2].to_i32() as usize;
+
+        let bytes = data_guard.take_input_buffer(buffer)?;
+        let decode_options = parse_decode_options(&main_memory, options_ptr, options_len)?;
+
+        let video_decoder = decode_video::VideoDecoder::open_memory(bytes, &decode_options)?;
+        open_stream_session(data_guard, &mut main_memory, video_decoder, &args[3..6])
+    })
+}
+
+/// Decodes the next frame of a video opened with `open_video_stream` into the guest buffer.
+/// Returns `EndOfStream` once every frame of the stream has been handed out.
+#[host_function]
+fn next_frame(
+    caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<Mutex<VideoSessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("next_frame");
+
+    with_sessions(data, |data_guard| {
+        let mut main_memory = GuestMemory::from_caller(&caller)?;
+
+        let session = args[0].to_i32() as u32;
+        let image_buf_ptr = args[1].to_i32();
+        let image_buf_len = args[2].to_i32() as usize;
+        let frame_index_ptr = args[3].to_i32();
+
+        let video_session = data_guard.session_mut(session)?;
+
+        let video_decoder = video_session.video_decoder.as_mut().ok_or_else(|| {
+            PluginError::new(
+                VideoProcessingPluginError::InvalidState,
+                "No Video Stream opened when calling next_frame",
+            )
+        })?;
+
+        let next_frame = video_decoder.next_frame()?;
+
+        // Audio and other streams read alongside the frame go straight to the encoder when there is one
+        video_session
+            .passthrough_packets
+            .extend(video_decoder.take_passthrough_packets());
+        if let Some(video_encoder) = video_session.video_encoder.as_mut() {
+            for packet in video_session.passthrough_packets.drain(..) {
+                video_encoder.write_passthrough_packet(packet)?;
+            }
+        }
+
+        let mut frame_map = next_frame.ok_or_else(|| {
+            PluginError::new(
+                VideoProcessingPluginError::EndOfStream,
+                "End of Video Stream",
+            )
+        })?;
+
+        // The guest now owns the pixels, only keep the frame metadata around for encoding
+        if let Some(input_frame) = frame_map.input_frame.take() {
+            let frame_size =
+                frame_buffer::packed_frame_size(input_frame.width(), input_frame.height());
+            if image_buf_len < frame_size {
+                return Err(PluginError::new(
+                    VideoProcessingPluginError::BufferTooSmall,
+                    format!(
+                        "Image buffer of {image_buf_len} bytes is too small for frame of {frame_size} bytes"
+                    ),
+                ));
+            }
+
+            let image_buf = main_memory.slice_mut(image_buf_ptr, frame_size)?;
+            frame_buffer::copy_frame_to_packed(&input_frame, image_buf);
+        }
+
+        let frame_index = video_session.frames.len();
+        video_session.frames.push(frame_map);
+        main_memory.write_u32(frame_index_ptr, frame_index as u32)?;
+
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
+
+/// Seeks a video opened with `open_video_stream` so that `next_frame` returns the frame shown
+/// `time_us` microseconds after the start of the video, not just the key frame before it.
+#[host_function]
+fn seek_to_timestamp(
+    _caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<Mutex<VideoSessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("seek_to_timestamp");
+
+    with_sessions(data, |data_guard| {
+        let session = args[0].to_i32() as u32;
+        let time_us = args[1].to_i64();
+
+        if time_us < 0 {
+            return Err(PluginError::new(
+                VideoProcessingPluginError::InvalidArgument,
+                format!("Cannot seek to negative time {time_us}us"),
+            ));
+        }
+
+        let video_decoder = data_guard
+            .session_mut(session)?
+            .video_decoder_mut("seek_to_timestamp")?;
+        let timestamp = video_decoder.stream_timestamp(Duration::from_micros(time_us as u64));
+        video_decoder.seek(timestamp)?;
+
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
+
+/// Seeks a video opened with `open_video_stream` so that `next_frame` returns frame `frame_number`,
+/// counted from the start of the video at its nominal frame rate.
+#[host_function]
+fn seek_to_frame(
+    _caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<Mutex<VideoSessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("seek_to_frame");
+
+    with_sessions(data, |data_guard| {
+        let session = args[0].to_i32() as u32;
+        let frame_number = args[1].to_i64();
+
+        if frame_number < 0 {
+            return Err(PluginError::new(
+                VideoProcessingPluginError::InvalidArgument,
+                format!("Cannot seek to negative frame {frame_number}"),
+            ));
+        }
+
+        let video_decoder = data_guard
+            .session_mut(session)?
+            .video_decoder_mut("seek_to_frame")?;
+        let timestamp = video_decoder
+            .frame_timestamp(frame_number as u64)
+            .ok_or_else(|| {
+                PluginError::new(
+                    VideoProcessingPluginError::InvalidState,
+                    "Video has no frame rate to find the frame by, use seek_to_timestamp instead",
+                )
+            })?;
+        video_decoder.seek(timestamp)?;
+
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
+
 #[host_function]
 fn write_frame(
     caller: Caller,
     args: Vec<WasmValue>,
-    data: &mut Arc<Mutex<FramesMap>>,
+    data: &mut Arc<Mutex<VideoSessions>>,
 ) -> Result<Vec<WasmValue>, HostFuncError> {
     debug!("write_frame");
 
-    let mut data_guard = match data.lock() {
-        Ok(x) => x,
-        Err(err) => {
-            error!("Mutex Carrying plugin Data Poisoned {err}");
-            return Err(HostFuncError::Runtime(1));
+    with_sessions(data, |data_guard| {
+        let main_memory = GuestMemory::from_caller(&caller)?;
+
+        let session = args[0].to_i32() as u32;
+        let idx = args[1].to_i32() as usize;
+        let image_buf_ptr = args[2].to_i32();
+        let image_buf_len = args[3].to_i32() as usize;
+
+        let video_session = data_guard.session_mut(session)?;
+
+        let video_info = video_session.video_info()?;
+        let frame_size = frame_buffer::packed_frame_size(video_info.width(), video_info.height());
+
+        debug!("BUFFER SIZE {}", frame_size);
+
+        if image_buf_len < frame_size {
+            return Err(PluginError::new(
+                VideoProcessingPluginError::BufferTooSmall,
+                format!("Image buffer of {image_buf_len} bytes is too small for frame of {frame_size} bytes"),
+            ));
         }
-    };
 
-    let mut main_memory = caller.memory(0).ok_or(HostFuncError::User(1))?;
+        let mut video_frame = frame::Video::new(
+            ffmpeg::format::Pixel::RGB24,
+            video_info.width.0,
+            video_info.height.0,
+        );
+
+        let image_buf = main_memory.slice(image_buf_ptr, frame_size)?;
+        frame_buffer::copy_packed_to_frame(image_buf, &mut video_frame);
+
+        debug!("Writing Frame {idx}");
+
+        // Make sure the frame exists before handing it to the encoder
+        video_session.frame(idx)?;
+
+        match video_session.video_encoder.as_mut() {
+            // Streaming encode, the frame goes straight to the encoder instead of being stored
+            Some(video_encoder) => {
+                if idx != video_encoder.frames_encoded() {
+                    return Err(PluginError::new(
+                        VideoProcessingPluginError::InvalidState,
+                        format!(
+                            "Frame {idx} written out of order, expected frame {}",
+                            video_encoder.frames_encoded()
+                        ),
+                    ));
+                }
+                let timestamp = video_session.frames[idx].timestamp;
+                video_encoder.encode_frame(&mut video_frame, timestamp)?;
+            }
+            None => video_session.frames[idx].output_frame = Some(video_frame),
+        };
 
-    let video_info = data_guard
-        .video_info
-        .as_ref()
-        .expect("Could not get Video Info data ");
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
 
-    let idx = args[0].to_i32() as usize;
-    let image_buf_ptr = args[1].to_i32();
-    let image_buf_len = args[2].to_i32() as usize;
+/// Draws box outlines and labels of the detections in the `DrawOptions` JSON into a frame, e.g.
+/// `{"detections": [{"x": 10, "y": 20, "width": 50, "height": 80, "class_id": 0, "score": 0.9}]}`.
+/// Draws into the output frame, or into the input frame if the guest has not written one yet.
+#[host_function]
+fn draw_detections(
+    caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<Mutex<VideoSessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("draw_detections");
 
-    let image_ptr_wasm_memory = main_memory
-        .data_pointer_mut(image_buf_ptr as u32, image_buf_len as u32)
-        .expect("Could not get Data pointer");
+    with_sessions(data, |data_guard| {
+        let main_memory = GuestMemory::from_caller(&caller)?;
 
-    let vec = unsafe {
-        Vec::from_raw_parts(
-            image_ptr_wasm_memory,
-            image_buf_len,
-            (video_info.width() * video_info.height() * 3) as usize,
-        )
-    };
+        let session = args[0].to_i32() as u32;
+        let idx = args[1].to_i32();
+        let json_ptr = args[2].to_i32();
+        let json_len = args[3].to_i32() as usize;
 
-    debug!(
-        "BUFFER SIZE {}",
-        video_info.width() * video_info.height() * 3
-    );
+        let draw_options: draw::DrawOptions =
+            parse_options(&main_memory, json_ptr, json_len, "Draw Options")?;
+        draw_options.validate()?;
 
-    let mut video_frame = frame::Video::new(
-        ffmpeg::format::Pixel::RGB24,
-        video_info.width.0,
-        video_info.height.0,
-    );
+        let frame = data_guard
+            .session_mut(session)?
+            .frame_mut(idx as usize)?
+            .drawable_frame_mut(idx as usize)?;
+        draw::draw_detections(frame, &draw_options);
 
-    {
-        let data = video_frame.data_mut(0);
-        data.copy_from_slice(&vec);
-    }
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
+
+/// Blurs, pixelates or fills regions of a frame, e.g. to anonymise faces and licence plates.
+/// Takes `RedactOptions` as JSON, e.g. `{"regions": [{"x": 10, "y": 20, "width": 50,
+/// "height": 80}, {"points": [[0, 0], [40, 0], [20, 30]]}], "method": "pixelate", "padding": 4}`.
+/// Works on the same frame as `draw_detections`.
+#[host_function]
+fn redact_regions(
+    caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<Mutex<VideoSessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("redact_regions");
 
-    debug!("Writing Frame {idx}");
+    with_sessions(data, |data_guard| {
+        let main_memory = GuestMemory::from_caller(&caller)?;
 
-    if let Some(frame_map) = data_guard.frames.get_mut(idx) {
-        frame_map.output_frame = Some(video_frame);
-    } else {
-        // Need to forget x otherwise we get a double free
-        std::mem::forget(vec);
-        return Ok(vec![WasmValue::from_i32(1)]);
-    };
+        let session = args[0].to_i32() as u32;
+        let idx = args[1].to_i32() as usize;
+        let json_ptr = args[2].to_i32();
+        let json_len = args[3].to_i32() as usize;
 
-    // Need to forget x otherwise we get a double free
-    std::mem::forget(vec);
-    Ok(vec![WasmValue::from_i32(0)])
+        let redact_options: redact::RedactOptions =
+            parse_options(&main_memory, json_ptr, json_len, "Redact Options")?;
+
+        let frame = data_guard
+            .session_mut(session)?
+            .frame_mut(idx)?
+            .drawable_frame_mut(idx)?;
+        redact_options.validate(frame.width(), frame.height())?;
+        redact::redact_regions(frame, &redact_options);
+
+        Ok(vec![WasmValue::from_i32(0)])
+    })
 }
 
+/// Records the detections of a frame for the annotation export, as a JSON array of detections
+/// like `draw_detections` takes. Replaces what was recorded for the frame before.
 #[host_function]
-fn assemble_output_frames_to_video(
+fn set_frame_detections(
     caller: Caller,
     args: Vec<WasmValue>,
-    data: &mut Arc<Mutex<FramesMap>>,
+    data: &mut Arc<Mutex<VideoSessions>>,
 ) -> Result<Vec<WasmValue>, HostFuncError> {
-    debug!("assemble_video");
-    let mut data_guard = match data.lock() {
-        Ok(x) => x,
-        Err(err) => {
-            error!("Mutex Carrying plugin Data Poisoned {err}");
-            return Err(HostFuncError::Runtime(1));
+    debug!("set_frame_detections");
+
+    with_sessions(data, |data_guard| {
+        let main_memory = GuestMemory::from_caller(&caller)?;
+
+        let session = args[0].to_i32() as u32;
+        let idx = args[1].to_i32() as usize;
+        let json_ptr = args[2].to_i32();
+        let json_len = args[3].to_i32() as usize;
+
+        let detections: Vec<detection::Detection> =
+            parse_options(&main_memory, json_ptr, json_len, "Detections")?;
+        for detection in &detections {
+            detection.validate()?;
         }
-    };
 
-    let mut main_memory = caller.memory(0).ok_or(HostFuncError::Runtime(1))?;
+        let video_session = data_guard.session_mut(session)?;
+        video_session.frame(idx)?;
+        video_session.detections.insert(idx, detections);
+
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
+
+/// Starts a new tracker for the session with `TrackerOptions` as JSON,
+/// e.g. `{"max_age": 30, "min_hits": 3, "iou_threshold": 0.3}`. Track ids start over at 1.
+#[host_function]
+fn init_tracker(
+    caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<Mutex<VideoSessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("init_tracker");
+
+    with_sessions(data, |data_guard| {
+        let main_memory = GuestMemory::from_caller(&caller)?;
+
+        let session = args[0].to_i32() as u32;
+        let json_ptr = args[1].to_i32();
+        let json_len = args[2].to_i32() as usize;
+
+        let tracker_options: tracking::TrackerOptions =
+            parse_options(&main_memory, json_ptr, json_len, "Tracker Options")?;
+        tracker_options
+            .validate()
+            .map_err(|err| PluginError::new(VideoProcessingPluginError::InvalidArgument, err))?;
+
+        let video_session = data_guard.session_mut(session)?;
+        video_session.tracker = Some(tracking::Tracker::new(tracker_options));
+        video_session.last_tracked_frame = None;
+
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
 
-    let filename_ptr = args[0].to_i32();
-    let filename_len = args[1].to_i32();
-    let filaname_capacity = args[2].to_i32();
+/// Feeds the detections of the next frame, a JSON array like `set_frame_detections` takes,
+/// to the tracker of the session and writes one track id per detection as i32 to `ids_ptr`.
+/// Detections of tracks that are not confirmed yet get -1. Frames have to be tracked in order
+/// without gaps, frames without detections with an empty array, or this fails with InvalidState.
+/// The detections are recorded with their track ids for the annotation export.
+#[host_function]
+fn track_detections(
+    caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<Mutex<VideoSessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("track_detections");
+
+    with_sessions(data, |data_guard| {
+        let mut main_memory = GuestMemory::from_caller(&caller)?;
+
+        let session = args[0].to_i32() as u32;
+        let idx = args[1].to_i32() as usize;
+        let json_ptr = args[2].to_i32();
+        let json_len = args[3].to_i32() as usize;
+        let ids_ptr = args[4].to_i32();
+        let ids_len = args[5].to_i32() as usize;
+
+        let mut detections: Vec<detection::Detection> =
+            parse_options(&main_memory, json_ptr, json_len, "Detections")?;
+        for detection in &detections {
+            detection.validate()?;
+        }
 
-    // TODO proper Handling of errors
-    let filename_ptr_main_memory = main_memory.try_get_ptr::<u8>(filename_ptr as u32, 1)?;
+        if ids_len < detections.len() {
+            return Err(PluginError::new(
+                VideoProcessingPluginError::BufferTooSmall,
+                format!(
+                    "Track id buffer of {ids_len} ids is too small for {} detections",
+                    detections.len()
+                ),
+            ));
+        }
 
-    let video_struct = &mut (*data_guard);
-    let frames = &mut video_struct.frames;
-    let video_info = match &video_struct.video_info {
-        Some(video_info) => video_info,
-        None => {
-            error!("No Video Information when attempting to Assemble output assemble_output_frames_to_video");
-            return Err(HostFuncError::User(1));
+        let video_session = data_guard.session_mut(session)?;
+        video_session.frame(idx)?;
+        if let Some(last_tracked_frame) = video_session.last_tracked_frame {
+            if idx != last_tracked_frame + 1 {
+                return Err(PluginError::new(
+                    VideoProcessingPluginError::InvalidState,
+                    format!("Frame {idx} does not follow frame {last_tracked_frame} tracked last"),
+                ));
+            }
+        }
+        video_session.last_tracked_frame = Some(idx);
+        video_session
+            .tracker
+            .get_or_insert_with(|| tracking::Tracker::new(tracking::TrackerOptions::default()))
+            .update(&mut detections);
+
+        let ids_buf =
+            main_memory.slice_mut(ids_ptr, detections.len() * std::mem::size_of::<i32>())?;
+        for (id_bytes, detection) in ids_buf.chunks_exact_mut(4).zip(&detections) {
+            let track_id = detection.track_id.map_or(-1, |track_id| track_id as i32);
+            id_bytes.copy_from_slice(&track_id.to_le_bytes());
         }
-    };
 
-    let output_file: String = unsafe {
-        String::from_raw_parts(
-            filename_ptr_main_memory,
-            filename_len as usize,
-            filaname_capacity as usize,
-        )
-    };
+        video_session.detections.insert(idx, detections);
+
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
+
+/// Reads `AnnotationOptions` as JSON from the guest, e.g. `{"formats": ["json_lines", "mot"]}`.
+/// The recorded detections are written in these formats next to the output video once it has
+/// been written by `assemble_output_frames_to_video` or `finish_video_encoder`.
+#[host_function]
+fn set_annotation_options(
+    caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<Mutex<VideoSessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("set_annotation_options");
+
+    with_sessions(data, |data_guard| {
+        let main_memory = GuestMemory::from_caller(&caller)?;
+
+        let session = args[0].to_i32() as u32;
+        let json_ptr = args[1].to_i32();
+        let json_len = args[2].to_i32() as usize;
+
+        let annotation_options: annotations::AnnotationOptions =
+            parse_options(&main_memory, json_ptr, json_len, "Annotation Options")?;
+
+        data_guard.session_mut(session)?.annotation_options = annotation_options;
+
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
+
+#[host_function]
+fn assemble_output_frames_to_video(
+    caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<Mutex<VideoSessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("assemble_video");
+
+    with_sessions(data, |data_guard| {
+        let main_memory = GuestMemory::from_caller(&caller)?;
+
+        let session = args[0].to_i32() as u32;
+        let filename_ptr = args[1].to_i32();
+        let filename_len = args[2].to_i32() as usize;
+
+        let filename = main_memory.str(filename_ptr, filename_len)?;
+        let output_file = data_guard
+            .sandbox
+            .resolve(filename, sandbox::Access::Write)?;
+
+        let video_struct = data_guard.session_mut(session)?;
+        assemble_output_frames(video_struct, encode_video::OutputTarget::File(&output_file))?;
+        video_struct.write_annotations(&output_file)?;
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
+
+/// Like `assemble_output_frames_to_video`, but the video is kept in memory to be read with
+/// `read_output_bytes`. MP4s get their index in front of the media data, like with faststart.
+/// No annotation files are written as there is no video file to put them next to.
+#[host_function]
+fn assemble_output_frames_to_memory(
+    _caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<Mutex<VideoSessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("assemble_output_frames_to_memory");
+
+    with_sessions(data, |data_guard| {
+        let session = args[0].to_i32() as u32;
+
+        let video_struct = data_guard.session_mut(session)?;
+        let output_bytes =
+            assemble_output_frames(video_struct, encode_video::OutputTarget::Memory)?;
+        video_struct
+            .output_bytes
+            .extend(output_bytes.unwrap_or_default());
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
+
+// Encodes every output frame of a loaded video, returns the bytes of a memory output
+fn assemble_output_frames(
+    video_struct: &mut FramesMap,
+    target: encode_video::OutputTarget,
+) -> Result<Option<Vec<u8>>, PluginError> {
+    if video_struct.video_encoder.is_some() {
+        return Err(PluginError::new(
+            VideoProcessingPluginError::InvalidState,
+            "Frames are being streamed to an encoder, call finish_video_encoder instead",
+        ));
+    }
+
+    let frames = &video_struct.frames;
 
     // Check Frames have all been Written
     // Save Indexes of frames that have not been written
     let (mut frames, missing_frames) = frames.into_iter().enumerate().fold(
         (Vec::new(), Vec::new()),
         |(mut iter_frames, mut iter_missing), (idx, frame_map)| {
-            match frame_map.output_frame.as_mut() {
+            match frame_map.output_frame.as_ref() {
                 Some(fr) => {
                     // TODO REMOVE CLONE
                     iter_frames.push((fr.clone(), frame_map.frame_type, frame_map.timestamp))
@@ -404,41 +1350,630 @@ fn assemble_output_frames_to_video(
     );
 
     if missing_frames.len() > 0 {
-        error!("Error Missing Frames {:?} ", missing_frames);
-        return Err(HostFuncError::User(1));
+        return Err(PluginError::new(
+            VideoProcessingPluginError::MissingOutputFrames,
+            format!("Error Missing Frames {:?} ", missing_frames),
+        ));
     }
 
-    let mut video_encoder = encode_video::VideoEncoder::new(&video_info, &output_file)
-        .map_err(|_| HostFuncError::User(1))?;
+    let passthrough_packets = std::mem::take(&mut video_struct.passthrough_packets);
+    let video_info = video_struct.video_info()?;
+    let res = encode_video::VideoEncoder::new(video_info, target, &video_struct.encoder_options)
+        .and_then(|mut video_encoder| {
+            video_encoder.receive_and_process_decoded_frames(&mut frames, passthrough_packets)?;
+            video_encoder.take_output_bytes()
+        });
 
-    if let Err(err) = video_encoder.receive_and_process_decoded_frames(&mut frames) {
-        error!("Encode stream Error {:?}", err);
-    };
+    Ok(res?)
+}
 
-    // Need to forget x otherwise we get a double free
-    std::mem::forget(output_file);
-    Ok(vec![WasmValue::from_i32(0)])
+#[host_function]
+fn open_video_encoder(
+    caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<Mutex<VideoSessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("open_video_encoder");
+
+    with_sessions(data, |data_guard| {
+        let main_memory = GuestMemory::from_caller(&caller)?;
+
+        let session = args[0].to_i32() as u32;
+        let filename_ptr = args[1].to_i32();
+        let filename_len = args[2].to_i32() as usize;
+
+        let filename = main_memory.str(filename_ptr, filename_len)?;
+        let output_file = data_guard
+            .sandbox
+            .resolve(filename, sandbox::Access::Write)?;
+
+        let video_session = data_guard.session_mut(session)?;
+        open_streaming_encoder(
+            video_session,
+            encode_video::OutputTarget::File(&output_file),
+        )?;
+        video_session.output_file = Some(output_file);
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
+
+/// Like `open_video_encoder`, but the video is written to memory. MP4s are fragmented so that
+/// `read_output_bytes` can hand out the bytes written so far while frames are still encoded.
+#[host_function]
+fn open_video_encoder_to_memory(
+    _caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<Mutex<VideoSessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("open_video_encoder_to_memory");
+
+    with_sessions(data, |data_guard| {
+        let session = args[0].to_i32() as u32;
+
+        let video_session = data_guard.session_mut(session)?;
+        open_streaming_encoder(video_session, encode_video::OutputTarget::Memory)?;
+        video_session.output_file = None;
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
+
+fn open_streaming_encoder(
+    video_session: &mut FramesMap,
+    target: encode_video::OutputTarget,
+) -> Result<(), PluginError> {
+    let video_info = video_session.video_info()?;
+
+    let res = encode_video::VideoEncoder::new_streaming(
+        video_info,
+        target,
+        &video_session.encoder_options,
+    );
+
+    let mut video_encoder = res?;
+    // Packets read before the encoder was opened
+    for packet in video_session.passthrough_packets.drain(..) {
+        video_encoder.write_passthrough_packet(packet)?;
+    }
+    video_session.video_encoder = Some(video_encoder);
+    Ok(())
+}
+
+/// Copies up to `buf_len` bytes of a video encoded to memory into the guest buffer and writes
+/// how many to `len_ptr`. Every byte is handed out once, 0 means that none are ready, which
+/// after `finish_video_encoder` or `assemble_output_frames_to_memory` is the end of the video.
+#[host_function]
+fn read_output_bytes(
+    caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<Mutex<VideoSessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("read_output_bytes");
+
+    with_sessions(data, |data_guard| {
+        let mut main_memory = GuestMemory::from_caller(&caller)?;
+
+        let session = args[0].to_i32() as u32;
+        let buf_ptr = args[1].to_i32();
+        let buf_len = args[2].to_i32() as usize;
+        let len_ptr = args[3].to_i32();
+
+        let video_session = data_guard.session_mut(session)?;
+        // Whatever a streaming encoder has written since the last call
+        if let Some(video_encoder) = video_session.video_encoder.as_mut() {
+            if let Some(output_bytes) = video_encoder.take_output_bytes()? {
+                video_session.output_bytes.extend(output_bytes);
+            }
+        }
+
+        let len = video_session.output_bytes.len().min(buf_len);
+        // Both pointers are checked before any bytes are taken out
+        main_memory.slice_mut(buf_ptr, len)?;
+        main_memory.write_u32(len_ptr, len as u32)?;
+        let buffer = main_memory.slice_mut(buf_ptr, len)?;
+        for (byte, output_byte) in buffer
+            .iter_mut()
+            .zip(video_session.output_bytes.drain(..len))
+        {
+            *byte = output_byte;
+        }
+
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
+
+/// Reads `EncoderOptions` as JSON from the guest, for the next encoder opened for the session.
+/// They are checked against the linked FFmpeg straight away, e.g. that the codec is available.
+#[host_function]
+fn set_encoder_options(
+    caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<Mutex<VideoSessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("set_encoder_options");
+
+    with_sessions(data, |data_guard| {
+        let main_memory = GuestMemory::from_caller(&caller)?;
+
+        let session = args[0].to_i32() as u32;
+        let json_ptr = args[1].to_i32();
+        let json_len = args[2].to_i32() as usize;
+
+        let encoder_options: encode_video::EncoderOptions =
+            parse_options(&main_memory, json_ptr, json_len, "Encoder Options")?;
+        encoder_options.validate()?;
+
+        let video_session = data_guard.session_mut(session)?;
+        // A filter that does not fit the video is reported now rather than when encoding
+        encoder_options.validate_filter(video_session.video_info()?)?;
+        if video_session.video_encoder.is_some() {
+            return Err(PluginError::new(
+                VideoProcessingPluginError::InvalidState,
+                "Encoder already opened, set the Encoder Options before open_video_encoder",
+            ));
+        }
+        video_session.encoder_options = encoder_options;
+
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
+
+#[host_function]
+fn finish_video_encoder(
+    _caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<Mutex<VideoSessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("finish_video_encoder");
+
+    with_sessions(data, |data_guard| {
+        let session = args[0].to_i32() as u32;
+
+        let video_session = data_guard.session_mut(session)?;
+
+        let mut video_encoder = video_session.video_encoder.take().ok_or_else(|| {
+            PluginError::new(
+                VideoProcessingPluginError::InvalidState,
+                "No Encoder opened when calling finish_video_encoder",
+            )
+        })?;
+
+        // Whatever the decoder read after the last frame, e.g. the tail of the soundtrack
+        if let Some(video_decoder) = video_session.video_decoder.as_mut() {
+            video_session
+                .passthrough_packets
+                .extend(video_decoder.take_passthrough_packets());
+        }
+        for packet in video_session.passthrough_packets.drain(..) {
+            video_encoder.write_passthrough_packet(packet)?;
+        }
+
+        video_encoder
+            .finish()
+            .map_err(encode_video::VideoEncoderError::from)?;
+        if let Some(output_bytes) = video_encoder.take_output_bytes()? {
+            video_session.output_bytes.extend(output_bytes);
+        }
+
+        if let Some(output_file) = video_session.output_file.as_deref() {
+            video_session.write_annotations(output_file)?;
+        }
+
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
+
+/// Frees a video session together with its frames, decoder and encoder.
+/// A streaming encoder that has not been finished yet is finalised before it is dropped.
+#[host_function]
+fn close_video(
+    _caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<Mutex<VideoSessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("close_video");
+
+    with_sessions(data, |data_guard| {
+        let session = args[0].to_i32() as u32;
+
+        if data_guard.sessions.remove(&session).is_none() {
+            return Err(PluginError::new(
+                VideoProcessingPluginError::InvalidSession,
+                format!("No Video Session with handle {session} to close"),
+            ));
+        }
+
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
+
+/// Copies the message of the last error into the guest buffer and writes its length to `len_ptr`.
+/// If the buffer is too small nothing is copied, the required length is still written.
+#[host_function]
+fn get_last_error_message(
+    caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<Mutex<VideoSessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("get_last_error_message");
+
+    with_sessions(data, |data_guard| {
+        let mut main_memory = GuestMemory::from_caller(&caller)?;
+
+        let message_buf_ptr = args[0].to_i32();
+        let message_buf_len = args[1].to_i32() as usize;
+        let message_len_ptr = args[2].to_i32();
+
+        let message = data_guard
+            .last_error
+            .as_ref()
+            .map(|err| err.to_string())
+            .unwrap_or_default();
+
+        match main_memory.copy_to_buffer(
+            message.as_bytes(),
+            message_buf_ptr,
+            message_buf_len,
+            message_len_ptr,
+        ) {
+            Ok(()) => Ok(vec![WasmValue::from_i32(0)]),
+            // Must not replace the message the guest is asking for
+            Err(err) if err.code == VideoProcessingPluginError::BufferTooSmall => {
+                Ok(vec![WasmValue::from_i32(err.code as i32)])
+            }
+            Err(err) => Err(err),
+        }
+    })
+}
+
+/// Writes all `VideoInfo` of a session into the guest buffer as versioned JSON,
+/// see `VideoInfoReport`. The length of the JSON is written to `len_ptr`.
+#[host_function]
+fn get_video_info(
+    caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<Mutex<VideoSessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("get_video_info");
+
+    with_sessions(data, |data_guard| {
+        let mut main_memory = GuestMemory::from_caller(&caller)?;
+
+        let session = args[0].to_i32() as u32;
+        let json_buf_ptr = args[1].to_i32();
+        let json_buf_len = args[2].to_i32() as usize;
+        let json_len_ptr = args[3].to_i32();
+
+        let video_info = data_guard.session(session)?.video_info()?;
+
+        let json = serde_json::to_vec(&VideoInfoReport::from(video_info)).map_err(|err| {
+            PluginError::new(
+                VideoProcessingPluginError::InvalidState,
+                format!("Could not serialise Video Info {err}"),
+            )
+        })?;
+
+        main_memory.copy_to_buffer(&json, json_buf_ptr, json_buf_len, json_len_ptr)?;
+
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
+
+/// Writes the `FrameMetadata` of an input frame into the guest struct at `metadata_ptr`
+#[host_function]
+fn get_frame_metadata(
+    caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<Mutex<VideoSessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("get_frame_metadata");
+
+    with_sessions(data, |data_guard| {
+        let mut main_memory = GuestMemory::from_caller(&caller)?;
+
+        let session = args[0].to_i32() as u32;
+        let idx = args[1].to_i32() as usize;
+        let metadata_ptr = args[2].to_i32();
+
+        let video_session = data_guard.session(session)?;
+        let time_base = video_session.video_info()?.time_base;
+        let frame_metadata = FrameMetadata::new(video_session.frame(idx)?, time_base);
+
+        main_memory.write_value(metadata_ptr, frame_metadata)?;
+
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
+
+/// Writes the `FrameTransform` of a session into the guest struct at `transform_ptr`,
+/// so that detections on resized frames can be mapped back to the source
+#[host_function]
+fn get_frame_transform(
+    caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<Mutex<VideoSessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("get_frame_transform");
+
+    with_sessions(data, |data_guard| {
+        let mut main_memory = GuestMemory::from_caller(&caller)?;
+
+        let session = args[0].to_i32() as u32;
+        let transform_ptr = args[1].to_i32();
+
+        let frame_transform = data_guard.session(session)?.video_info()?.frame_transform;
+        main_memory.write_value(transform_ptr, frame_transform)?;
+
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
+
+/// All videos currently opened by the guest, keyed by the opaque handle handed out on load
+struct VideoSessions {
+    sessions: HashMap<u32, FramesMap>,
+    // Handle given to the next session, 0 is never handed out
+    next_session: u32,
+    // Last error returned to the guest, for get_last_error_message
+    last_error: Option<PluginError>,
+    // Video files the guest is pushing into the host, keyed by their handle
+    input_buffers: HashMap<u32, Vec<u8>>,
+    // Handle given to the next input buffer, 0 is never handed out
+    next_input_buffer: u32,
+    // Directories the guest may read videos from and write them to
+    sandbox: sandbox::Sandbox,
+}
+
+impl VideoSessions {
+    // Adds a session and writes its handle to `session_ptr`. Nothing is added if the handle
+    // cannot be written, the guest would have no way to close the session.
+    fn open_session(
+        &mut self,
+        main_memory: &mut GuestMemory,
+        session_ptr: i32,
+        frames_map: FramesMap,
+    ) -> Result<(), PluginError> {
+        let session = self.next_session;
+        main_memory.write_u32(session_ptr, session)?;
+        self.next_session += 1;
+        self.sessions.insert(session, frames_map);
+        Ok(())
+    }
+
+    fn session(&self, session: u32) -> Result<&FramesMap, PluginError> {
+        self.sessions.get(&session).ok_or_else(|| {
+            PluginError::new(
+                VideoProcessingPluginError::InvalidSession,
+                format!("No Video Session with handle {session}"),
+            )
+        })
+    }
+
+    fn session_mut(&mut self, session: u32) -> Result<&mut FramesMap, PluginError> {
+        self.sessions.get_mut(&session).ok_or_else(|| {
+            PluginError::new(
+                VideoProcessingPluginError::InvalidSession,
+                format!("No Video Session with handle {session}"),
+            )
+        })
+    }
+
+    fn input_buffer_mut(&mut self, buffer: u32) -> Result<&mut Vec<u8>, PluginError> {
+        self.input_buffers.get_mut(&buffer).ok_or_else(|| {
+            PluginError::new(
+                VideoProcessingPluginError::InvalidSession,
+                format!("No Input Buffer with handle {buffer}"),
+            )
+        })
+    }
+
+    fn take_input_buffer(&mut self, buffer: u32) -> Result<Vec<u8>, PluginError> {
+        self.input_buffers.remove(&buffer).ok_or_else(|| {
+            PluginError::new(
+                VideoProcessingPluginError::InvalidSession,
+                format!("No Input Buffer with handle {buffer}"),
+            )
+        })
+    }
 }
 
-#[derive(Clone)]
 struct FramesMap {
     frames: Frames,
     video_info: Option<VideoInfo>,
+    // Only set while a video is being decoded frame by frame
+    video_decoder: Option<decode_video::VideoDecoder>,
+    // Only set while output frames are streamed to the encoder as they are written
+    video_encoder: Option<encode_video::VideoEncoder>,
+    // Audio, subtitle and data packets waiting to be written to the output
+    passthrough_packets: Vec<Packet>,
+    // Used by the next encoder opened for the session
+    encoder_options: encode_video::EncoderOptions,
+    // Detections the guest recorded per frame index, for the annotation export
+    detections: BTreeMap<usize, Vec<detection::Detection>>,
+    annotation_options: annotations::AnnotationOptions,
+    // Path of the video a streaming encoder writes to
+    output_file: Option<String>,
+    // Encoded video not read by the guest yet, for encoders writing to memory
+    output_bytes: VecDeque<u8>,
+    // Created by init_tracker or the first track_detections
+    tracker: Option<tracking::Tracker>,
+    // Frame index track_detections was called with last, the tracker predicts one frame a call
+    last_tracked_frame: Option<usize>,
+}
+
+impl FramesMap {
+    fn video_info(&self) -> Result<&VideoInfo, PluginError> {
+        self.video_info.as_ref().ok_or_else(|| {
+            PluginError::new(
+                VideoProcessingPluginError::InvalidState,
+                "No Video Information for session",
+            )
+        })
+    }
+
+    fn video_decoder_mut(
+        &mut self,
+        host_function: &str,
+    ) -> Result<&mut decode_video::VideoDecoder, PluginError> {
+        self.video_decoder.as_mut().ok_or_else(|| {
+            PluginError::new(
+                VideoProcessingPluginError::InvalidState,
+                format!("No Video Stream opened when calling {host_function}"),
+            )
+        })
+    }
+
+    fn frame(&self, idx: usize) -> Result<&FrameMap, PluginError> {
+        self.frames.get(idx).ok_or_else(|| {
+            PluginError::new(
+                VideoProcessingPluginError::FrameIndexOutOfRange,
+                format!(
+                    "Frame {idx} does not exist, session holds {} frames",
+                    self.frames.len()
+                ),
+            )
+        })
+    }
+
+    // Writes the detections next to the output video in every format the guest asked for
+    fn write_annotations(&self, output_file: &str) -> Result<(), PluginError> {
+        if self.annotation_options.formats.is_empty() {
+            return Ok(());
+        }
+
+        let video_info = self.video_info()?;
+        let annotated_frames: Vec<annotations::AnnotatedFrame> = self
+            .frames
+            .iter()
+            .enumerate()
+            .map(|(index, frame_map)| annotations::AnnotatedFrame {
+                index,
+                timestamp: frame_map.timestamp,
+                time_seconds: frame_map
+                    .timestamp
+                    .map(|timestamp| timestamp as f64 * f64::from(video_info.time_base)),
+                detections: self
+                    .detections
+                    .get(&index)
+                    .map(Vec::as_slice)
+                    .unwrap_or_default(),
+            })
+            .collect();
+
+        annotations::write_annotations(
+            output_file,
+            &self.annotation_options,
+            &annotated_frames,
+            video_info.width(),
+            video_info.height(),
+        )
+        .map_err(|err| {
+            let code = match err.kind() {
+                std::io::ErrorKind::PermissionDenied => {
+                    VideoProcessingPluginError::PermissionDenied
+                }
+                _ => VideoProcessingPluginError::IoError,
+            };
+            PluginError::new(
+                code,
+                format!("Could not write annotations next to {output_file} {err}"),
+            )
+        })
+    }
+
+    fn frame_mut(&mut self, idx: usize) -> Result<&mut FrameMap, PluginError> {
+        let frame_count = self.frames.len();
+        self.frames.get_mut(idx).ok_or_else(|| {
+            PluginError::new(
+                VideoProcessingPluginError::FrameIndexOutOfRange,
+                format!("Frame {idx} does not exist, session holds {frame_count} frames"),
+            )
+        })
+    }
 }
 
 #[derive(Clone)]
 pub struct FrameMap {
-    input_frame: frame::Video,
+    // None once the frame has been handed to the guest by next_frame
+    input_frame: Option<frame::Video>,
     // Input Frame Type
     frame_type: picture::Type,
     // Input Frame Timestamp
     timestamp: Option<i64>,
+    // Input Frame Duration, in the time base of the input stream
+    duration: i64,
+    // Whether the input frame is a keyframe
+    key_frame: bool,
     // Option as we are not sure if it has been processed yet or not
     output_frame: Option<frame::Video>,
 }
 
+impl FrameMap {
+    // Frame that drawing host functions work on, the output frame or the input frame if the
+    // guest has not written one yet
+    fn drawable_frame_mut(&mut self, idx: usize) -> Result<&mut frame::Video, PluginError> {
+        match (self.output_frame.as_mut(), self.input_frame.as_mut()) {
+            (Some(output_frame), _) => Ok(output_frame),
+            (None, Some(input_frame)) => Ok(input_frame),
+            (None, None) => Err(PluginError::new(
+                VideoProcessingPluginError::InvalidState,
+                format!(
+                    "Frame {idx} was streamed to the guest and is no longer held by the plugin"
+                ),
+            )),
+        }
+    }
+}
+
+/// Metadata of a single input frame as written into guest memory by `get_frame_metadata`.
+/// The layout is identical on the host and on wasm32, the guest declares the same struct.
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct FrameMetadata {
+    // Presentation timestamp in the time base of the input stream, only valid if has_pts is 1
+    pub pts: i64,
+    // Duration in the time base of the input stream, 0 if unknown
+    pub duration: i64,
+    pub pts_seconds: f64,
+    pub duration_seconds: f64,
+    pub time_base_num: i32,
+    pub time_base_den: i32,
+    // 'I', 'P', 'B', ... as returned by av_get_picture_type_char, '?' if unknown
+    pub picture_type: u8,
+    pub key_frame: u8,
+    pub has_pts: u8,
+    pub _reserved: [u8; 5],
+}
+
+impl FrameMetadata {
+    fn new(frame_map: &FrameMap, time_base: Rational) -> Self {
+        let seconds = |ts: i64| ts as f64 * f64::from(time_base);
+        let picture_type = match frame_map.frame_type {
+            picture::Type::I => b'I',
+            picture::Type::P => b'P',
+            picture::Type::B => b'B',
+            picture::Type::S => b'S',
+            picture::Type::SI => b'i',
+            picture::Type::SP => b'p',
+            picture::Type::BI => b'b',
+            picture::Type::None => b'?',
+        };
+
+        FrameMetadata {
+            pts: frame_map.timestamp.unwrap_or(0),
+            duration: frame_map.duration,
+            pts_seconds: frame_map.timestamp.map(seconds).unwrap_or(0.0),
+            duration_seconds: seconds(frame_map.duration),
+            time_base_num: time_base.numerator(),
+            time_base_den: time_base.denominator(),
+            picture_type,
+            key_frame: frame_map.key_frame as u8,
+            has_pts: frame_map.timestamp.is_some() as u8,
+            _reserved: [0; 5],
+        }
+    }
+}
+
 type Frames = Vec<FrameMap>;
-type ShareFrames = Arc<Mutex<FramesMap>>;
+type ShareFrames = Arc<Mutex<VideoSessions>>;
 
 /// Defines Plugin module instance
 unsafe extern "C" fn create_test_module(
@@ -446,9 +1981,13 @@ unsafe extern "C" fn create_test_module(
 ) -> *mut ffi::WasmEdge_ModuleInstanceContext {
     let module_name = "yolo-video-proc";
 
-    let video_frames = FramesMap {
-        frames: Vec::new(),
-        video_info: None,
+    let video_frames = VideoSessions {
+        sessions: HashMap::new(),
+        next_session: 1,
+        last_error: None,
+        input_buffers: HashMap::new(),
+        next_input_buffer: 1,
+        sandbox: sandbox::Sandbox::from_env(),
     };
 
     let video_frames_arc = Box::new(Arc::new(Mutex::new(video_frames)));
@@ -456,34 +1995,218 @@ unsafe extern "C" fn create_test_module(
     type Width = i32;
     type Height = i32;
     type Frames = i32;
+    type Session = i32;
+    type InputBuffer = i32;
 
     let plugin_module = PluginModuleBuilder::<NeverType>::new()
         .with_func::<i32, i32, ()>("init_plugin_logging", init_plugin_logging, None)
         .expect("failed to create init_plugin_logging host function")
-        .with_func::<(i32, i32, i32, Width, Height, Frames), i32, ShareFrames>(
+        .with_func::<(), i32, ()>("get_abi_version", get_abi_version, None)
+        .expect("failed to create get_abi_version host function")
+        .with_func::<(i32, i32, Width, Height, Frames, Session), i32, ShareFrames>(
             "load_video_to_host_memory",
             load_video_to_host_memory,
             Some(video_frames_arc.clone()),
         )
         .expect("failed to create load_video_to_host_memory host function")
-        .with_func::<(i32, i32, i32, i32), i32, ShareFrames>(
+        .with_func::<(i32, i32, i64, i64, Width, Height, Frames, Session), i32, ShareFrames>(
+            "load_video_range_to_host_memory",
+            load_video_range_to_host_memory,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create load_video_range_to_host_memory host function")
+        .with_func::<(i32, i32, i32, i32, Width, Height, Frames, Session), i32, ShareFrames>(
+            "load_video_with_options_to_host_memory",
+            load_video_with_options_to_host_memory,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create load_video_with_options_to_host_memory host function")
+        .with_func::<(Session, i32, i32, i32), i32, ShareFrames>(
             "get_frame",
             get_frame,
             Some(video_frames_arc.clone()),
         )
         .expect("failed to create get_frame host function")
-        .with_func::<(i32, i32, i32), i32, ShareFrames>(
+        .with_func::<(Session, i32, i32, i32, i32, i32, i32, i32), i32, ShareFrames>(
+            "get_frame_tensor",
+            get_frame_tensor,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create get_frame_tensor host function")
+        .with_func::<(i32, i32, Width, Height, Session), i32, ShareFrames>(
+            "open_video_stream",
+            open_video_stream,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create open_video_stream host function")
+        .with_func::<(i32, i32, i32, i32, Width, Height, Session), i32, ShareFrames>(
+            "open_video_stream_with_options",
+            open_video_stream_with_options,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create open_video_stream_with_options host function")
+        .with_func::<InputBuffer, i32, ShareFrames>(
+            "create_input_buffer",
+            create_input_buffer,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create create_input_buffer host function")
+        .with_func::<(InputBuffer, i32, i32), i32, ShareFrames>(
+            "append_input_bytes",
+            append_input_bytes,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create append_input_bytes host function")
+        .with_func::<InputBuffer, i32, ShareFrames>(
+            "discard_input_buffer",
+            discard_input_buffer,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create discard_input_buffer host function")
+        .with_func::<(InputBuffer, i32, i32, Width, Height, Frames, Session), i32, ShareFrames>(
+            "load_video_from_input_buffer",
+            load_video_from_input_buffer,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create load_video_from_input_buffer host function")
+        .with_func::<(InputBuffer, i32, i32, Width, Height, Session), i32, ShareFrames>(
+            "open_video_stream_from_input_buffer",
+            open_video_stream_from_input_buffer,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create open_video_stream_from_input_buffer host function")
+        .with_func::<(Session, i32, i32, i32), i32, ShareFrames>(
+            "next_frame",
+            next_frame,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create next_frame host function")
+        .with_func::<(Session, i64), i32, ShareFrames>(
+            "seek_to_timestamp",
+            seek_to_timestamp,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create seek_to_timestamp host function")
+        .with_func::<(Session, i64), i32, ShareFrames>(
+            "seek_to_frame",
+            seek_to_frame,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create seek_to_frame host function")
+        .with_func::<(Session, i32, i32, i32), i32, ShareFrames>(
             "write_frame",
             write_frame,
             Some(video_frames_arc.clone()),
         )
         .expect("failed to create write_frame host function")
-        .with_func::<(i32, i32, i32), i32, ShareFrames>(
+        .with_func::<(Session, i32, i32, i32), i32, ShareFrames>(
+            "draw_detections",
+            draw_detections,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create draw_detections host function")
+        .with_func::<(Session, i32, i32, i32), i32, ShareFrames>(
+            "redact_regions",
+            redact_regions,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create redact_regions host function")
+        .with_func::<(Session, i32, i32, i32), i32, ShareFrames>(
+            "set_frame_detections",
+            set_frame_detections,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create set_frame_detections host function")
+        .with_func::<(Session, i32, i32), i32, ShareFrames>(
+            "init_tracker",
+            init_tracker,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create init_tracker host function")
+        .with_func::<(Session, i32, i32, i32, i32, i32), i32, ShareFrames>(
+            "track_detections",
+            track_detections,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create track_detections host function")
+        .with_func::<(Session, i32, i32), i32, ShareFrames>(
+            "set_annotation_options",
+            set_annotation_options,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create set_annotation_options host function")
+        .with_func::<(Session, i32, i32), i32, ShareFrames>(
             "assemble_output_frames_to_video",
             assemble_output_frames_to_video,
             Some(video_frames_arc.clone()),
         )
         .expect("failed to create assemble_output_frames_to_video host function")
+        .with_func::<Session, i32, ShareFrames>(
+            "assemble_output_frames_to_memory",
+            assemble_output_frames_to_memory,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create assemble_output_frames_to_memory host function")
+        .with_func::<(Session, i32, i32), i32, ShareFrames>(
+            "open_video_encoder",
+            open_video_encoder,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create open_video_encoder host function")
+        .with_func::<Session, i32, ShareFrames>(
+            "open_video_encoder_to_memory",
+            open_video_encoder_to_memory,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create open_video_encoder_to_memory host function")
+        .with_func::<(Session, i32, i32), i32, ShareFrames>(
+            "set_encoder_options",
+            set_encoder_options,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create set_encoder_options host function")
+        .with_func::<Session, i32, ShareFrames>(
+            "finish_video_encoder",
+            finish_video_encoder,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create finish_video_encoder host function")
+        .with_func::<(Session, i32, i32, i32), i32, ShareFrames>(
+            "read_output_bytes",
+            read_output_bytes,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create read_output_bytes host function")
+        .with_func::<Session, i32, ShareFrames>(
+            "close_video",
+            close_video,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create close_video host function")
+        .with_func::<(i32, i32, i32), i32, ShareFrames>(
+            "get_last_error_message",
+            get_last_error_message,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create get_last_error_message host function")
+        .with_func::<(Session, i32, i32, i32), i32, ShareFrames>(
+            "get_video_info",
+            get_video_info,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create get_video_info host function")
+        .with_func::<(Session, i32, i32), i32, ShareFrames>(
+            "get_frame_metadata",
+            get_frame_metadata,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create get_frame_metadata host function")
+        .with_func::<(Session, i32), i32, ShareFrames>(
+            "get_frame_transform",
+            get_frame_transform,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create get_frame_transform host function")
         .build(module_name)
         .expect("failed to create plugin module");
 
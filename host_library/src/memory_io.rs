@@ -0,0 +1,276 @@
+use std::{
+    ffi::{c_int, c_void, CString},
+    ops::{Deref, DerefMut},
+    ptr,
+};
+
+use ffmpeg::{
+    ffi::{
+        av_free, av_malloc, avformat_alloc_context, avformat_alloc_output_context2,
+        avformat_close_input, avformat_find_stream_info, avformat_open_input, avio_alloc_context,
+        avio_context_free, AVIOContext, AVERROR, AVERROR_EOF, AVFMT_FLAG_CUSTOM_IO,
+    },
+    format::context::{Input, Output},
+    util::error::{EINVAL, ENOMEM},
+    Dictionary, Error as FFmpegError,
+};
+
+// Size of the buffer FFmpeg reads through, as for files
+const IO_BUFFER_SIZE: usize = 32 * 1024;
+
+// Whence values of the AVIOContext seek callback, from stdio.h and libavformat/avio.h
+const SEEK_SET: c_int = 0;
+const SEEK_CUR: c_int = 1;
+const SEEK_END: c_int = 2;
+const AVSEEK_SIZE: c_int = 0x10000;
+const AVSEEK_FORCE: c_int = 0x20000;
+
+// Bytes of a video together with the read position of FFmpeg in them
+struct MemoryReader {
+    data: Vec<u8>,
+    position: usize,
+}
+
+/// Custom AVIOContext FFmpeg reads a video held in memory through, instead of opening a file.
+/// Has to outlive the input context opened on it.
+pub struct MemoryInput {
+    avio: *mut AVIOContext,
+    // Boxed so that its address, which the AVIOContext holds on to, never changes
+    _reader: Box<MemoryReader>,
+}
+
+impl Drop for MemoryInput {
+    fn drop(&mut self) {
+        unsafe {
+            // The buffer may have been replaced by FFmpeg, free whatever it holds now
+            av_free((*self.avio).buffer as *mut c_void);
+            avio_context_free(&mut self.avio);
+        }
+    }
+}
+
+/// Opens an input context on the bytes of a whole video file, e.g. an MP4 received over the network
+pub fn open_input(data: Vec<u8>, options: Dictionary) -> Result<(Input, MemoryInput), FFmpegError> {
+    let mut reader = Box::new(MemoryReader { data, position: 0 });
+
+    unsafe {
+        let buffer = av_malloc(IO_BUFFER_SIZE) as *mut u8;
+        if buffer.is_null() {
+            return Err(FFmpegError::Other { errno: ENOMEM });
+        }
+        let avio = avio_alloc_context(
+            buffer,
+            IO_BUFFER_SIZE as c_int,
+            0,
+            &mut *reader as *mut MemoryReader as *mut c_void,
+            Some(read_packet),
+            None,
+            Some(seek_reader),
+        );
+        if avio.is_null() {
+            av_free(buffer as *mut c_void);
+            return Err(FFmpegError::Other { errno: ENOMEM });
+        }
+        let memory_input = MemoryInput {
+            avio,
+            _reader: reader,
+        };
+
+        let mut format_context = avformat_alloc_context();
+        if format_context.is_null() {
+            return Err(FFmpegError::Other { errno: ENOMEM });
+        }
+        (*format_context).pb = avio;
+
+        // Frees the format context itself should it fail
+        let mut options = options.disown();
+        let result = avformat_open_input(
+            &mut format_context,
+            ptr::null(),
+            ptr::null_mut(),
+            &mut options,
+        );
+        Dictionary::own(options);
+        if result != 0 {
+            return Err(FFmpegError::from(result));
+        }
+
+        match avformat_find_stream_info(format_context, ptr::null_mut()) {
+            result if result >= 0 => Ok((Input::wrap(format_context), memory_input)),
+            err => {
+                avformat_close_input(&mut format_context);
+                Err(FFmpegError::from(err))
+            }
+        }
+    }
+}
+
+unsafe extern "C" fn read_packet(opaque: *mut c_void, buf: *mut u8, buf_size: c_int) -> c_int {
+    let reader = &mut *(opaque as *mut MemoryReader);
+    let remaining = reader.data.len() - reader.position;
+    if remaining == 0 {
+        return AVERROR_EOF;
+    }
+
+    let len = remaining.min(buf_size.max(0) as usize);
+    ptr::copy_nonoverlapping(reader.data.as_ptr().add(reader.position), buf, len);
+    reader.position += len;
+    len as c_int
+}
+
+unsafe extern "C" fn seek_reader(opaque: *mut c_void, offset: i64, whence: c_int) -> i64 {
+    let reader = &mut *(opaque as *mut MemoryReader);
+    seek(&mut reader.position, reader.data.len(), offset, whence)
+}
+
+// Bytes an output context has written so far, with the write position of the muxer in them
+struct MemoryWriter {
+    data: Vec<u8>,
+    position: usize,
+}
+
+/// Output context that writes the muxed video into memory instead of a file
+pub struct MemoryOutput {
+    octx: Output,
+    avio: *mut AVIOContext,
+    seekable: bool,
+    // Boxed so that its address, which the AVIOContext holds on to, never changes
+    writer: Box<MemoryWriter>,
+}
+
+// Only ever used behind the plugin Mutex, like the rest of the encoder
+unsafe impl Send for MemoryOutput {}
+
+impl MemoryOutput {
+    /// Opens an output context for the muxer `container`, e.g. "mp4" or "webm".
+    /// Muxers can go back to fix up headers in a `seekable` output, e.g. to write the index of
+    /// an MP4 or Matroska file, but its bytes are only complete once the trailer is written.
+    pub fn open(container: &str, seekable: bool) -> Result<Self, FFmpegError> {
+        let c_container = CString::new(container).map_err(|_| FFmpegError::MuxerNotFound)?;
+        let mut writer = Box::new(MemoryWriter {
+            data: Vec::new(),
+            position: 0,
+        });
+
+        unsafe {
+            let mut format_context = ptr::null_mut();
+            match avformat_alloc_output_context2(
+                &mut format_context,
+                ptr::null_mut(),
+                c_container.as_ptr(),
+                ptr::null(),
+            ) {
+                0 => {}
+                err => return Err(FFmpegError::from(err)),
+            }
+            let octx = Output::wrap(format_context);
+
+            let buffer = av_malloc(IO_BUFFER_SIZE) as *mut u8;
+            if buffer.is_null() {
+                return Err(FFmpegError::Other { errno: ENOMEM });
+            }
+            let avio = avio_alloc_context(
+                buffer,
+                IO_BUFFER_SIZE as c_int,
+                1,
+                &mut *writer as *mut MemoryWriter as *mut c_void,
+                None,
+                Some(write_packet),
+                seekable.then_some(seek_writer as _),
+            );
+            if avio.is_null() {
+                av_free(buffer as *mut c_void);
+                return Err(FFmpegError::Other { errno: ENOMEM });
+            }
+            (*format_context).pb = avio;
+            (*format_context).flags |= AVFMT_FLAG_CUSTOM_IO as c_int;
+
+            Ok(MemoryOutput {
+                octx,
+                avio,
+                seekable,
+                writer,
+            })
+        }
+    }
+
+    pub fn is_seekable(&self) -> bool {
+        self.seekable
+    }
+
+    /// Takes the bytes written so far. A seekable output hands out its bytes only once,
+    /// after the trailer has been written, as the muxer may still go back and change them.
+    pub fn take_bytes(&mut self) -> Vec<u8> {
+        if !self.seekable {
+            self.writer.position = 0;
+        }
+        std::mem::take(&mut self.writer.data)
+    }
+}
+
+impl Deref for MemoryOutput {
+    type Target = Output;
+
+    fn deref(&self) -> &Output {
+        &self.octx
+    }
+}
+
+impl DerefMut for MemoryOutput {
+    fn deref_mut(&mut self) -> &mut Output {
+        &mut self.octx
+    }
+}
+
+impl Drop for MemoryOutput {
+    fn drop(&mut self) {
+        unsafe {
+            // Output closes its I/O context like a file when dropped, which this one is not
+            (*self.octx.as_mut_ptr()).pb = ptr::null_mut();
+            av_free((*self.avio).buffer as *mut c_void);
+            avio_context_free(&mut self.avio);
+        }
+    }
+}
+
+unsafe extern "C" fn write_packet(opaque: *mut c_void, buf: *mut u8, buf_size: c_int) -> c_int {
+    let writer = &mut *(opaque as *mut MemoryWriter);
+    let bytes = std::slice::from_raw_parts(buf, buf_size.max(0) as usize);
+
+    // The muxer may overwrite bytes it has written before, e.g. the size of an atom
+    let end = writer.position + bytes.len();
+    if end > writer.data.len() {
+        writer.data.resize(end, 0);
+    }
+    writer.data[writer.position..end].copy_from_slice(bytes);
+    writer.position = end;
+    buf_size
+}
+
+unsafe extern "C" fn seek_writer(opaque: *mut c_void, offset: i64, whence: c_int) -> i64 {
+    let writer = &mut *(opaque as *mut MemoryWriter);
+    seek(&mut writer.position, writer.data.len(), offset, whence)
+}
+
+// Moves `position` within bytes of length `len` like fseek, or answers AVSEEK_SIZE
+fn seek(position: &mut usize, len: usize, offset: i64, whence: c_int) -> i64 {
+    let len = len as i64;
+
+    if whence & AVSEEK_SIZE != 0 {
+        return len;
+    }
+    let base = match whence & !AVSEEK_FORCE {
+        SEEK_SET => 0,
+        SEEK_CUR => *position as i64,
+        SEEK_END => len,
+        _ => return AVERROR(EINVAL) as i64,
+    };
+
+    match base.checked_add(offset) {
+        Some(new_position) if (0..=len).contains(&new_position) => {
+            *position = new_position as usize;
+            new_position
+        }
+        _ => AVERROR(EINVAL) as i64,
+    }
+}
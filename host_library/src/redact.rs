@@ -0,0 +1,555 @@
+use ffmpeg::util::frame::video::Video;
+use serde::Deserialize;
+
+use crate::error::{PluginError, VideoProcessingPluginError};
+
+// Largest blur radius, every pixel reads 2 * radius + 1 others in each pass and a blur this wide
+// already leaves nothing recognisable
+const MAX_BLUR_RADIUS: u32 = 64;
+
+/// Area of a frame to redact, in pixels of the frame
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum Region {
+    Rect {
+        x: f32,
+        y: f32,
+        width: f32,
+        height: f32,
+    },
+    Polygon {
+        points: Vec<[f32; 2]>,
+    },
+}
+
+impl Region {
+    fn polygon(&self) -> Vec<[f32; 2]> {
+        match self {
+            Region::Rect {
+                x,
+                y,
+                width,
+                height,
+            } => vec![
+                [*x, *y],
+                [x + width, *y],
+                [x + width, y + height],
+                [*x, y + height],
+            ],
+            Region::Polygon { points } => points.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RedactMethod {
+    #[default]
+    Blur,
+    Pixelate,
+    Fill,
+}
+
+/// What `redact_regions` does to a frame, sent by the guest as JSON
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RedactOptions {
+    pub regions: Vec<Region>,
+    pub method: RedactMethod,
+    // Radius of the Gaussian blur in pixels
+    pub blur_radius: u32,
+    // Size of the blocks of the pixelation in pixels
+    pub block_size: u32,
+    // RGB colour of a solid fill
+    pub color: [u8; 3],
+    // Pixels around each region that are redacted as well
+    pub padding: f32,
+    // Width in pixels over which the redaction fades out around the padded region
+    pub feather: f32,
+}
+
+impl Default for RedactOptions {
+    fn default() -> Self {
+        RedactOptions {
+            regions: Vec::new(),
+            method: RedactMethod::default(),
+            blur_radius: 15,
+            block_size: 16,
+            color: [0, 0, 0],
+            padding: 0.0,
+            feather: 0.0,
+        }
+    }
+}
+
+impl RedactOptions {
+    /// Checks the options for a frame of `frame_width` x `frame_height` pixels. Blur radius is
+    /// capped at `MAX_BLUR_RADIUS`, block size at the longer side where larger blocks change
+    /// nothing.
+    pub fn validate(&self, frame_width: u32, frame_height: u32) -> Result<(), PluginError> {
+        if self.blur_radius == 0 || self.block_size == 0 {
+            return Err(PluginError::new(
+                VideoProcessingPluginError::InvalidArgument,
+                "Blur radius and block size have to be at least 1",
+            ));
+        }
+        if self.blur_radius > MAX_BLUR_RADIUS {
+            return Err(PluginError::new(
+                VideoProcessingPluginError::InvalidArgument,
+                format!("Blur radius can be at most {MAX_BLUR_RADIUS}"),
+            ));
+        }
+        let max_size = frame_width.max(frame_height);
+        if self.block_size > max_size {
+            return Err(PluginError::new(
+                VideoProcessingPluginError::InvalidArgument,
+                format!("Block size can be at most {max_size}, the frame size"),
+            ));
+        }
+        let finite_and_not_negative = |value: f32| value.is_finite() && value >= 0.0;
+        if !(finite_and_not_negative(self.padding) && finite_and_not_negative(self.feather)) {
+            return Err(PluginError::new(
+                VideoProcessingPluginError::InvalidArgument,
+                "Padding and feather have to be finite and not negative",
+            ));
+        }
+        if let Some(region) = self.regions.iter().find(|region| match region {
+            Region::Polygon { points } => points.len() < 3,
+            Region::Rect { width, height, .. } => *width < 0.0 || *height < 0.0,
+        }) {
+            return Err(PluginError::new(
+                VideoProcessingPluginError::InvalidArgument,
+                format!("Region {region:?} does not enclose an area"),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Blurs, pixelates or fills every region of an RGB24 frame, whatever falls outside the
+/// frame is clipped
+pub fn redact_regions(frame: &mut Video, options: &RedactOptions) {
+    let (frame_width, frame_height) = (frame.width() as i32, frame.height() as i32);
+    let reach = options.padding + options.feather;
+
+    for region in &options.regions {
+        let polygon = region.polygon();
+
+        // Pixels the redaction can touch
+        let min_x = polygon
+            .iter()
+            .map(|point| point[0])
+            .fold(f32::MAX, f32::min)
+            - reach;
+        let min_y = polygon
+            .iter()
+            .map(|point| point[1])
+            .fold(f32::MAX, f32::min)
+            - reach;
+        let max_x = polygon
+            .iter()
+            .map(|point| point[0])
+            .fold(f32::MIN, f32::max)
+            + reach;
+        let max_y = polygon
+            .iter()
+            .map(|point| point[1])
+            .fold(f32::MIN, f32::max)
+            + reach;
+        let area = Area {
+            left: (min_x.floor() as i32).clamp(0, frame_width),
+            top: (min_y.floor() as i32).clamp(0, frame_height),
+            right: (max_x.ceil() as i32).clamp(0, frame_width),
+            bottom: (max_y.ceil() as i32).clamp(0, frame_height),
+        };
+        if area.left >= area.right || area.top >= area.bottom {
+            continue;
+        }
+
+        let redacted = match options.method {
+            RedactMethod::Blur => blurred(frame, &area, options.blur_radius as i32),
+            RedactMethod::Pixelate => pixelated(frame, &area, options.block_size as i32),
+            RedactMethod::Fill => options.color.repeat(area.pixel_count()),
+        };
+
+        let stride = frame.stride(0);
+        let data = frame.data_mut(0);
+        for y in area.top..area.bottom {
+            for x in area.left..area.right {
+                let alpha = coverage(&polygon, x as f32 + 0.5, y as f32 + 0.5, options);
+                if alpha <= 0.0 {
+                    continue;
+                }
+                let offset = y as usize * stride + x as usize * 3;
+                let redacted_offset = area.offset(x, y);
+                for channel in 0..3 {
+                    let original = data[offset + channel] as f32;
+                    let redacted = redacted[redacted_offset + channel] as f32;
+                    data[offset + channel] =
+                        (original + (redacted - original) * alpha).round() as u8;
+                }
+            }
+        }
+    }
+}
+
+// Pixels from (left, top) up to but excluding (right, bottom)
+struct Area {
+    left: i32,
+    top: i32,
+    right: i32,
+    bottom: i32,
+}
+
+impl Area {
+    fn width(&self) -> usize {
+        (self.right - self.left) as usize
+    }
+
+    fn pixel_count(&self) -> usize {
+        self.width() * (self.bottom - self.top) as usize
+    }
+
+    // Offset of a frame pixel in a packed RGB24 buffer of the area
+    fn offset(&self, x: i32, y: i32) -> usize {
+        ((y - self.top) as usize * self.width() + (x - self.left) as usize) * 3
+    }
+}
+
+// How much of the redaction a pixel gets, 1 inside the padded polygon fading to 0 over feather
+fn coverage(polygon: &[[f32; 2]], x: f32, y: f32, options: &RedactOptions) -> f32 {
+    if contains(polygon, x, y) {
+        return 1.0;
+    }
+    let distance = distance_to_outline(polygon, x, y);
+    if distance <= options.padding {
+        return 1.0;
+    }
+    if options.feather <= 0.0 {
+        return 0.0;
+    }
+    (1.0 - (distance - options.padding) / options.feather).max(0.0)
+}
+
+// Even-odd rule
+fn contains(polygon: &[[f32; 2]], x: f32, y: f32) -> bool {
+    let mut inside = false;
+    let mut previous = polygon[polygon.len() - 1];
+    for &point in polygon {
+        if (point[1] > y) != (previous[1] > y)
+            && x < (previous[0] - point[0]) * (y - point[1]) / (previous[1] - point[1]) + point[0]
+        {
+            inside = !inside;
+        }
+        previous = point;
+    }
+    inside
+}
+
+fn distance_to_outline(polygon: &[[f32; 2]], x: f32, y: f32) -> f32 {
+    let mut previous = polygon[polygon.len() - 1];
+    let mut distance = f32::MAX;
+    for &point in polygon {
+        let (edge_x, edge_y) = (point[0] - previous[0], point[1] - previous[1]);
+        let length_squared = edge_x * edge_x + edge_y * edge_y;
+        let t = if length_squared > 0.0 {
+            (((x - previous[0]) * edge_x + (y - previous[1]) * edge_y) / length_squared)
+                .clamp(0.0, 1.0)
+        } else {
+            0.0
+        };
+        let (closest_x, closest_y) = (previous[0] + t * edge_x, previous[1] + t * edge_y);
+        distance = distance.min(((x - closest_x).powi(2) + (y - closest_y).powi(2)).sqrt());
+        previous = point;
+    }
+    distance
+}
+
+// Separable Gaussian blur of the area, reading up to `radius` pixels around it from the frame
+fn blurred(frame: &Video, area: &Area, radius: i32) -> Vec<u8> {
+    let (frame_width, frame_height) = (frame.width() as i32, frame.height() as i32);
+    let sigma = (radius as f32 / 2.0).max(0.5);
+    let kernel: Vec<f32> = (-radius..=radius)
+        .map(|offset| (-((offset * offset) as f32) / (2.0 * sigma * sigma)).exp())
+        .collect();
+    let kernel_sum: f32 = kernel.iter().sum();
+    let kernel: Vec<f32> = kernel.iter().map(|weight| weight / kernel_sum).collect();
+
+    let stride = frame.stride(0);
+    let data = frame.data(0);
+    let pixel = |x: i32, y: i32, channel: usize| -> f32 {
+        let x = x.clamp(0, frame_width - 1) as usize;
+        let y = y.clamp(0, frame_height - 1) as usize;
+        data[y * stride + x * 3 + channel] as f32
+    };
+
+    // Horizontal pass over the rows the vertical pass needs
+    let top = (area.top - radius).max(0);
+    let bottom = (area.bottom + radius).min(frame_height);
+    let width = area.width();
+    let mut horizontal = vec![0.0f32; width * (bottom - top) as usize * 3];
+    for y in top..bottom {
+        for x in area.left..area.right {
+            let offset = ((y - top) as usize * width + (x - area.left) as usize) * 3;
+            for channel in 0..3 {
+                horizontal[offset + channel] = kernel
+                    .iter()
+                    .zip(-radius..=radius)
+                    .map(|(weight, kernel_x)| weight * pixel(x + kernel_x, y, channel))
+                    .sum();
+            }
+        }
+    }
+
+    let mut blurred = vec![0u8; area.pixel_count() * 3];
+    for y in area.top..area.bottom {
+        for x in area.left..area.right {
+            let offset = area.offset(x, y);
+            for channel in 0..3 {
+                let value: f32 = kernel
+                    .iter()
+                    .zip(-radius..=radius)
+                    .map(|(weight, kernel_y)| {
+                        let row = (y + kernel_y).clamp(top, bottom - 1);
+                        let horizontal_offset =
+                            ((row - top) as usize * width + (x - area.left) as usize) * 3;
+                        weight * horizontal[horizontal_offset + channel]
+                    })
+                    .sum();
+                blurred[offset + channel] = value.round().clamp(0.0, 255.0) as u8;
+            }
+        }
+    }
+    blurred
+}
+
+// Every block of the area takes the average colour of its pixels
+fn pixelated(frame: &Video, area: &Area, block_size: i32) -> Vec<u8> {
+    let stride = frame.stride(0);
+    let data = frame.data(0);
+    let mut pixelated = vec![0u8; area.pixel_count() * 3];
+
+    for block_top in (area.top..area.bottom).step_by(block_size as usize) {
+        let block_bottom = (block_top + block_size).min(area.bottom);
+        for block_left in (area.left..area.right).step_by(block_size as usize) {
+            let block_right = (block_left + block_size).min(area.right);
+
+            let mut sum = [0u32; 3];
+            for y in block_top..block_bottom {
+                for x in block_left..block_right {
+                    let offset = y as usize * stride + x as usize * 3;
+                    for channel in 0..3 {
+                        sum[channel] += data[offset + channel] as u32;
+                    }
+                }
+            }
+            let count = ((block_bottom - block_top) * (block_right - block_left)) as u32;
+            let average = sum.map(|sum| (sum / count) as u8);
+
+            for y in block_top..block_bottom {
+                for x in block_left..block_right {
+                    let offset = area.offset(x, y);
+                    pixelated[offset..offset + 3].copy_from_slice(&average);
+                }
+            }
+        }
+    }
+    pixelated
+}
+
+#[cfg(test)]
+mod tests {
+    use ffmpeg::format::Pixel;
+
+    use crate::frame_buffer;
+
+    use super::*;
+
+    // Frame of `width` x `height` pixels given row by row
+    fn frame(width: u32, height: u32, pixels: &[[u8; 3]]) -> Video {
+        let mut frame = Video::new(Pixel::RGB24, width, height);
+        frame_buffer::copy_packed_to_frame(pixels.concat().as_slice(), &mut frame);
+        frame
+    }
+
+    fn pixels(frame: &Video) -> Vec<[u8; 3]> {
+        let mut buf = vec![0; frame_buffer::packed_frame_size(frame.width(), frame.height())];
+        frame_buffer::copy_frame_to_packed(frame, &mut buf);
+        buf.chunks_exact(3)
+            .map(|pixel| [pixel[0], pixel[1], pixel[2]])
+            .collect()
+    }
+
+    fn whole(frame: &Video) -> Area {
+        Area {
+            left: 0,
+            top: 0,
+            right: frame.width() as i32,
+            bottom: frame.height() as i32,
+        }
+    }
+
+    #[test]
+    fn contains_follows_concave_outlines() {
+        // An L, 4 pixels tall and wide with arms 1 pixel thick
+        let polygon = [
+            [0.0, 0.0],
+            [4.0, 0.0],
+            [4.0, 1.0],
+            [1.0, 1.0],
+            [1.0, 4.0],
+            [0.0, 4.0],
+        ];
+        assert!(contains(&polygon, 0.5, 3.5));
+        assert!(contains(&polygon, 3.5, 0.5));
+        // Inside the bounding box but in the notch of the L
+        assert!(!contains(&polygon, 2.5, 2.5));
+        assert!(!contains(&polygon, 1.5, 1.5));
+        assert!(!contains(&polygon, 4.5, 0.5));
+    }
+
+    #[test]
+    fn coverage_pads_then_fades_over_the_feather() {
+        let polygon = Region::Rect {
+            x: 0.0,
+            y: 0.0,
+            width: 4.0,
+            height: 4.0,
+        }
+        .polygon();
+        let options = RedactOptions {
+            padding: 1.0,
+            feather: 2.0,
+            ..RedactOptions::default()
+        };
+        assert_eq!(coverage(&polygon, 2.0, 2.0, &options), 1.0);
+        assert_eq!(coverage(&polygon, 4.5, 2.0, &options), 1.0);
+        assert_eq!(coverage(&polygon, 5.0, 2.0, &options), 1.0);
+        assert_eq!(coverage(&polygon, 6.0, 2.0, &options), 0.5);
+        assert_eq!(coverage(&polygon, 7.5, 2.0, &options), 0.0);
+
+        let options = RedactOptions {
+            padding: 1.0,
+            ..RedactOptions::default()
+        };
+        assert_eq!(coverage(&polygon, 5.0, 2.0, &options), 1.0);
+        assert_eq!(coverage(&polygon, 5.5, 2.0, &options), 0.0);
+    }
+
+    #[test]
+    fn pixelated_averages_blocks_including_partial_ones() {
+        // 3 x 3 in blocks of 2 leaves partial blocks on the right and at the bottom
+        let values = [0, 10, 20, 100, 110, 120, 200, 210, 220];
+        let frame = frame(3, 3, &values.map(|value| [value, value, 255 - value]));
+
+        let pixelated = pixelated(&frame, &whole(&frame), 2);
+
+        let expected = [55, 55, 70, 55, 55, 70, 205, 205, 220];
+        assert_eq!(
+            pixelated,
+            expected
+                .iter()
+                .flat_map(|&value| [value, value, 255 - value])
+                .collect::<Vec<u8>>()
+        );
+    }
+
+    #[test]
+    fn blur_leaves_flat_colour_unchanged() {
+        let color = [40, 80, 120];
+        let mut frame = frame(6, 6, &[color; 36]);
+        let options = RedactOptions {
+            regions: vec![Region::Rect {
+                x: 1.0,
+                y: 1.0,
+                width: 3.0,
+                height: 3.0,
+            }],
+            blur_radius: 3,
+            feather: 1.0,
+            ..RedactOptions::default()
+        };
+        options.validate(6, 6).unwrap();
+
+        redact_regions(&mut frame, &options);
+
+        assert_eq!(pixels(&frame), [color; 36]);
+    }
+
+    #[test]
+    fn regions_outside_the_frame_are_clipped() {
+        let mut frame = frame(4, 4, &[[0, 0, 0]; 16]);
+        let options = RedactOptions {
+            regions: vec![
+                // Sticks out on the left and at the bottom
+                Region::Rect {
+                    x: -2.0,
+                    y: 2.0,
+                    width: 4.0,
+                    height: 10.0,
+                },
+                // Entirely outside
+                Region::Rect {
+                    x: 100.0,
+                    y: 100.0,
+                    width: 5.0,
+                    height: 5.0,
+                },
+                Region::Polygon {
+                    points: vec![[-10.0, -10.0], [-5.0, -10.0], [-5.0, -5.0]],
+                },
+            ],
+            method: RedactMethod::Fill,
+            color: [255, 0, 0],
+            ..RedactOptions::default()
+        };
+        options.validate(4, 4).unwrap();
+
+        redact_regions(&mut frame, &options);
+
+        let redacted: Vec<bool> = pixels(&frame)
+            .iter()
+            .map(|&pixel| pixel == [255, 0, 0])
+            .collect();
+        let expected: Vec<bool> = (0..16).map(|index| index >= 8 && index % 4 < 2).collect();
+        assert_eq!(redacted, expected);
+    }
+
+    #[test]
+    fn validate_rejects_infinite_reach_and_huge_blurs() {
+        let valid = RedactOptions::default();
+        assert!(valid.validate(640, 480).is_ok());
+        for options in [
+            RedactOptions {
+                padding: f32::INFINITY,
+                ..RedactOptions::default()
+            },
+            RedactOptions {
+                feather: f32::NAN,
+                ..RedactOptions::default()
+            },
+            RedactOptions {
+                feather: -1.0,
+                ..RedactOptions::default()
+            },
+            RedactOptions {
+                blur_radius: MAX_BLUR_RADIUS + 1,
+                ..RedactOptions::default()
+            },
+            RedactOptions {
+                block_size: 641,
+                ..RedactOptions::default()
+            },
+        ] {
+            assert_eq!(
+                options.validate(640, 480).unwrap_err().code,
+                VideoProcessingPluginError::InvalidArgument
+            );
+        }
+        let widest = RedactOptions {
+            blur_radius: MAX_BLUR_RADIUS,
+            ..RedactOptions::default()
+        };
+        assert!(widest.validate(640, 480).is_ok());
+    }
+}
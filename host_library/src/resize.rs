@@ -0,0 +1,269 @@
+use ffmpeg::{
+    format::Pixel,
+    software::scaling::{context::Context, flag::Flags},
+    util::frame::video::Video,
+    Error as FFmpegError,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::frame_buffer;
+
+/// Padding colour of letterboxed frames when the guest sets none, the grey YOLO is trained with
+pub const DEFAULT_PAD_COLOR: [u8; 3] = [114, 114, 114];
+
+/// How a frame is fitted into a target size of another aspect ratio
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResizeMode {
+    // Scales width and height independently, distorting the image
+    #[default]
+    Stretch,
+    // Scales the whole frame into the target and pads the borders
+    Letterbox,
+    // Scales the frame to cover the target and cuts off what sticks out
+    CenterCrop,
+}
+
+/// Interpolation of the FFmpeg scaler
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Interpolation {
+    FastBilinear,
+    #[default]
+    Bilinear,
+    Bicubic,
+    Point,
+    Area,
+    Gauss,
+    Sinc,
+    Lanczos,
+    Spline,
+}
+
+impl Interpolation {
+    pub fn flags(self) -> Flags {
+        match self {
+            Interpolation::FastBilinear => Flags::FAST_BILINEAR,
+            Interpolation::Bilinear => Flags::BILINEAR,
+            Interpolation::Bicubic => Flags::BICUBIC,
+            Interpolation::Point => Flags::POINT,
+            Interpolation::Area => Flags::AREA,
+            Interpolation::Gauss => Flags::GAUSS,
+            Interpolation::Sinc => Flags::SINC,
+            Interpolation::Lanczos => Flags::LANCZOS,
+            Interpolation::Spline => Flags::SPLINE,
+        }
+    }
+}
+
+/// Where the source frame ended up in a resized frame.
+/// A point of the resized frame maps back to the source with
+/// `source_x = (x - offset_x) / scale_x` and `source_y = (y - offset_y) / scale_y`.
+/// Offsets are positive for letterbox padding and negative for cropped borders.
+/// Written to the guest by `get_frame_transform`, which mirrors this struct.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Serialize)]
+pub struct FrameTransform {
+    pub source_width: u32,
+    pub source_height: u32,
+    pub width: u32,
+    pub height: u32,
+    pub scale_x: f64,
+    pub scale_y: f64,
+    pub offset_x: i32,
+    pub offset_y: i32,
+}
+
+impl FrameTransform {
+    /// Frames handed out at the size of the source
+    pub fn identity(width: u32, height: u32) -> Self {
+        FrameTransform::new(width, height, width, height, ResizeMode::Stretch)
+    }
+
+    pub fn new(
+        source_width: u32,
+        source_height: u32,
+        width: u32,
+        height: u32,
+        mode: ResizeMode,
+    ) -> Self {
+        let scale_x = width as f64 / source_width as f64;
+        let scale_y = height as f64 / source_height as f64;
+        let (scale_x, scale_y) = match mode {
+            ResizeMode::Stretch => (scale_x, scale_y),
+            ResizeMode::Letterbox => (scale_x.min(scale_y), scale_x.min(scale_y)),
+            ResizeMode::CenterCrop => (scale_x.max(scale_y), scale_x.max(scale_y)),
+        };
+
+        let mut frame_transform = FrameTransform {
+            source_width,
+            source_height,
+            width,
+            height,
+            scale_x,
+            scale_y,
+            offset_x: 0,
+            offset_y: 0,
+        };
+        frame_transform.offset_x = (width as i32 - frame_transform.scaled_width() as i32) / 2;
+        frame_transform.offset_y = (height as i32 - frame_transform.scaled_height() as i32) / 2;
+        frame_transform
+    }
+
+    // Size the scaler produces before padding or cropping
+    fn scaled_width(&self) -> u32 {
+        ((self.source_width as f64 * self.scale_x).round() as u32).max(1)
+    }
+
+    fn scaled_height(&self) -> u32 {
+        ((self.source_height as f64 * self.scale_y).round() as u32).max(1)
+    }
+
+    fn is_plain_scale(&self) -> bool {
+        self.scaled_width() == self.width && self.scaled_height() == self.height
+    }
+}
+
+/// Converts decoded frames to RGB24 at the size of a `FrameTransform`
+pub(crate) struct Resizer {
+    scaler: Context,
+    frame_transform: FrameTransform,
+    pad_color: [u8; 3],
+}
+
+impl Resizer {
+    pub fn new(
+        format: Pixel,
+        frame_transform: FrameTransform,
+        interpolation: Interpolation,
+        pad_color: [u8; 3],
+    ) -> Result<Self, FFmpegError> {
+        let scaler = Context::get(
+            format,
+            frame_transform.source_width,
+            frame_transform.source_height,
+            Pixel::RGB24,
+            frame_transform.scaled_width(),
+            frame_transform.scaled_height(),
+            interpolation.flags(),
+        )?;
+        Ok(Resizer {
+            scaler,
+            frame_transform,
+            pad_color,
+        })
+    }
+
+    pub fn run(&mut self, decoded_frame: &Video) -> Result<Video, FFmpegError> {
+        let mut scaled_frame = Video::empty();
+        self.scaler.run(decoded_frame, &mut scaled_frame)?;
+        if self.frame_transform.is_plain_scale() {
+            return Ok(scaled_frame);
+        }
+
+        let mut rgb_frame = Video::new(
+            Pixel::RGB24,
+            self.frame_transform.width,
+            self.frame_transform.height,
+        );
+        frame_buffer::fill_rgb(&mut rgb_frame, self.pad_color);
+        frame_buffer::copy_rgb_at(
+            &scaled_frame,
+            &mut rgb_frame,
+            self.frame_transform.offset_x,
+            self.frame_transform.offset_y,
+        );
+        Ok(rgb_frame)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Where a point of the resized frame lands in the source
+    fn to_source(frame_transform: &FrameTransform, x: f64, y: f64) -> (f64, f64) {
+        (
+            (x - frame_transform.offset_x as f64) / frame_transform.scale_x,
+            (y - frame_transform.offset_y as f64) / frame_transform.scale_y,
+        )
+    }
+
+    #[test]
+    fn stretch_scales_each_axis() {
+        let frame_transform = FrameTransform::new(1920, 1080, 640, 640, ResizeMode::Stretch);
+        assert_eq!(frame_transform.scale_x, 1.0 / 3.0);
+        assert_eq!(frame_transform.scale_y, 640.0 / 1080.0);
+        assert_eq!((frame_transform.offset_x, frame_transform.offset_y), (0, 0));
+        assert!(frame_transform.is_plain_scale());
+        assert!(FrameTransform::identity(1366, 768).is_plain_scale());
+    }
+
+    #[test]
+    fn letterbox_pads_the_shorter_side() {
+        let frame_transform = FrameTransform::new(1920, 1080, 640, 640, ResizeMode::Letterbox);
+        assert_eq!(
+            (frame_transform.scale_x, frame_transform.scale_y),
+            (1.0 / 3.0, 1.0 / 3.0)
+        );
+        assert_eq!(
+            (
+                frame_transform.scaled_width(),
+                frame_transform.scaled_height()
+            ),
+            (640, 360)
+        );
+        assert_eq!(
+            (frame_transform.offset_x, frame_transform.offset_y),
+            (0, 140)
+        );
+        assert!(!frame_transform.is_plain_scale());
+
+        // The corners of the image in the padded frame are the corners of the source
+        let (x, y) = to_source(&frame_transform, 0.0, 140.0);
+        assert!(x.abs() < 1e-9 && y.abs() < 1e-9);
+        let (x, y) = to_source(&frame_transform, 640.0, 500.0);
+        assert!((x - 1920.0).abs() < 1e-9 && (y - 1080.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn center_crop_cuts_the_longer_side() {
+        let frame_transform = FrameTransform::new(1366, 768, 416, 416, ResizeMode::CenterCrop);
+        assert_eq!(frame_transform.scale_x, 416.0 / 768.0);
+        assert_eq!(
+            (
+                frame_transform.scaled_width(),
+                frame_transform.scaled_height()
+            ),
+            (740, 416)
+        );
+        assert_eq!(
+            (frame_transform.offset_x, frame_transform.offset_y),
+            (-162, 0)
+        );
+
+        // The centre stays the centre
+        let (x, y) = to_source(&frame_transform, 208.0, 208.0);
+        assert!((x - 683.0).abs() < 1.0 && (y - 384.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn odd_sizes_round_the_scaled_frame() {
+        let frame_transform = FrameTransform::new(1366, 768, 416, 416, ResizeMode::Letterbox);
+        assert_eq!(
+            (
+                frame_transform.scaled_width(),
+                frame_transform.scaled_height()
+            ),
+            (416, 234)
+        );
+        assert_eq!(
+            (frame_transform.offset_x, frame_transform.offset_y),
+            (0, 91)
+        );
+
+        // A tiny side never scales down to nothing
+        let frame_transform = FrameTransform::new(4000, 2, 100, 100, ResizeMode::Letterbox);
+        assert_eq!(frame_transform.scaled_height(), 1);
+    }
+}
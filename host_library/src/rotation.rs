@@ -0,0 +1,70 @@
+use ffmpeg::{
+    codec::packet::side_data::Type as SideDataType, ffi::AVPacketSideDataType, util::error::ENOMEM,
+    Error as FFmpegError, Stream, StreamMut,
+};
+use log::warn;
+
+/// Clockwise rotation in degrees a video stream has to be shown with, 0, 90, 180 or 270.
+/// Read from the display matrix of the stream, or from the `rotate` tag of older files.
+pub fn display_rotation(stream: &Stream) -> u32 {
+    let matrix = stream
+        .side_data()
+        .find(|side_data| side_data.kind() == SideDataType::DisplayMatrix)
+        .filter(|side_data| side_data.data().len() >= 9 * 4)
+        .map(|side_data| {
+            let matrix: [i32; 9] = std::array::from_fn(|index| {
+                let bytes = &side_data.data()[index * 4..index * 4 + 4];
+                i32::from_ne_bytes(bytes.try_into().expect("slice of 4 bytes"))
+            });
+            matrix
+        });
+
+    let degrees = match matrix {
+        // The matrix rotates counterclockwise
+        Some(matrix) => -unsafe { ffmpeg::ffi::av_display_rotation_get(matrix.as_ptr()) },
+        None => match stream
+            .metadata()
+            .get("rotate")
+            .and_then(|rotate| rotate.parse::<f64>().ok())
+        {
+            Some(rotate) => rotate,
+            None => return 0,
+        },
+    };
+    if !degrees.is_finite() {
+        return 0;
+    }
+
+    let snapped = (degrees / 90.0).round() * 90.0;
+    let rotation = (snapped as i64).rem_euclid(360) as u32;
+    if (degrees - snapped).abs() > 0.5 {
+        warn!("Rotation of {degrees} degrees is not a multiple of 90, using {rotation}");
+    }
+    rotation
+}
+
+/// Filter that rotates frames clockwise by `rotation` degrees, `None` for 0
+pub fn rotation_filter(rotation: u32) -> Option<&'static str> {
+    match rotation % 360 {
+        90 => Some("transpose=clock"),
+        180 => Some("hflip,vflip"),
+        270 => Some("transpose=cclock"),
+        _ => None,
+    }
+}
+
+/// Writes a display matrix that tells players to show the stream rotated clockwise by `rotation`
+pub fn set_display_rotation(stream: &mut StreamMut, rotation: u32) -> Result<(), FFmpegError> {
+    unsafe {
+        let matrix = ffmpeg::ffi::av_stream_new_side_data(
+            stream.as_mut_ptr(),
+            AVPacketSideDataType::AV_PKT_DATA_DISPLAYMATRIX,
+            (9 * std::mem::size_of::<i32>()) as _,
+        );
+        if matrix.is_null() {
+            return Err(FFmpegError::Other { errno: ENOMEM });
+        }
+        ffmpeg::ffi::av_display_rotation_set(matrix as *mut i32, -(rotation as f64));
+    }
+    Ok(())
+}
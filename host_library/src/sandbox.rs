@@ -0,0 +1,316 @@
+use std::{
+    fs,
+    io::ErrorKind,
+    path::{Component, Path, PathBuf},
+};
+
+use ffmpeg::Dictionary;
+use log::warn;
+
+use crate::error::{PluginError, VideoProcessingPluginError};
+
+/// Environment variable with the directories the guest may read and write videos in,
+/// as `guest_dir:host_dir` or just `dir` entries separated by commas, like `--dir` of wasmedge.
+/// Without it the guest may only use the working directory of the runtime, as `.`.
+pub const DIRS_ENV: &str = "YOLO_VIDEO_PROC_DIRS";
+
+// Demuxers of self-contained video files. Playlists like HLS or concat could point FFmpeg at
+// any file of the host, so their demuxers are left out.
+const FORMAT_WHITELIST: &str = "mov,mp4,m4a,3gp,3g2,mj2,matroska,webm,avi,flv,mpegts,mpeg,\
+                                ogg,asf,ivf,h264,hevc,m4v,mjpeg,gif,yuv4mpegpipe";
+
+/// Options for opening an input that keep FFmpeg to the single file or buffer it was given.
+/// `protocol` is the only protocol it may open anything with, `None` for inputs in memory.
+pub fn input_options(protocol: Option<&str>) -> Dictionary<'static> {
+    let mut options = Dictionary::new();
+    options.set("format_whitelist", FORMAT_WHITELIST);
+    // An empty whitelist allows no protocol at all
+    options.set("protocol_whitelist", protocol.unwrap_or(""));
+    options
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    // The file is created or overwritten
+    Write,
+}
+
+// A host directory the guest sees at `guest`
+#[derive(Debug)]
+struct PreopenDir {
+    // Normalised, `.` is the empty path
+    guest: PathBuf,
+    // Canonical, without symlinks
+    host: PathBuf,
+}
+
+/// Turns paths sent by the guest into host paths, only inside the directories the plugin was
+/// given. WASI keeps the preopens of the guest to itself, so they are configured for the
+/// plugin with `DIRS_ENV` the same way.
+#[derive(Debug)]
+pub struct Sandbox {
+    dirs: Vec<PreopenDir>,
+}
+
+impl Sandbox {
+    pub fn from_env() -> Self {
+        Self::from_config(&std::env::var(DIRS_ENV).unwrap_or_else(|_| ".".to_string()))
+    }
+
+    fn from_config(config: &str) -> Self {
+        let dirs = config
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .filter_map(|entry| {
+                let (guest, host) = entry.split_once(':').unwrap_or((entry, entry));
+                let Some(guest) = normalise(Path::new(guest)) else {
+                    warn!("Ignoring {DIRS_ENV} entry {entry}, its guest path contains ..");
+                    return None;
+                };
+                match fs::canonicalize(host) {
+                    Ok(host) => Some(PreopenDir { guest, host }),
+                    Err(err) => {
+                        warn!("Ignoring {DIRS_ENV} entry {entry}, {host} is not accessible {err}");
+                        None
+                    }
+                }
+            })
+            .collect();
+        Sandbox { dirs }
+    }
+
+    /// Host path of the file at `guest_path`. Fails with `PermissionDenied` if the path leaves
+    /// the directories of the plugin, through `..` or a symlink. A file to `Read` has to exist,
+    /// one to `Write` only its directory.
+    pub fn resolve(&self, guest_path: &str, access: Access) -> Result<String, PluginError> {
+        if guest_path.is_empty() || guest_path.contains('\0') {
+            return Err(PluginError::new(
+                VideoProcessingPluginError::InvalidArgument,
+                format!("Invalid path {guest_path:?}"),
+            ));
+        }
+        let normalised = normalise(Path::new(guest_path))
+            .ok_or_else(|| permission_denied(format!("Path {guest_path} must not contain ..")))?;
+
+        // The innermost directory wins, as with nested preopens
+        let dir = self
+            .dirs
+            .iter()
+            .filter(|dir| {
+                dir.guest.has_root() == normalised.has_root() && normalised.starts_with(&dir.guest)
+            })
+            .max_by_key(|dir| dir.guest.components().count())
+            .ok_or_else(|| {
+                permission_denied(format!(
+                    "Path {guest_path} is outside of the directories the plugin may access"
+                ))
+            })?;
+        let host_path = dir.host.join(
+            normalised
+                .strip_prefix(&dir.guest)
+                .expect("path starts with the guest directory"),
+        );
+
+        let resolved = match access {
+            Access::Read => canonicalize(&host_path, guest_path)?,
+            Access::Write => {
+                let (Some(parent), Some(file_name)) = (host_path.parent(), host_path.file_name())
+                else {
+                    return Err(PluginError::new(
+                        VideoProcessingPluginError::InvalidArgument,
+                        format!("Path {guest_path} does not name a file"),
+                    ));
+                };
+                let resolved = canonicalize(parent, guest_path)?.join(file_name);
+                // An existing symlink would be written through, also one that points nowhere yet
+                if fs::symlink_metadata(&resolved).is_ok_and(|meta| meta.file_type().is_symlink()) {
+                    fs::canonicalize(&resolved).map_err(|_| {
+                        permission_denied(format!("Path {guest_path} is a dangling symlink"))
+                    })?
+                } else {
+                    resolved
+                }
+            }
+        };
+        if !resolved.starts_with(&dir.host) {
+            return Err(permission_denied(format!(
+                "Path {guest_path} leads outside of the directories the plugin may access \
+                 through a symlink"
+            )));
+        }
+
+        resolved.into_os_string().into_string().map_err(|_| {
+            PluginError::new(
+                VideoProcessingPluginError::InvalidArgument,
+                format!("Path {guest_path} is not valid UTF-8 on the host"),
+            )
+        })
+    }
+}
+
+// Drops `.` components, `None` if there is a `..`
+fn normalise(path: &Path) -> Option<PathBuf> {
+    let mut normalised = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => return None,
+            component => normalised.push(component),
+        }
+    }
+    Some(normalised)
+}
+
+fn canonicalize(host_path: &Path, guest_path: &str) -> Result<PathBuf, PluginError> {
+    fs::canonicalize(host_path).map_err(|err| match err.kind() {
+        ErrorKind::NotFound => PluginError::new(
+            VideoProcessingPluginError::FileNotFound,
+            format!("Could not find {guest_path}"),
+        ),
+        ErrorKind::PermissionDenied => permission_denied(format!("Could not access {guest_path}")),
+        _ => PluginError::new(
+            VideoProcessingPluginError::IoError,
+            format!("Could not resolve {guest_path} {err}"),
+        ),
+    })
+}
+
+fn permission_denied(message: String) -> PluginError {
+    PluginError::new(VideoProcessingPluginError::PermissionDenied, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+
+    use super::*;
+
+    // A fresh directory per test, tests run in parallel
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("sandbox-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::canonicalize(dir).unwrap()
+    }
+
+    fn code(result: Result<String, PluginError>) -> VideoProcessingPluginError {
+        result.unwrap_err().code
+    }
+
+    #[test]
+    fn parent_components_are_rejected() {
+        let sandbox = Sandbox::from_config(".");
+        for path in ["../Cargo.toml", "src/../../Cargo.toml", "src/.."] {
+            assert_eq!(
+                code(sandbox.resolve(path, Access::Read)),
+                VideoProcessingPluginError::PermissionDenied,
+                "{path}"
+            );
+        }
+    }
+
+    #[test]
+    fn working_directory_is_the_default() {
+        let sandbox = Sandbox::from_config(".");
+        let expected = fs::canonicalize("Cargo.toml").unwrap();
+        for path in ["Cargo.toml", "./Cargo.toml", ".//Cargo.toml"] {
+            assert_eq!(
+                sandbox.resolve(path, Access::Read).unwrap(),
+                expected.to_str().unwrap()
+            );
+        }
+        assert_eq!(
+            code(sandbox.resolve("/etc/hostname", Access::Read)),
+            VideoProcessingPluginError::PermissionDenied
+        );
+        assert_eq!(
+            code(sandbox.resolve("missing.mp4", Access::Read)),
+            VideoProcessingPluginError::FileNotFound
+        );
+    }
+
+    #[test]
+    fn guest_directories_map_to_host_directories() {
+        let dir = temp_dir("mapping");
+        fs::write(dir.join("a.mp4"), b"").unwrap();
+        let sandbox = Sandbox::from_config(&format!("/videos:{}", dir.display()));
+
+        assert_eq!(
+            sandbox.resolve("/videos/a.mp4", Access::Read).unwrap(),
+            dir.join("a.mp4").to_str().unwrap()
+        );
+        assert_eq!(
+            sandbox.resolve("/videos/./b.mp4", Access::Write).unwrap(),
+            dir.join("b.mp4").to_str().unwrap()
+        );
+        // Relative paths do not fall into an absolute guest directory
+        assert_eq!(
+            code(sandbox.resolve("videos/a.mp4", Access::Read)),
+            VideoProcessingPluginError::PermissionDenied
+        );
+        assert_eq!(
+            code(sandbox.resolve("/videosx/a.mp4", Access::Read)),
+            VideoProcessingPluginError::PermissionDenied
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn innermost_directory_wins() {
+        let outer = temp_dir("outer");
+        let inner = temp_dir("inner");
+        fs::create_dir(outer.join("clips")).unwrap();
+        fs::write(outer.join("clips/a.mp4"), b"").unwrap();
+        fs::write(inner.join("a.mp4"), b"").unwrap();
+        let sandbox = Sandbox::from_config(&format!(
+            "/videos:{}, /videos/clips:{}",
+            outer.display(),
+            inner.display()
+        ));
+
+        assert_eq!(
+            sandbox
+                .resolve("/videos/clips/a.mp4", Access::Read)
+                .unwrap(),
+            inner.join("a.mp4").to_str().unwrap()
+        );
+        fs::remove_dir_all(outer).unwrap();
+        fs::remove_dir_all(inner).unwrap();
+    }
+
+    #[test]
+    fn symlinks_out_of_the_directory_are_rejected() {
+        let dir = temp_dir("symlinks");
+        let outside = temp_dir("symlinks-outside");
+        fs::write(outside.join("secret"), b"").unwrap();
+        fs::write(dir.join("a.mp4"), b"").unwrap();
+        symlink(outside.join("secret"), dir.join("secret.mp4")).unwrap();
+        symlink(&outside, dir.join("escape")).unwrap();
+        symlink(dir.join("a.mp4"), dir.join("link.mp4")).unwrap();
+        symlink(outside.join("missing"), dir.join("dangling.mp4")).unwrap();
+        let sandbox = Sandbox::from_config(&format!("/videos:{}", dir.display()));
+
+        for (path, access) in [
+            ("/videos/secret.mp4", Access::Read),
+            ("/videos/secret.mp4", Access::Write),
+            ("/videos/escape/secret", Access::Read),
+            ("/videos/escape/new.mp4", Access::Write),
+            ("/videos/dangling.mp4", Access::Write),
+        ] {
+            assert_eq!(
+                code(sandbox.resolve(path, access)),
+                VideoProcessingPluginError::PermissionDenied,
+                "{path} {access:?}"
+            );
+        }
+        // Symlinks that stay inside are fine
+        assert_eq!(
+            sandbox.resolve("/videos/link.mp4", Access::Read).unwrap(),
+            dir.join("a.mp4").to_str().unwrap()
+        );
+        fs::remove_dir_all(dir).unwrap();
+        fs::remove_dir_all(outside).unwrap();
+    }
+}
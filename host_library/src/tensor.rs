@@ -0,0 +1,436 @@
+use ffmpeg::{format::Pixel, util::frame::video::Video};
+use serde::Deserialize;
+
+use crate::{
+    error::{PluginError, VideoProcessingPluginError},
+    resize::{FrameTransform, Interpolation, ResizeMode, Resizer, DEFAULT_PAD_COLOR},
+};
+
+/// Largest width and height of a tensor, far beyond the input of any vision model.
+/// An F32 tensor this size already takes 768 MiB.
+pub const MAX_TENSOR_SIZE: u32 = 8192;
+
+/// Element type of a tensor, written little endian. F16 values are rounded to the nearest half,
+/// ties to even.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TensorDataType {
+    #[default]
+    F32,
+    F16,
+}
+
+impl TensorDataType {
+    fn size(self) -> usize {
+        match self {
+            TensorDataType::F32 => 4,
+            TensorDataType::F16 => 2,
+        }
+    }
+}
+
+/// Order of the channel planes of a tensor
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChannelOrder {
+    #[default]
+    Rgb,
+    Bgr,
+}
+
+/// Options the guest sends as JSON to `get_frame_tensor`, everything is optional.
+/// Pixels are scaled to 0..1 and then normalised with `(value - mean) / std` per channel,
+/// `mean` and `std` are given in the channel order of the tensor.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TensorOptions {
+    pub data_type: TensorDataType,
+    pub channel_order: ChannelOrder,
+    pub mean: [f32; 3],
+    pub std: [f32; 3],
+    // Size of the tensor, the frame is resized into it when it differs from the frame size
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub resize_mode: ResizeMode,
+    // RGB colour of the letterbox borders
+    pub pad_color: Option<[u8; 3]>,
+    pub interpolation: Interpolation,
+}
+
+impl Default for TensorOptions {
+    fn default() -> Self {
+        TensorOptions {
+            data_type: TensorDataType::default(),
+            channel_order: ChannelOrder::default(),
+            mean: [0.0; 3],
+            std: [1.0; 3],
+            width: None,
+            height: None,
+            resize_mode: ResizeMode::default(),
+            pad_color: None,
+            interpolation: Interpolation::default(),
+        }
+    }
+}
+
+impl TensorOptions {
+    pub fn validate(&self) -> Result<(), PluginError> {
+        if self.std.iter().any(|std| *std == 0.0 || !std.is_finite()) {
+            return Err(PluginError::new(
+                VideoProcessingPluginError::InvalidArgument,
+                format!("Tensor std {:?} has to be finite and not 0", self.std),
+            ));
+        }
+        if self.width == Some(0) || self.height == Some(0) {
+            return Err(PluginError::new(
+                VideoProcessingPluginError::InvalidArgument,
+                "Tensor width and height have to be at least 1",
+            ));
+        }
+        if self.width.max(self.height) > Some(MAX_TENSOR_SIZE) {
+            return Err(PluginError::new(
+                VideoProcessingPluginError::InvalidArgument,
+                format!("Tensor width and height can be at most {MAX_TENSOR_SIZE}"),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Where a frame of the given size ends up in the tensor
+    pub fn frame_transform(&self, frame_width: u32, frame_height: u32) -> FrameTransform {
+        FrameTransform::new(
+            frame_width,
+            frame_height,
+            self.width.unwrap_or(frame_width),
+            self.height.unwrap_or(frame_height),
+            self.resize_mode,
+        )
+    }
+
+    /// Size in bytes of the NCHW tensor with a batch of one
+    pub fn tensor_size(&self, frame_transform: &FrameTransform) -> usize {
+        3 * frame_transform.width as usize * frame_transform.height as usize * self.data_type.size()
+    }
+}
+
+/// Writes an RGB24 frame into `buf` as planar NCHW tensor with a batch of one, resizing it
+/// to the size of `frame_transform` first if needed.
+/// `buf` must hold at least `TensorOptions::tensor_size` bytes.
+pub fn write_tensor(
+    frame: &Video,
+    options: &TensorOptions,
+    frame_transform: &FrameTransform,
+    buf: &mut [u8],
+) -> Result<(), PluginError> {
+    let resized_frame;
+    let frame =
+        if frame_transform.width != frame.width() || frame_transform.height != frame.height() {
+            let mut resizer = Resizer::new(
+                Pixel::RGB24,
+                *frame_transform,
+                options.interpolation,
+                options.pad_color.unwrap_or(DEFAULT_PAD_COLOR),
+            )
+            .map_err(|err| {
+                PluginError::new(
+                    VideoProcessingPluginError::InvalidArgument,
+                    format!("Could not resize frame for the tensor {err}"),
+                )
+            })?;
+            resized_frame = resizer.run(frame).map_err(|err| {
+                PluginError::new(
+                    VideoProcessingPluginError::DecoderError,
+                    format!("Could not resize frame for the tensor {err}"),
+                )
+            })?;
+            &resized_frame
+        } else {
+            frame
+        };
+
+    let (width, height) = (frame.width() as usize, frame.height() as usize);
+    let plane_len = width * height;
+    let element_size = options.data_type.size();
+    // Channel of the RGB24 pixel each tensor plane is read from
+    let channels = match options.channel_order {
+        ChannelOrder::Rgb => [0, 1, 2],
+        ChannelOrder::Bgr => [2, 1, 0],
+    };
+
+    // Encoded bytes of every normalised pixel value per plane, so the loop below only copies
+    let lookup: Vec<Vec<u8>> = (0..3)
+        .map(|plane| {
+            (0..=255u8)
+                .flat_map(|pixel| {
+                    let value = (pixel as f32 / 255.0 - options.mean[plane]) / options.std[plane];
+                    match options.data_type {
+                        TensorDataType::F32 => value.to_le_bytes().to_vec(),
+                        TensorDataType::F16 => f16_bits(value).to_le_bytes().to_vec(),
+                    }
+                })
+                .collect()
+        })
+        .collect();
+
+    let stride = frame.stride(0);
+    let rows = frame.data(0).chunks(stride).take(height);
+    for (y, row) in rows.enumerate() {
+        for (x, pixel) in row[..width * 3].chunks_exact(3).enumerate() {
+            let index = y * width + x;
+            for (plane, &channel) in channels.iter().enumerate() {
+                let value = pixel[channel] as usize * element_size;
+                let offset = (plane * plane_len + index) * element_size;
+                buf[offset..offset + element_size]
+                    .copy_from_slice(&lookup[plane][value..value + element_size]);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Bits of the IEEE 754 half precision float closest to `value`, ties to even
+fn f16_bits(value: f32) -> u16 {
+    let bits = value.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exponent = ((bits >> 23) & 0xff) as i32;
+    let mantissa = bits & 0x007f_ffff;
+
+    // Infinity and NaN
+    if exponent == 0xff {
+        return sign | 0x7c00 | if mantissa != 0 { 0x0200 } else { 0 };
+    }
+
+    let exponent = exponent - 127 + 15;
+    if exponent >= 0x1f {
+        // Too big, rounds to infinity
+        return sign | 0x7c00;
+    }
+    if exponent <= 0 {
+        // Too small even for a subnormal half
+        if exponent < -10 {
+            return sign;
+        }
+        let mantissa = mantissa | 0x0080_0000;
+        let shift = (14 - exponent) as u32;
+        return sign | round_to_even(mantissa, shift) as u16;
+    }
+
+    // A carry of the rounding into the exponent is still the correctly rounded value
+    sign | round_to_even((exponent as u32) << 23 | mantissa, 13) as u16
+}
+
+// `bits >> shift`, rounded to the nearest value and to an even one on ties
+fn round_to_even(bits: u32, shift: u32) -> u32 {
+    let truncated = bits >> shift;
+    let remainder = bits & ((1 << shift) - 1);
+    let half = 1 << (shift - 1);
+    if remainder > half || (remainder == half && truncated & 1 == 1) {
+        truncated + 1
+    } else {
+        truncated
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::frame_buffer;
+
+    use super::*;
+
+    fn f16_value(bits: u16) -> f32 {
+        let sign = if bits & 0x8000 != 0 { -1.0 } else { 1.0 };
+        let exponent = ((bits >> 10) & 0x1f) as i32;
+        let mantissa = (bits & 0x03ff) as f32;
+        sign * match exponent {
+            0 => mantissa * 2f32.powi(-24),
+            0x1f if mantissa == 0.0 => f32::INFINITY,
+            0x1f => f32::NAN,
+            _ => (1.0 + mantissa / 1024.0) * 2f32.powi(exponent - 15),
+        }
+    }
+
+    #[test]
+    fn f16_bits_of_exact_values() {
+        for (value, bits) in [
+            (0.0, 0x0000),
+            (-0.0, 0x8000),
+            (1.0, 0x3c00),
+            (-2.0, 0xc000),
+            (0.5, 0x3800),
+            (65504.0, 0x7bff),
+            // Smallest normal and subnormal half
+            (2f32.powi(-14), 0x0400),
+            (2f32.powi(-24), 0x0001),
+        ] {
+            assert_eq!(f16_bits(value), bits, "{value}");
+        }
+    }
+
+    #[test]
+    fn f16_bits_round_ties_to_even() {
+        // Halfway between 0x3c00 and 0x3c01, then between 0x3c01 and 0x3c02
+        assert_eq!(f16_bits(1.0 + 2f32.powi(-11)), 0x3c00);
+        assert_eq!(f16_bits(1.0 + 3.0 * 2f32.powi(-11)), 0x3c02);
+        // The same for subnormals
+        assert_eq!(f16_bits(2f32.powi(-25)), 0x0000);
+        assert_eq!(f16_bits(3.0 * 2f32.powi(-25)), 0x0002);
+        // Just above a tie rounds up
+        assert_eq!(f16_bits(1.0 + 2f32.powi(-11) + 2f32.powi(-20)), 0x3c01);
+    }
+
+    #[test]
+    fn f16_bits_out_of_range() {
+        assert_eq!(f16_bits(70000.0), 0x7c00);
+        assert_eq!(f16_bits(-1e10), 0xfc00);
+        assert_eq!(f16_bits(f32::INFINITY), 0x7c00);
+        assert_eq!(f16_bits(f32::NAN) & 0x7e00, 0x7e00);
+        assert_eq!(f16_bits(1e-9), 0x0000);
+        assert_eq!(f16_bits(-1e-9), 0x8000);
+    }
+
+    #[test]
+    fn f16_bits_round_to_the_nearest_half() {
+        // Values as normalised pixels end up, also after subtracting a mean
+        for pixel in 0..=255 {
+            for mean in [0.0, 0.485, 0.5] {
+                let value = (pixel as f32 / 255.0 - mean) / 0.229;
+                let half = f16_value(f16_bits(value));
+                // Half an ulp of the 11 significant bits of a half
+                let ulp = 2f32.powi(value.abs().log2().floor().max(-14.0) as i32 - 10);
+                assert!((half - value).abs() <= ulp / 2.0, "{value} became {half}");
+            }
+        }
+    }
+
+    // A frame of `pixels.len()` x 1 RGB24 pixels
+    fn row_frame(pixels: &[[u8; 3]]) -> Video {
+        let mut frame = Video::new(Pixel::RGB24, pixels.len() as u32, 1);
+        frame_buffer::copy_packed_to_frame(pixels.concat().as_slice(), &mut frame);
+        frame
+    }
+
+    fn tensor(frame: &Video, options: &TensorOptions) -> (Vec<u8>, FrameTransform) {
+        options.validate().unwrap();
+        let frame_transform = options.frame_transform(frame.width(), frame.height());
+        let mut buf = vec![0; options.tensor_size(&frame_transform)];
+        write_tensor(frame, options, &frame_transform, &mut buf).unwrap();
+        (buf, frame_transform)
+    }
+
+    fn f32_values(buf: &[u8]) -> Vec<f32> {
+        buf.chunks_exact(4)
+            .map(|bytes| f32::from_le_bytes(bytes.try_into().unwrap()))
+            .collect()
+    }
+
+    fn assert_close(values: &[f32], expected: &[f32]) {
+        assert_eq!(values.len(), expected.len());
+        for (value, expected) in values.iter().zip(expected) {
+            assert!(
+                (value - expected).abs() < 1e-6,
+                "{values:?} != {expected:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn rgb_planes_are_normalised_per_channel() {
+        let frame = row_frame(&[[255, 0, 51], [0, 102, 255]]);
+        let options = TensorOptions {
+            mean: [0.5, 0.0, 0.0],
+            std: [0.5, 1.0, 2.0],
+            ..Default::default()
+        };
+        let (buf, _) = tensor(&frame, &options);
+        // R plane, G plane, B plane
+        assert_close(&f32_values(&buf), &[1.0, -1.0, 0.0, 0.4, 0.1, 0.5]);
+    }
+
+    #[test]
+    fn bgr_planes_start_with_blue() {
+        let frame = row_frame(&[[255, 0, 51], [0, 102, 255]]);
+        let options = TensorOptions {
+            channel_order: ChannelOrder::Bgr,
+            // In the order of the tensor, so this is the mean of blue
+            mean: [0.2, 0.0, 0.0],
+            ..Default::default()
+        };
+        let (buf, _) = tensor(&frame, &options);
+        assert_close(&f32_values(&buf), &[0.0, 0.8, 0.0, 0.4, 1.0, 0.0]);
+    }
+
+    #[test]
+    fn f16_tensors_hold_half_floats() {
+        let frame = row_frame(&[[255, 0, 0], [0, 255, 0]]);
+        let options = TensorOptions {
+            data_type: TensorDataType::F16,
+            mean: [0.5; 3],
+            std: [0.5; 3],
+            ..Default::default()
+        };
+        let (buf, _) = tensor(&frame, &options);
+        let halves: Vec<u16> = buf
+            .chunks_exact(2)
+            .map(|bytes| u16::from_le_bytes(bytes.try_into().unwrap()))
+            .collect();
+        // 1.0 and -1.0
+        assert_eq!(halves, [0x3c00, 0xbc00, 0xbc00, 0x3c00, 0xbc00, 0xbc00]);
+    }
+
+    #[test]
+    fn letterbox_pads_the_tensor() {
+        let mut frame = Video::new(Pixel::RGB24, 4, 2);
+        frame_buffer::fill_rgb(&mut frame, [255, 0, 0]);
+        let options = TensorOptions {
+            width: Some(4),
+            height: Some(4),
+            resize_mode: ResizeMode::Letterbox,
+            pad_color: Some([0, 51, 255]),
+            ..Default::default()
+        };
+        let (buf, frame_transform) = tensor(&frame, &options);
+
+        assert_eq!(
+            (frame_transform.source_width, frame_transform.source_height),
+            (4, 2)
+        );
+        assert_eq!((frame_transform.width, frame_transform.height), (4, 4));
+        assert_eq!(
+            (frame_transform.scale_x, frame_transform.scale_y),
+            (1.0, 1.0)
+        );
+        assert_eq!((frame_transform.offset_x, frame_transform.offset_y), (0, 1));
+
+        // One padded row above and below the frame in every plane
+        let values = f32_values(&buf);
+        for (plane, (pad, image)) in [(0.0, 1.0), (0.2, 0.0), (1.0, 0.0)].into_iter().enumerate() {
+            let expected: Vec<f32> = [pad, image, image, pad]
+                .iter()
+                .flat_map(|&value| [value; 4])
+                .collect();
+            assert_close(&values[plane * 16..plane * 16 + 16], &expected);
+        }
+    }
+
+    #[test]
+    fn tensor_size_is_bounded() {
+        for (width, height) in [(Some(0), None), (None, Some(MAX_TENSOR_SIZE + 1))] {
+            let options = TensorOptions {
+                width,
+                height,
+                ..Default::default()
+            };
+            assert_eq!(
+                options.validate().unwrap_err().code,
+                VideoProcessingPluginError::InvalidArgument
+            );
+        }
+        let options = TensorOptions {
+            width: Some(MAX_TENSOR_SIZE),
+            height: Some(640),
+            ..Default::default()
+        };
+        assert!(options.validate().is_ok());
+    }
+}
@@ -17,6 +17,10 @@ impl Time {
         }
     }
 
+    pub fn new(time: Option<i64>, time_base: ffmpeg::Rational) -> Self {
+        Self { time, time_base }
+    }
+
     pub fn zero() -> Self {
         Time {
             time: Some(0),
@@ -0,0 +1,555 @@
+use serde::Deserialize;
+
+use crate::detection::Detection;
+
+/// Settings of the tracker of a session, sent by the guest as JSON
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TrackerOptions {
+    // Frames a track survives without a matching detection
+    pub max_age: u32,
+    // Matches a track needs before its id is handed out
+    pub min_hits: u32,
+    // Minimum overlap of a detection and the predicted box of a track to match them
+    pub iou_threshold: f32,
+    // Only match detections and tracks of the same class
+    pub class_aware: bool,
+}
+
+impl Default for TrackerOptions {
+    fn default() -> Self {
+        TrackerOptions {
+            max_age: 30,
+            min_hits: 3,
+            iou_threshold: 0.3,
+            class_aware: true,
+        }
+    }
+}
+
+impl TrackerOptions {
+    pub fn validate(&self) -> Result<(), String> {
+        if !(0.0..=1.0).contains(&self.iou_threshold) {
+            return Err(format!(
+                "iou_threshold {} has to be between 0 and 1",
+                self.iou_threshold
+            ));
+        }
+        Ok(())
+    }
+}
+
+// Noise of the motion model relative to the box height, as in ByteTrack
+const STD_WEIGHT_POSITION: f32 = 1.0 / 20.0;
+const STD_WEIGHT_VELOCITY: f32 = 1.0 / 160.0;
+
+/// Constant velocity Kalman filter of a single box coordinate.
+/// The coordinates of a box are predicted independently, with independent noise this is
+/// the same as one filter over the whole box.
+#[derive(Debug, Clone)]
+struct KalmanFilter {
+    // Position and velocity
+    state: [f32; 2],
+    covariance: [[f32; 2]; 2],
+}
+
+impl KalmanFilter {
+    fn new(position: f32, scale: f32) -> Self {
+        let std_position = 2.0 * STD_WEIGHT_POSITION * scale;
+        let std_velocity = 10.0 * STD_WEIGHT_VELOCITY * scale;
+        KalmanFilter {
+            state: [position, 0.0],
+            covariance: [
+                [std_position * std_position, 0.0],
+                [0.0, std_velocity * std_velocity],
+            ],
+        }
+    }
+
+    fn predict(&mut self, scale: f32) {
+        let std_position = STD_WEIGHT_POSITION * scale;
+        let std_velocity = STD_WEIGHT_VELOCITY * scale;
+        let [[p00, p01], [p10, p11]] = self.covariance;
+
+        self.state[0] += self.state[1];
+        self.covariance = [
+            [
+                p00 + p01 + p10 + p11 + std_position * std_position,
+                p01 + p11,
+            ],
+            [p10 + p11, p11 + std_velocity * std_velocity],
+        ];
+    }
+
+    fn update(&mut self, measurement: f32, scale: f32) {
+        let std_measurement = STD_WEIGHT_POSITION * scale;
+        let [[p00, p01], [p10, p11]] = self.covariance;
+
+        let innovation_covariance = p00 + std_measurement * std_measurement;
+        let gain = [p00 / innovation_covariance, p10 / innovation_covariance];
+        let innovation = measurement - self.state[0];
+
+        self.state[0] += gain[0] * innovation;
+        self.state[1] += gain[1] * innovation;
+        self.covariance = [
+            [(1.0 - gain[0]) * p00, (1.0 - gain[0]) * p01],
+            [p10 - gain[1] * p00, p11 - gain[1] * p01],
+        ];
+    }
+}
+
+// Box as center x, center y, width and height
+type BoxCoordinates = [f32; 4];
+
+fn box_coordinates(detection: &Detection) -> BoxCoordinates {
+    [
+        detection.x + detection.width / 2.0,
+        detection.y + detection.height / 2.0,
+        detection.width,
+        detection.height,
+    ]
+}
+
+fn iou(a: &BoxCoordinates, b: &BoxCoordinates) -> f32 {
+    let left = (a[0] - a[2] / 2.0).max(b[0] - b[2] / 2.0);
+    let right = (a[0] + a[2] / 2.0).min(b[0] + b[2] / 2.0);
+    let top = (a[1] - a[3] / 2.0).max(b[1] - b[3] / 2.0);
+    let bottom = (a[1] + a[3] / 2.0).min(b[1] + b[3] / 2.0);
+
+    let intersection = (right - left).max(0.0) * (bottom - top).max(0.0);
+    let union = a[2] * a[3] + b[2] * b[3] - intersection;
+    if union <= 0.0 {
+        return 0.0;
+    }
+    intersection / union
+}
+
+#[derive(Debug, Clone)]
+struct Track {
+    id: u32,
+    class_id: u32,
+    // One filter per coordinate of BoxCoordinates
+    filters: [KalmanFilter; 4],
+    hits: u32,
+    // Set once the track has been matched min_hits times, its id is handed out from then on
+    confirmed: bool,
+    frames_since_update: u32,
+}
+
+impl Track {
+    fn new(id: u32, detection: &Detection) -> Self {
+        let coordinates = box_coordinates(detection);
+        let scale = coordinates[3];
+        Track {
+            id,
+            class_id: detection.class_id,
+            filters: coordinates.map(|coordinate| KalmanFilter::new(coordinate, scale)),
+            hits: 1,
+            confirmed: false,
+            frames_since_update: 0,
+        }
+    }
+
+    fn predicted_box(&self) -> BoxCoordinates {
+        let [center_x, center_y, width, height]: BoxCoordinates =
+            std::array::from_fn(|coordinate| self.filters[coordinate].state[0]);
+        [center_x, center_y, width.max(1.0), height.max(1.0)]
+    }
+
+    fn predict(&mut self) {
+        let scale = self.predicted_box()[3];
+        for filter in &mut self.filters {
+            filter.predict(scale);
+        }
+        self.frames_since_update += 1;
+    }
+
+    fn update(&mut self, detection: &Detection) {
+        let coordinates = box_coordinates(detection);
+        let scale = coordinates[3];
+        for (filter, coordinate) in self.filters.iter_mut().zip(coordinates) {
+            filter.update(coordinate, scale);
+        }
+        self.hits += 1;
+        self.frames_since_update = 0;
+    }
+}
+
+/// SORT style multi object tracker: Kalman filter motion prediction and IoU matching of
+/// detections to the predicted boxes with the Hungarian algorithm
+#[derive(Debug, Clone)]
+pub struct Tracker {
+    options: TrackerOptions,
+    tracks: Vec<Track>,
+    next_id: u32,
+    frame_count: u32,
+}
+
+impl Tracker {
+    pub fn new(options: TrackerOptions) -> Self {
+        Tracker {
+            options,
+            tracks: Vec::new(),
+            next_id: 1,
+            frame_count: 0,
+        }
+    }
+
+    /// Advances the tracker by one frame and sets the track id of every detection of it.
+    /// Detections of tracks that have not been confirmed yet get no id.
+    pub fn update(&mut self, detections: &mut [Detection]) {
+        self.frame_count += 1;
+        for track in &mut self.tracks {
+            track.predict();
+        }
+
+        let predicted_boxes: Vec<BoxCoordinates> =
+            self.tracks.iter().map(Track::predicted_box).collect();
+        let costs: Vec<Vec<f64>> = detections
+            .iter()
+            .map(|detection| {
+                let coordinates = box_coordinates(detection);
+                self.tracks
+                    .iter()
+                    .zip(&predicted_boxes)
+                    .map(|(track, predicted_box)| {
+                        if self.options.class_aware && track.class_id != detection.class_id {
+                            return 1.0;
+                        }
+                        1.0 - iou(&coordinates, predicted_box) as f64
+                    })
+                    .collect()
+            })
+            .collect();
+
+        let assignment = if self.tracks.is_empty() {
+            vec![None; detections.len()]
+        } else {
+            hungarian(&costs)
+        };
+
+        for (detection_index, detection) in detections.iter_mut().enumerate() {
+            let matched = assignment[detection_index].filter(|&track_index| {
+                1.0 - costs[detection_index][track_index] >= self.options.iou_threshold as f64
+            });
+
+            let track = match matched {
+                Some(track_index) => {
+                    let track = &mut self.tracks[track_index];
+                    track.update(detection);
+                    track
+                }
+                None => {
+                    self.tracks.push(Track::new(self.next_id, detection));
+                    self.next_id += 1;
+                    self.tracks.last_mut().expect("track was just pushed")
+                }
+            };
+
+            if track.hits >= self.options.min_hits {
+                track.confirmed = true;
+            }
+            // Every track is shown right away at the start of the video, like SORT does
+            detection.track_id =
+                (track.confirmed || self.frame_count <= self.options.min_hits).then_some(track.id);
+        }
+
+        let max_age = self.options.max_age;
+        self.tracks
+            .retain(|track| track.frames_since_update <= max_age);
+    }
+}
+
+/// Minimum cost assignment of rows to columns, every row gets a column if there are enough.
+/// Kuhn-Munkres with potentials, O(rows^2 * columns).
+fn hungarian(costs: &[Vec<f64>]) -> Vec<Option<usize>> {
+    let rows = costs.len();
+    if rows == 0 {
+        return Vec::new();
+    }
+    let columns = costs[0].len();
+    if rows > columns {
+        // Solve for the columns and turn the result around
+        let transposed: Vec<Vec<f64>> = (0..columns)
+            .map(|column| costs.iter().map(|row| row[column]).collect())
+            .collect();
+        let mut assignment = vec![None; rows];
+        for (column, row) in hungarian(&transposed).into_iter().enumerate() {
+            if let Some(row) = row {
+                assignment[row] = Some(column);
+            }
+        }
+        return assignment;
+    }
+
+    // 1-based as in the textbook version, row 0 and column 0 are sentinels
+    let mut row_potential = vec![0.0; rows + 1];
+    let mut column_potential = vec![0.0; columns + 1];
+    let mut column_row = vec![0usize; columns + 1];
+    let mut previous_column = vec![0usize; columns + 1];
+
+    for row in 1..=rows {
+        column_row[0] = row;
+        let mut column = 0;
+        let mut min_slack = vec![f64::INFINITY; columns + 1];
+        let mut used = vec![false; columns + 1];
+
+        loop {
+            used[column] = true;
+            let current_row = column_row[column];
+            let mut delta = f64::INFINITY;
+            let mut next_column = 0;
+            for candidate in 1..=columns {
+                if used[candidate] {
+                    continue;
+                }
+                let slack = costs[current_row - 1][candidate - 1]
+                    - row_potential[current_row]
+                    - column_potential[candidate];
+                if slack < min_slack[candidate] {
+                    min_slack[candidate] = slack;
+                    previous_column[candidate] = column;
+                }
+                if min_slack[candidate] < delta {
+                    delta = min_slack[candidate];
+                    next_column = candidate;
+                }
+            }
+            for candidate in 0..=columns {
+                if used[candidate] {
+                    row_potential[column_row[candidate]] += delta;
+                    column_potential[candidate] -= delta;
+                } else {
+                    min_slack[candidate] -= delta;
+                }
+            }
+            column = next_column;
+            if column_row[column] == 0 {
+                break;
+            }
+        }
+
+        // Flip the augmenting path
+        loop {
+            let previous = previous_column[column];
+            column_row[column] = column_row[previous];
+            column = previous;
+            if column == 0 {
+                break;
+            }
+        }
+    }
+
+    let mut assignment = vec![None; rows];
+    for column in 1..=columns {
+        if column_row[column] != 0 {
+            assignment[column_row[column] - 1] = Some(column - 1);
+        }
+    }
+    assignment
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn total(costs: &[Vec<f64>], assignment: &[Option<usize>]) -> f64 {
+        assignment
+            .iter()
+            .enumerate()
+            .filter_map(|(row, column)| column.map(|column| costs[row][column]))
+            .sum()
+    }
+
+    // Cheapest total over every way to assign min(rows, columns) pairs
+    fn brute_force(costs: &[Vec<f64>], row: usize, used: &mut [bool]) -> f64 {
+        let rows_left = costs.len() - row;
+        let columns_left = used.iter().filter(|used| !**used).count();
+        if rows_left == 0 || columns_left == 0 {
+            return 0.0;
+        }
+        let mut best = f64::INFINITY;
+        // The row may stay unassigned if there are more rows than columns
+        if rows_left > columns_left {
+            best = brute_force(costs, row + 1, used);
+        }
+        for column in 0..used.len() {
+            if !used[column] {
+                used[column] = true;
+                best = best.min(costs[row][column] + brute_force(costs, row + 1, used));
+                used[column] = false;
+            }
+        }
+        best
+    }
+
+    fn detection(x: f32, y: f32, class_id: u32) -> Detection {
+        Detection {
+            x,
+            y,
+            width: 20.0,
+            height: 40.0,
+            class_id,
+            score: 0.9,
+            label: None,
+            color: None,
+            track_id: None,
+        }
+    }
+
+    // Track ids the tracker hands out for the detections of one frame
+    fn track_ids(tracker: &mut Tracker, mut detections: Vec<Detection>) -> Vec<Option<u32>> {
+        tracker.update(&mut detections);
+        detections
+            .iter()
+            .map(|detection| detection.track_id)
+            .collect()
+    }
+
+    #[test]
+    fn hungarian_of_empty_matrices() {
+        assert!(hungarian(&[]).is_empty());
+        assert_eq!(hungarian(&[vec![], vec![]]), vec![None, None]);
+    }
+
+    #[test]
+    fn hungarian_finds_the_cheapest_assignment() {
+        let costs = vec![
+            vec![4.0, 1.0, 3.0],
+            vec![2.0, 0.0, 5.0],
+            vec![3.0, 2.0, 2.0],
+        ];
+        assert_eq!(hungarian(&costs), vec![Some(1), Some(0), Some(2)]);
+    }
+
+    #[test]
+    fn hungarian_of_rectangular_matrices() {
+        let wide = vec![vec![0.9, 0.1, 0.5], vec![0.2, 0.3, 0.8]];
+        assert_eq!(hungarian(&wide), vec![Some(1), Some(0)]);
+
+        let tall = vec![vec![0.9, 0.2], vec![0.1, 0.3], vec![0.5, 0.8]];
+        assert_eq!(hungarian(&tall), vec![Some(1), Some(0), None]);
+    }
+
+    #[test]
+    fn hungarian_matches_brute_force() {
+        // Small linear congruential generator, the test has to be deterministic
+        let mut state = 12345u64;
+        let mut next = || {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1);
+            (state >> 33) as f64 / (1u64 << 31) as f64
+        };
+        for rows in 1..=5 {
+            for columns in 1..=5 {
+                let costs: Vec<Vec<f64>> = (0..rows)
+                    .map(|_| (0..columns).map(|_| next()).collect())
+                    .collect();
+                let assignment = hungarian(&costs);
+
+                let mut columns_taken: Vec<usize> = assignment.iter().flatten().copied().collect();
+                assert_eq!(columns_taken.len(), rows.min(columns));
+                columns_taken.sort_unstable();
+                columns_taken.dedup();
+                assert_eq!(columns_taken.len(), rows.min(columns));
+
+                let expected = brute_force(&costs, 0, &mut vec![false; columns]);
+                assert!(
+                    (total(&costs, &assignment) - expected).abs() < 1e-9,
+                    "{costs:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn ids_stay_stable_at_constant_velocity() {
+        let mut tracker = Tracker::new(TrackerOptions::default());
+        for frame in 0..50 {
+            let offset = frame as f32 * 6.0;
+            let ids = track_ids(
+                &mut tracker,
+                vec![
+                    detection(10.0 + offset, 100.0, 0),
+                    detection(600.0 - offset, 300.0 + offset / 2.0, 0),
+                ],
+            );
+            assert_eq!(ids, vec![Some(1), Some(2)], "frame {frame}");
+        }
+    }
+
+    #[test]
+    fn new_id_after_max_age_frames_without_a_match() {
+        let mut tracker = Tracker::new(TrackerOptions {
+            max_age: 2,
+            min_hits: 1,
+            ..TrackerOptions::default()
+        });
+        for _ in 0..3 {
+            assert_eq!(
+                track_ids(&mut tracker, vec![detection(50.0, 50.0, 0)]),
+                vec![Some(1)]
+            );
+        }
+
+        // Missing for max_age frames keeps the track
+        for _ in 0..2 {
+            assert!(track_ids(&mut tracker, Vec::new()).is_empty());
+        }
+        assert_eq!(
+            track_ids(&mut tracker, vec![detection(50.0, 50.0, 0)]),
+            vec![Some(1)]
+        );
+
+        // One frame more drops it
+        for _ in 0..3 {
+            track_ids(&mut tracker, Vec::new());
+        }
+        assert_eq!(
+            track_ids(&mut tracker, vec![detection(50.0, 50.0, 0)]),
+            vec![Some(2)]
+        );
+    }
+
+    #[test]
+    fn no_id_before_min_hits_matches() {
+        let mut tracker = Tracker::new(TrackerOptions {
+            min_hits: 3,
+            ..TrackerOptions::default()
+        });
+        // Tracks of the first min_hits frames are shown right away
+        for _ in 0..3 {
+            assert_eq!(
+                track_ids(&mut tracker, vec![detection(10.0, 10.0, 0)]),
+                vec![Some(1)]
+            );
+        }
+
+        for expected in [None, None, Some(2), Some(2)] {
+            let ids = track_ids(
+                &mut tracker,
+                vec![detection(10.0, 10.0, 0), detection(200.0, 200.0, 0)],
+            );
+            assert_eq!(ids, vec![Some(1), expected]);
+        }
+    }
+
+    #[test]
+    fn class_aware_tracks_do_not_match_across_classes() {
+        for (class_aware, expected) in [(true, Some(2)), (false, Some(1))] {
+            let mut tracker = Tracker::new(TrackerOptions {
+                min_hits: 1,
+                class_aware,
+                ..TrackerOptions::default()
+            });
+            assert_eq!(
+                track_ids(&mut tracker, vec![detection(50.0, 50.0, 0)]),
+                vec![Some(1)]
+            );
+            assert_eq!(
+                track_ids(&mut tracker, vec![detection(50.0, 50.0, 1)]),
+                vec![expected],
+                "class_aware {class_aware}"
+            );
+        }
+    }
+}
@@ -1,132 +1,118 @@
-#[macro_use]
-extern crate log;
 extern crate simplelog;
 
-use image::{GenericImage, ImageBuffer, Rgb};
+use guest_library::{AnnotationFormat, Detection, DrawStyle, StreamWriter, VideoReader};
+use image::{GenericImage, Rgb};
 use log::{debug, info, LevelFilter};
 use prgrs::Prgrs;
 use simplelog::{ColorChoice, CombinedLogger, Config, TermLogger, TerminalMode};
 
-mod plugin {
-    use log::LevelFilter;
-
-    pub fn init_plugin_logging_with_log_level(level_filter: LevelFilter) {
-        let level_filter_i32 = level_filter as i32;
-        let level_filter_ptr = std::ptr::addr_of!(level_filter_i32);
-        unsafe { init_plugin_logging(level_filter_ptr) };
-    }
-
-    #[link(wasm_import_module = "yolo-video-proc")]
-    extern "C" {
-        pub fn init_plugin_logging(level: *const i32) -> i32;
-
-        pub fn load_video_to_host_memory(
-            str_ptr: i32,
-            str_len: i32,
-            str_capacity: i32,
-            width_ptr: *mut i32,
-            height_ptr: *mut i32,
-            frame_count: *mut i32,
-        ) -> i32;
-
-        pub fn get_frame(
-            frame_index: i32,
-            image_buf_ptr: i32,
-            image_buf_len: i32,
-            image_buf_capacity: i32,
-        ) -> i32;
-
-        pub fn write_frame(frame_index: i32, image_buf_ptr: i32, image_buf_len: i32) -> i32;
-
-        pub fn assemble_output_frames_to_video(
-            str_ptr: i32,
-            str_len: i32,
-            str_capacity: i32,
-        ) -> i32;
-
-    }
-}
-
-fn process_video(mut filename: String, fail: u32) -> Result<(), ()> {
+fn process_video(filename: &str) -> Result<(), guest_library::Error> {
     debug!("Start Proc Video");
 
-    plugin::init_plugin_logging_with_log_level(LevelFilter::Info);
-
-    let (mut width, mut height, mut frame_count): (i32, i32, i32) = (0, 0, 10);
-    let width_ptr = std::ptr::addr_of_mut!(width);
-    let height_ptr = std::ptr::addr_of_mut!(height);
-    let frame_count_ptr = std::ptr::addr_of_mut!(frame_count);
+    guest_library::init_plugin_logging(LevelFilter::Info);
 
     let mut red_square = image::RgbImage::new(32, 32);
-    let mut blue_square = image::RgbImage::new(32, 32);
     for x in 0..32 {
         for y in 0..32 {
             red_square.put_pixel(x, y, Rgb([255, 0, 0]));
-            blue_square.put_pixel(x, y, Rgb([0, 0, 255]));
         }
     }
-
-    debug!("Call load_video_to_host_memory() ");
-    let result = unsafe {
-        plugin::load_video_to_host_memory(
-            filename.as_mut_ptr() as usize as i32,
-            filename.len() as i32,
-            filename.capacity() as i32,
-            width_ptr,
-            height_ptr,
-            frame_count_ptr,
-        )
-    };
-
-    if fail == 1 {
-        return Ok(());
-    }
-
-    let image_buf_size: usize = (width * height * 3) as usize;
-    debug!("WIDTH {}", width);
-    debug!("HEIGHT {}", height);
+    // Boxes and labels are drawn by the plugin instead of in wasm
+    let detections = [Detection {
+        x: 64.0,
+        y: 64.0,
+        width: 128.0,
+        height: 96.0,
+        class_id: 0,
+        score: 1.0,
+        label: Some("demo".into()),
+        color: Some([0, 0, 255]),
+        track_id: None,
+    }];
+
+    let reader = VideoReader::open(filename)?;
+    let frame_count = reader.frame_count().unwrap_or_default();
+
+    debug!("WIDTH {}", reader.width());
+    debug!("HEIGHT {}", reader.height());
     debug!("Number of Frames {}", frame_count);
+    info!("Video Info {}", reader.video_info_json()?);
 
     info!("Begin Processing {} frames ", frame_count);
 
+    // The detections also end up in a .jsonl file next to the output video
+    reader.set_annotation_export(&[AnnotationFormat::JsonLines], &["demo"])?;
+
+    let mut writer = reader.writer();
     for idx in Prgrs::new(0..frame_count, frame_count as usize) {
         debug!("------ Run for frame {}", idx);
-        let mut image_buf: Vec<u8> = vec![0; image_buf_size];
-
-        let buf_ptr_raw = image_buf.as_mut_ptr() as usize as i32;
-        let buf_len = image_buf.len() as i32;
-        let buf_capacity = image_buf.capacity() as i32;
-        debug!("WASM image_buf_ptr {:?}", buf_ptr_raw);
-        debug!("WASM image_buf_len {:?}", buf_len);
-        debug!("WASM image_buf_capacity {:?}", buf_capacity);
-
-        {
-            unsafe { plugin::get_frame(idx, buf_ptr_raw, buf_len, buf_capacity) };
-            let mut image_buf: ImageBuffer<image::Rgb<u8>, Vec<u8>> =
-                ImageBuffer::from_vec(width as u32, height as u32, image_buf).unwrap();
-            let _ = image_buf.copy_from(&red_square, 0, 0);
-            let _ = image_buf.copy_from(&blue_square, 64, 64);
-
-            unsafe { plugin::write_frame(idx, buf_ptr_raw, buf_len) };
+        if let Ok(metadata) = reader.frame_metadata(idx) {
+            debug!(
+                "Frame {} at {:.3}s type {}",
+                idx, metadata.pts_seconds, metadata.picture_type as char
+            );
         }
+        let mut image = reader.frame(idx)?;
+
+        let _ = image.copy_from(&red_square, 0, 0);
+
+        writer.write(idx, &image)?;
+        // Track ids are drawn in front of the labels and exported with the detections
+        let mut frame_detections = detections.clone();
+        reader.track_detections(idx, &mut frame_detections)?;
+        reader.draw_detections(idx, &frame_detections, &DrawStyle::default())?;
     }
 
     info!("Finished Writing {:?} Frames To Plugin", frame_count);
 
+    let output_filename = output_filename(filename);
+
+    info!("Begin Encode Video {:?}", output_filename);
+    writer.finish(&output_filename)?;
+    info!("Finished Encoding Video : {}", output_filename);
+
+    Ok(())
+}
+
+fn output_filename(filename: &str) -> String {
     let mut out: Vec<&str> = filename.split(".").collect::<Vec<&str>>();
     out.insert(0, "./");
     out.insert(out.len() - 1, "_out.");
-    let mut output_filename = out.join("");
+    out.join("")
+}
 
-    info!("Begin Encode Video {:?}", output_filename);
-    let output_code = unsafe {
-        plugin::assemble_output_frames_to_video(
-            output_filename.as_mut_ptr() as usize as i32,
-            output_filename.len() as i32,
-            output_filename.capacity() as i32,
-        )
-    };
+// Decodes the video one frame at a time instead of loading all frames into host memory
+fn stream_video(filename: &str) -> Result<(), guest_library::Error> {
+    debug!("Start Stream Video");
+
+    guest_library::init_plugin_logging(LevelFilter::Info);
+
+    let mut red_square = image::RgbImage::new(32, 32);
+    for x in 0..32 {
+        for y in 0..32 {
+            red_square.put_pixel(x, y, Rgb([255, 0, 0]));
+        }
+    }
+
+    let mut reader = VideoReader::open_stream(filename)?;
+    reader.set_encoder_options(r#"{"codec": "h264", "crf": 20}"#)?;
+
+    // Frames are encoded as soon as they are written
+    let output_filename = output_filename(filename);
+    let mut writer: StreamWriter = reader.stream_writer(&output_filename)?;
+    let mut frame_count = 0;
+
+    info!("Begin Streaming {} ", filename);
+    for frame in &mut reader {
+        let (frame_index, mut image) = frame?;
+        let _ = image.copy_from(&red_square, 0, 0);
+        writer.write(frame_index, &image)?;
+        frame_count += 1;
+    }
+
+    info!("Finished Streaming {:?} Frames To Plugin", frame_count);
 
+    writer.finish()?;
     info!("Finished Encoding Video : {}", output_filename);
 
     Ok(())
@@ -141,10 +127,11 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     )])
     .unwrap();
 
-    // process_video("./1080p60.mp4".to_string(), 1).unwrap();
-    // process_video("./ts_wide.mp4".to_string()).unwrap();
-    // process_video("./times_square.mp4".to_string()).unwrap();
-    process_video("small_bunny_1080p_60fps.mp4".to_string(), 0).unwrap();
+    // process_video("./1080p60.mp4")?;
+    // process_video("./ts_wide.mp4")?;
+    // process_video("./times_square.mp4")?;
+    process_video("small_bunny_1080p_60fps.mp4")?;
+    stream_video("times_square.mp4")?;
 
     Ok(())
 }